@@ -0,0 +1,273 @@
+use crate::{
+    debug, dumpfs, extract, fsck,
+    gotenks::{self, scrub::Event},
+    mkfs, mount, resize,
+};
+use anyhow::anyhow;
+use byte_unit::Byte;
+use std::{fs::File, io::BufWriter, time::Duration};
+
+// Parses the command line and runs the subcommand it names.
+pub fn run() -> anyhow::Result<()> {
+    let matches = clap::App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .subcommand(
+            clap::App::new("mkfs")
+                .about("Create a new file system")
+                .arg("<file> 'Location of the new file system image'")
+                .arg(
+                    clap::Arg::with_name("block-size")
+                        .short('b')
+                        .long("block-size")
+                        .takes_value(true)
+                        .about("Specify the block size in bytes.")
+                        .possible_values(&["1024", "2048", "4096"])
+                        .default_value("4096"),
+                )
+                .arg(
+                    clap::Arg::with_name("size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .about("Specify the total size of the file system. The final size might be bigger than the provided value in order to have space for the file system structures.").required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("extents")
+                        .short('e')
+                        .long("extents")
+                        .about("Map the blocks of new files and directories with extents instead of indirect pointers."),
+                )
+                .arg(
+                    clap::Arg::with_name("checksums")
+                        .short('c')
+                        .long("checksums")
+                        .about("Keep a checksum of every data block and fail reads that don't match it."),
+                )
+                .arg(
+                    clap::Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .about("Copy the contents of a directory into the new file system."),
+                ),
+        ).subcommand(
+            clap::App::new("mount")
+                .about("Mount a file system")
+                .arg("<image> 'Location of the file system image'")
+                .arg("<mountpoint> 'Mountpoint'")
+                .arg(
+                    clap::Arg::with_name("scrub-interval")
+                        .long("scrub-interval")
+                        .takes_value(true)
+                        .about("Scrub the image while mounted, checking one block at most every given milliseconds."),
+                )
+                .arg(
+                    clap::Arg::with_name("no-mmap")
+                        .long("no-mmap")
+                        .about("Read and write the image with pread and pwrite instead of mapping it into memory, for images on network file systems."),
+                )
+        ).subcommand(
+            clap::App::new("fsck")
+                .about("Check a file system image for errors")
+                .arg("<image> 'Location of the file system image'")
+                .arg(
+                    clap::Arg::with_name("repair")
+                        .short('r')
+                        .long("repair")
+                        .about("Fix the problems that can be fixed without losing data."),
+                ),
+        ).subcommand(
+            clap::App::new("debug")
+                .about("Inspect and patch an unmounted file system image interactively")
+                .arg("<image> 'Location of the file system image'")
+                .arg(
+                    clap::Arg::with_name("force")
+                        .short('f')
+                        .long("force")
+                        .about("Allow changes even if the image looks mounted."),
+                )
+        ).subcommand(
+            clap::App::new("dumpfs")
+                .about("Print the superblock and block groups, or an inode, of a file system image")
+                .arg("<image> 'Location of the file system image'")
+                .arg(
+                    clap::Arg::with_name("inode")
+                        .short('i')
+                        .long("inode")
+                        .takes_value(true)
+                        .about("Print the inode with this index instead."),
+                )
+                .arg(
+                    clap::Arg::with_name("path")
+                        .short('p')
+                        .long("path")
+                        .takes_value(true)
+                        .about("Print the inode at this path instead, with the entries of directories."),
+                )
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .about("Print JSON instead of text."),
+                ),
+        ).subcommand(
+            clap::App::new("extract")
+                .about("Copy the contents of a file system image to a directory or a tar archive")
+                .arg("<image> 'Location of the file system image'")
+                .arg("<dest> 'Directory to copy into, or archive to write with --tar (- for stdout)'")
+                .arg(
+                    clap::Arg::with_name("tar")
+                        .short('t')
+                        .long("tar")
+                        .about("Write a tar archive instead of a directory."),
+                ),
+        ).subcommand(
+            clap::App::new("resize")
+                .about("Grow or shrink a file system image")
+                .arg("<image> 'Location of the file system image'")
+                .arg(
+                    clap::Arg::with_name("size")
+                        .short('s')
+                        .long("size")
+                        .takes_value(true)
+                        .about("Specify the new total size of the file system. As with mkfs, the final size might be bigger than the provided value.").required(true),
+                ),
+        ).subcommand(
+            clap::App::new("scrub")
+                .about("Verify the checksums of every inode, directory and data block")
+                .arg("<image> 'Location of the file system image'")
+        )
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("mkfs") {
+        let blk_size = matches
+            .value_of("block-size")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap();
+        let file_name = matches.value_of("file").unwrap();
+        let file_size = matches.value_of("size").unwrap();
+
+        let file_size = match Byte::from_str(file_size) {
+            Ok(size) => size.get_bytes(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let options = mkfs::Options {
+            extents: matches.is_present("extents"),
+            checksums: matches.is_present("checksums"),
+        };
+
+        mkfs::make(file_name, file_size, blk_size, options)?;
+        if let Some(dir) = matches.value_of("from") {
+            mkfs::populate(file_name, dir)?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("mount") {
+        let image = matches.value_of("image").unwrap();
+        let mountpoint = matches.value_of("mountpoint").unwrap();
+
+        let scrub_interval = match matches.value_of("scrub-interval") {
+            Some(ms) => Some(Duration::from_millis(ms.parse()?)),
+            None => None,
+        };
+
+        let mmap = !matches.is_present("no-mmap");
+        mount::mount(
+            image,
+            mountpoint,
+            scrub_interval,
+            mmap,
+            |event| match event {
+                Event::Corruption(corruption) => eprintln!("scrub: {}", corruption),
+                Event::PassFinished(found) => {
+                    eprintln!("scrub: pass finished, {} corruptions found", found)
+                }
+            },
+        )?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        let image = matches.value_of("image").unwrap();
+        let problems = fsck::check(image, matches.is_present("repair"))?;
+        for problem in &problems {
+            if problem.repaired {
+                println!("{} (repaired)", problem.description);
+            } else {
+                println!("{}", problem.description);
+            }
+        }
+
+        let left = problems.iter().filter(|p| !p.repaired).count();
+        println!(
+            "{}: {} problems found, {} repaired",
+            image,
+            problems.len(),
+            problems.len() - left
+        );
+        if left > 0 {
+            return Err(anyhow!("{} problems left", left));
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("scrub") {
+        let image = matches.value_of("image").unwrap();
+        let corruptions = gotenks::scrub::scrub(image)?;
+        for corruption in &corruptions {
+            println!("{}", corruption);
+        }
+
+        println!("{}: {} corruptions found", image, corruptions.len());
+        if !corruptions.is_empty() {
+            return Err(anyhow!("{} corruptions found", corruptions.len()));
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("resize") {
+        let image = matches.value_of("image").unwrap();
+        let size = match Byte::from_str(matches.value_of("size").unwrap()) {
+            Ok(size) => size.get_bytes(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let groups = resize::resize(image, size)?;
+        println!("{}: {} block groups", image, groups);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("extract") {
+        let image = matches.value_of("image").unwrap();
+        let dest = matches.value_of("dest").unwrap();
+
+        if !matches.is_present("tar") {
+            extract::extract(image, dest)?;
+        } else if dest == "-" {
+            extract::export_tar(image, std::io::stdout().lock())?;
+        } else {
+            extract::export_tar(image, BufWriter::new(File::create(dest)?))?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("dumpfs") {
+        let image = matches.value_of("image").unwrap();
+        let inode = match matches.value_of("inode") {
+            Some(index) => Some(index.parse()?),
+            None => None,
+        };
+
+        let value = dumpfs::dump(image, inode, matches.value_of("path"))?;
+        if matches.is_present("json") {
+            println!("{}", value.to_json());
+        } else {
+            print!("{}", value.to_text());
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("debug") {
+        debug::run(
+            matches.value_of("image").unwrap(),
+            matches.is_present("force"),
+        )?;
+    }
+
+    Ok(())
+}
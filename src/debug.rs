@@ -0,0 +1,391 @@
+use crate::{
+    dumpfs,
+    gotenks::{fs::GotenksFS, types::Inode, STATE_DIRTY},
+};
+use anyhow::anyhow;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    str::FromStr,
+};
+
+const HELP: &str = "\
+ls [dir]                          List the entries of a directory
+stat <inode>                      Print an inode and its block pointers
+cat <inode>                       Print the contents of a file
+blocks <inode>                    List the blocks an inode uses
+setfield <inode> <field> <value>  Change a field of an inode
+freeblock <block>...              Mark blocks as free
+help                              Print this message
+quit                              Leave the shell
+
+Inodes are given by index or by absolute path. Modes are in octal and - \
+clears a timestamp.
+";
+
+// Commands to look at an unmounted image and patch it by hand. Changes skip
+// every check the file system would make, but go through the journal like
+// any other update. An image that may be mounted is opened read-only unless
+// `force` is given.
+pub struct Shell {
+    fs: GotenksFS,
+    read_only: bool,
+}
+
+impl Shell {
+    pub fn new<P>(image_path: P, force: bool) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let fs = GotenksFS::read_only(image_path.as_ref())?;
+        if fs.superblock().state & STATE_DIRTY != 0 && !force {
+            return Ok(Self {
+                fs,
+                read_only: true,
+            });
+        }
+
+        drop(fs);
+        Ok(Self {
+            fs: GotenksFS::new(image_path)?,
+            read_only: false,
+        })
+    }
+
+    fn check_writable(&self) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow!(
+                "The image is mounted or wasn't cleanly unmounted, use --force to change it"
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns false once the shell should exit.
+    pub fn execute<W>(&mut self, line: &str, out: &mut W) -> anyhow::Result<bool>
+    where
+        W: Write,
+    {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(true),
+        };
+
+        match (command, args) {
+            ("ls", []) => self.ls("/", out)?,
+            ("ls", [dir]) => self.ls(dir, out)?,
+            ("stat", [inode]) => {
+                let index = self.inode_index(inode)?;
+                write!(out, "{}", dumpfs::dump_inode(&self.fs, index)?.to_text())?;
+            }
+            ("cat", [inode]) => self.cat(inode, out)?,
+            ("blocks", [inode]) => {
+                let inode = self.inode(inode)?.0;
+                let blocks: Vec<String> = self
+                    .fs
+                    .inode_blocks(&inode)?
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect();
+                writeln!(out, "{}", blocks.join(" "))?;
+            }
+            ("setfield", [inode, field, value]) => self.set_field(inode, field, value)?,
+            ("freeblock", blocks) if !blocks.is_empty() => self.free_blocks(blocks)?,
+            ("help", []) => write!(out, "{}", HELP)?,
+            ("quit", []) | ("exit", []) => return Ok(false),
+            _ => return Err(anyhow!("{}: unknown command or wrong arguments", command)),
+        }
+
+        Ok(true)
+    }
+
+    fn inode_index(&self, arg: &str) -> anyhow::Result<u32> {
+        if arg.starts_with('/') {
+            let (_, index) = self
+                .fs
+                .find_inode_from_path(arg)
+                .map_err(|e| anyhow!("{}: {}", arg, e))?;
+            return Ok(index);
+        }
+
+        let index = arg
+            .parse()
+            .map_err(|_| anyhow!("{}: not an inode index or an absolute path", arg))?;
+        if index == 0 || index > self.fs.superblock().inode_count {
+            return Err(anyhow!("Inode {} is out of range", index));
+        }
+        Ok(index)
+    }
+
+    fn inode(&self, arg: &str) -> anyhow::Result<(Inode, u32)> {
+        let index = self.inode_index(arg)?;
+        let inode = self
+            .fs
+            .find_inode(index)
+            .map_err(|e| anyhow!("Inode {}: {}", index, e))?;
+        Ok((inode, index))
+    }
+
+    fn ls<W: Write>(&self, dir: &str, out: &mut W) -> anyhow::Result<()> {
+        let index = self.inode_index(dir)?;
+        let entries = self
+            .fs
+            .find_dir_from_inode(index)
+            .map_err(|e| anyhow!("{}: {}", dir, e))?
+            .entries;
+        for (name, index) in entries {
+            let name = name.to_string_lossy();
+            match self.fs.find_inode(index) {
+                Ok(inode) => writeln!(
+                    out,
+                    "{:>8} {:>7o} {:>10} {}",
+                    index, inode.mode, inode.size, name
+                )?,
+                Err(_) => writeln!(out, "{:>8} {:>7} {:>10} {}", index, "?", "?", name)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cat<W: Write>(&self, arg: &str, out: &mut W) -> anyhow::Result<()> {
+        let (inode, index) = self.inode(arg)?;
+        if inode.is_dir() {
+            return Err(anyhow!("{}: is a directory", arg));
+        }
+
+        if inode.is_fast_symlink() {
+            let mut target = vec![0u8; inode.size as usize];
+            let start = self.fs.inode_seek_position(index) + inode.inline_data_offset();
+            self.fs.device().read_at(&mut target, start)?;
+            return Ok(out.write_all(&target)?);
+        }
+
+        let blk_size = self.fs.superblock().block_size as u64;
+        let mut buf = vec![0u8; blk_size as usize];
+        let mut offset = 0;
+        while offset < inode.size {
+            let read = self
+                .fs
+                .read_inode_data(&inode, &mut buf, offset)
+                .map_err(|e| anyhow!("{}: {}", arg, e))?;
+            out.write_all(&buf[..read])?;
+            offset += read as u64;
+        }
+
+        Ok(())
+    }
+
+    fn set_field(&mut self, arg: &str, field: &str, value: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
+        let (mut inode, index) = self.inode(arg)?;
+        let time = || match value {
+            "-" => Ok(None),
+            _ => parse(field, value).map(Some),
+        };
+
+        match field {
+            "mode" => {
+                inode.mode = u32::from_str_radix(value, 8)
+                    .map_err(|_| anyhow!("{}: invalid value for {}", value, field))?
+            }
+            "hard_links" => inode.hard_links = parse(field, value)?,
+            "user_id" => inode.user_id = parse(field, value)?,
+            "group_id" => inode.group_id = parse(field, value)?,
+            "size" => inode.size = parse(field, value)?,
+            "block_count" => inode.block_count = parse(field, value)?,
+            "created_at" => inode.created_at = parse(field, value)?,
+            "accessed_at" => inode.accessed_at = time()?,
+            "modified_at" => inode.modified_at = time()?,
+            "changed_at" => inode.changed_at = time()?,
+            "flags" => inode.flags = parse(field, value)?,
+            "indirect_block" => inode.indirect_block = parse(field, value)?,
+            "double_indirect_block" => inode.double_indirect_block = parse(field, value)?,
+            "triple_indirect_block" => inode.triple_indirect_block = parse(field, value)?,
+            _ => {
+                // direct_blocks[N]
+                let i = field
+                    .strip_prefix("direct_blocks[")
+                    .and_then(|f| f.strip_suffix(']'))
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .filter(|i| *i < inode.direct_blocks.len())
+                    .ok_or_else(|| anyhow!("{}: unknown field", field))?;
+                inode.direct_blocks[i] = parse(field, value)?;
+            }
+        }
+
+        self.fs.save_inode(inode, index)?;
+        self.fs.commit()
+    }
+
+    fn free_blocks(&mut self, args: &[&str]) -> anyhow::Result<()> {
+        self.check_writable()?;
+        let mut blocks = vec![];
+        for arg in args {
+            let block: u32 = arg
+                .parse()
+                .map_err(|_| anyhow!("{}: not a block index", arg))?;
+            if block == 0 || block > self.fs.superblock().block_count {
+                return Err(anyhow!("Block {} is out of range", block));
+            }
+
+            let (group_index, bitmap_index) = self.fs.data_block_offsets(block);
+            if !self.fs.groups()[group_index as usize].has_data_block(bitmap_index as usize + 1)
+                || blocks.contains(&block)
+            {
+                return Err(anyhow!("Block {} is already free", block));
+            }
+            blocks.push(block);
+        }
+
+        self.fs.release_data_blocks(&blocks);
+        self.fs.commit()
+    }
+}
+
+// Parses `value` into the type of `field`, refusing what doesn't fit.
+fn parse<T: FromStr>(field: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("{}: invalid value for {}", value, field))
+}
+
+pub fn run<P>(image_path: P, force: bool) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut shell = Shell::new(image_path, force)?;
+    if shell.read_only {
+        eprintln!("The image is mounted or wasn't cleanly unmounted, opened read-only");
+    } else if shell.fs.superblock().state & STATE_DIRTY != 0 {
+        eprintln!("The image is mounted or wasn't cleanly unmounted");
+    }
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    loop {
+        print!("debug: ");
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        match shell.execute(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gotenks::util, mkfs};
+    use fuse_rs::Filesystem;
+    use nix::sys::stat::Mode;
+
+    const BLOCK_SIZE: u32 = 128;
+
+    fn execute(shell: &mut Shell, line: &str) -> anyhow::Result<String> {
+        let mut out = vec![];
+        shell.execute(line, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn debug_shell() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "debug",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.create_dir(Path::new("/dir"), Mode::S_IRWXU)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(Path::new("/dir/foo.txt"), Mode::S_IRWXU, &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let data: Vec<u8> = (0..200).map(|i| b'a' + i % 26).collect();
+        fs.write(Path::new("/dir/foo.txt"), &data, 0, &mut write_file_info)?;
+        fs.symlink(Path::new("foo.txt"), Path::new("/dir/link"))?;
+        let foo = open_fi.handle().unwrap() as u32;
+        let blocks = fs.find_inode(foo)?.direct_blocks;
+        fs.destroy()?;
+
+        let mut shell = Shell::new(&tmp_file, false)?;
+        let ls = execute(&mut shell, "ls /dir")?;
+        let lines: Vec<&str> = ls.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" 200 foo.txt"));
+        assert!(lines[0].trim_start().starts_with(&foo.to_string()));
+        assert!(lines[1].ends_with(" link"));
+
+        assert_eq!(
+            execute(&mut shell, "cat /dir/foo.txt")?.as_bytes(),
+            &data[..]
+        );
+        assert_eq!(execute(&mut shell, "cat /dir/link")?, "foo.txt");
+        assert_eq!(
+            execute(&mut shell, &format!("blocks {}", foo))?,
+            format!("{} {}\n", blocks[0], blocks[1])
+        );
+        assert!(execute(&mut shell, "stat /dir/foo.txt")?.contains("size: 200\n"));
+
+        execute(&mut shell, "setfield /dir/foo.txt size 5")?;
+        execute(&mut shell, &format!("setfield {} direct_blocks[1] 0", foo))?;
+        execute(&mut shell, &format!("freeblock {}", blocks[1]))?;
+        assert!(execute(&mut shell, &format!("freeblock {}", blocks[1])).is_err());
+        assert!(execute(&mut shell, "setfield /dir/foo.txt nope 1").is_err());
+        assert!(execute(&mut shell, "setfield /dir/foo.txt hard_links 65536").is_err());
+        assert!(execute(&mut shell, "setfield /dir/foo.txt user_id 4294967296").is_err());
+        assert!(execute(&mut shell, "setfield /dir/foo.txt direct_blocks[0] -1").is_err());
+        assert!(execute(&mut shell, "cat /dir").is_err());
+        assert!(execute(&mut shell, "stat 0").is_err());
+        assert!(execute(&mut shell, "rm /dir").is_err());
+        assert!(!shell.execute("quit", &mut vec![])?);
+        drop(shell);
+
+        let fs = GotenksFS::new(&tmp_file)?;
+        let inode = fs.find_inode(foo)?;
+        assert_eq!(inode.size, 5);
+        assert_eq!(inode.direct_blocks[1], 0);
+        let (group_index, bitmap_index) = fs.data_block_offsets(blocks[1]);
+        assert!(!fs.groups()[group_index as usize].has_data_block(bitmap_index as usize + 1));
+        assert_eq!(
+            fs.superblock().free_blocks,
+            fs.groups()[0].free_data_blocks() as u32
+        );
+        assert_eq!(inode.hard_links, 1);
+        assert_eq!(inode.user_id, fs.find_inode(foo)?.user_id);
+        drop(fs);
+
+        // Looks mounted: nothing can be changed without forcing it.
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
+        drop(fs);
+        let before = std::fs::read(&tmp_file)?;
+        let mut shell = Shell::new(&tmp_file, false)?;
+        assert!(execute(&mut shell, "setfield /dir/foo.txt size 1").is_err());
+        assert!(execute(&mut shell, &format!("freeblock {}", blocks[0])).is_err());
+        assert!(execute(&mut shell, "stat /dir/foo.txt")?.contains("size: 5\n"));
+        drop(shell);
+        assert_eq!(std::fs::read(&tmp_file)?, before);
+
+        let mut shell = Shell::new(&tmp_file, true)?;
+        execute(&mut shell, "setfield /dir/foo.txt size 1")?;
+        drop(shell);
+        assert_eq!(GotenksFS::new(&tmp_file)?.find_inode(foo)?.size, 1);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::gotenks::{
+    fs::GotenksFS,
+    types::{ExtentNode, Inode},
+};
+use anyhow::anyhow;
+use std::{fmt::Write, path::Path};
+
+// What gets dumped, printed either as indented `key: value` lines or as JSON.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+macro_rules! number_from {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Self {
+                Value::Number(n.to_string())
+            }
+        })*
+    };
+}
+
+number_from!(u16, u32, u64, i64, usize);
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Value::Null, Into::into)
+    }
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+impl Value {
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) => out.push_str(n),
+            Value::String(s) => write_json_string(s, out),
+            Value::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_json(out);
+                }
+                out.push(']');
+            }
+            Value::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_json_string(key, out);
+                    out.push(':');
+                    value.write_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.write_text(0, &mut out);
+        out
+    }
+
+    // Objects and arrays of objects go one per line, anything else inline.
+    fn write_text(&self, indent: usize, out: &mut String) {
+        let pad = " ".repeat(indent);
+        match self {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    if value.is_nested() {
+                        let _ = writeln!(out, "{}{}:", pad, key);
+                        value.write_text(indent + 2, out);
+                    } else {
+                        let _ = writeln!(out, "{}{}: {}", pad, key, value.inline());
+                    }
+                }
+            }
+            Value::Array(items) if self.is_nested() => {
+                for item in items {
+                    let _ = writeln!(out, "{}-", pad);
+                    item.write_text(indent + 2, out);
+                }
+            }
+            _ => {
+                let _ = writeln!(out, "{}{}", pad, self.inline());
+            }
+        }
+    }
+
+    fn is_nested(&self) -> bool {
+        match self {
+            Value::Object(fields) => !fields.is_empty(),
+            Value::Array(items) => items.iter().any(|i| i.is_nested()),
+            _ => false,
+        }
+    }
+
+    fn inline(&self) -> String {
+        match self {
+            Value::Null => "-".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.clone(),
+            Value::String(s) => s.clone(),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(|i| i.inline()).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Object(_) => "{}".to_string(),
+        }
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// The superblock and the free inodes and blocks of each group.
+pub fn dump_fs(fs: &GotenksFS) -> Value {
+    let sb = fs.superblock();
+    let superblock = object(vec![
+        ("magic", format!("{:#x}", sb.magic).into()),
+        ("revision", sb.revision.into()),
+        ("features", format!("{:#x}", sb.features).into()),
+        ("block_size", sb.block_size.into()),
+        ("created_at", sb.created_at.into()),
+        ("modified_at", sb.modified_at.into()),
+        ("last_mounted_at", sb.last_mounted_at.into()),
+        ("block_count", sb.block_count.into()),
+        ("inode_count", sb.inode_count.into()),
+        ("free_blocks", sb.free_blocks.into()),
+        ("free_inodes", sb.free_inodes.into()),
+        ("groups", sb.groups.into()),
+        ("data_blocks_per_group", sb.data_blocks_per_group.into()),
+        ("uid", sb.uid.into()),
+        ("gid", sb.gid.into()),
+        ("inode_flags", sb.inode_flags.into()),
+        ("journal_blocks", sb.journal_blocks.into()),
+        ("state", sb.state.into()),
+        ("mount_count", sb.mount_count.into()),
+        ("max_mount_count", sb.max_mount_count.into()),
+        ("checksum", format!("{:#010x}", sb.checksum).into()),
+    ]);
+
+    let backups = sb.backup_groups();
+    let groups = fs
+        .groups()
+        .iter()
+        .enumerate()
+        .map(|(i, g)| {
+            object(vec![
+                ("index", i.into()),
+                ("free_inodes", g.free_inodes().into()),
+                ("free_blocks", g.free_data_blocks().into()),
+                ("backup_superblock", backups.contains(&(i as u32)).into()),
+            ])
+        })
+        .collect();
+
+    object(vec![
+        ("superblock", superblock),
+        ("groups", Value::Array(groups)),
+    ])
+}
+
+// Reads the inode even when the bitmap says it's free, that's also worth
+// looking at when something is off.
+pub fn dump_inode(fs: &GotenksFS, index: u32) -> anyhow::Result<Value> {
+    if index == 0 || index > fs.superblock().inode_count {
+        return Err(anyhow!("Inode {} is out of range", index));
+    }
+    let inode = fs.read_inode(index)?;
+    let (group_index, bitmap_index) = fs.inode_offsets(index);
+    let allocated = fs.groups()[group_index as usize].has_inode(bitmap_index as usize + 1);
+
+    let mut fields = vec![
+        ("index", index.into()),
+        ("allocated", allocated.into()),
+        ("mode", format!("{:o}", inode.mode).into()),
+        ("hard_links", inode.hard_links.into()),
+        ("user_id", inode.user_id.into()),
+        ("group_id", inode.group_id.into()),
+        ("size", inode.size.into()),
+        ("block_count", inode.block_count.into()),
+        ("created_at", inode.created_at.into()),
+        ("accessed_at", inode.accessed_at.into()),
+        ("modified_at", inode.modified_at.into()),
+        ("changed_at", inode.changed_at.into()),
+        ("flags", inode.flags.into()),
+        ("offset", fs.inode_seek_position(index).into()),
+    ];
+
+    if inode.is_fast_symlink() {
+        fields.push(("inline_data", true.into()));
+    } else if inode.uses_extents() {
+        fields.push(("extents", extent_tree(fs, &inode.extent_root())?));
+    } else {
+        let direct = inode.direct_blocks.iter().map(|b| (*b).into()).collect();
+        fields.push(("direct_blocks", Value::Array(direct)));
+        let names = ["indirect", "double_indirect", "triple_indirect"];
+        for (depth, name) in (1..=3).zip(names.iter()) {
+            let tree = indirect_tree(fs, indirect_root(&inode, depth), depth)?;
+            fields.push((*name, tree));
+        }
+    }
+
+    if inode.is_dir() {
+        let entries = fs
+            .find_dir_from_inode(index)?
+            .entries
+            .into_iter()
+            .map(|(name, index)| (name.to_string_lossy().into_owned(), index.into()))
+            .collect();
+        fields.push(("entries", Value::Object(entries)));
+    }
+
+    Ok(object(fields))
+}
+
+fn indirect_root(inode: &Inode, depth: u32) -> u32 {
+    match depth {
+        1 => inode.indirect_block,
+        2 => inode.double_indirect_block,
+        _ => inode.triple_indirect_block,
+    }
+}
+
+// Pointers past the last one in use are left out.
+fn indirect_tree(fs: &GotenksFS, block: u32, depth: u32) -> anyhow::Result<Value> {
+    if block == 0 {
+        return Ok(Value::Null);
+    }
+
+    let count = fs.superblock().block_size as u64 / 4;
+    let mut pointers = (0..count)
+        .map(|i| fs.read_u32(i, block))
+        .collect::<anyhow::Result<Vec<u32>>>()?;
+    while pointers.last() == Some(&0) {
+        pointers.pop();
+    }
+
+    let children = if depth == 1 {
+        (
+            "pointers",
+            Value::Array(pointers.into_iter().map(Into::into).collect()),
+        )
+    } else {
+        let children = pointers
+            .into_iter()
+            .map(|p| indirect_tree(fs, p, depth - 1))
+            .collect::<anyhow::Result<Vec<Value>>>()?;
+        ("children", Value::Array(children))
+    };
+
+    Ok(object(vec![("block", block.into()), children]))
+}
+
+fn extent_tree(fs: &GotenksFS, node: &ExtentNode) -> anyhow::Result<Value> {
+    let mut extents = vec![];
+    for extent in &node.extents {
+        let mut fields = vec![
+            ("logical", extent.logical.into()),
+            ("physical", extent.physical.into()),
+            ("len", extent.len.into()),
+        ];
+        if node.depth > 0 {
+            let child = fs.read_extent_node(extent.physical)?;
+            fields.push(("child", extent_tree(fs, &child)?));
+        }
+        extents.push(object(fields));
+    }
+
+    Ok(object(vec![
+        ("depth", node.depth.into()),
+        ("extents", Value::Array(extents)),
+    ]))
+}
+
+pub fn dump<P>(image_path: P, inode: Option<u32>, path: Option<&str>) -> anyhow::Result<Value>
+where
+    P: AsRef<Path>,
+{
+    let fs = GotenksFS::read_only(image_path)?;
+    match (inode, path) {
+        (Some(index), _) => dump_inode(&fs, index),
+        (None, Some(path)) => {
+            let (_, index) = fs
+                .find_inode_from_path(path)
+                .map_err(|e| anyhow!("{}: {}", path, e))?;
+            dump_inode(&fs, index)
+        }
+        (None, None) => Ok(dump_fs(&fs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gotenks::util, mkfs};
+    use fuse_rs::Filesystem;
+    use nix::sys::stat::Mode;
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn render() {
+        let value = object(vec![
+            ("name", "a \"b\"\n".to_string().into()),
+            ("size", 10u64.into()),
+            ("blocks", Value::Array(vec![1u32.into(), 2u32.into()])),
+            ("group", object(vec![("index", 0usize.into())])),
+            ("none", None::<u64>.into()),
+        ]);
+
+        assert_eq!(
+            value.to_json(),
+            r#"{"name":"a \"b\"\n","size":10,"blocks":[1,2],"group":{"index":0},"none":null}"#
+        );
+        assert_eq!(
+            value.to_text(),
+            "name: a \"b\"\n\nsize: 10\nblocks: [1, 2]\ngroup:\n  index: 0\nnone: -\n"
+        );
+    }
+
+    #[test]
+    fn dump_image() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "dumpfs",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(Path::new("/foo.txt"), Mode::S_IRWXU, &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        // Only the first block past the direct pointers
+        let offset = BLOCK_SIZE as u64 * 12;
+        fs.write(
+            Path::new("/foo.txt"),
+            &[1u8; 10],
+            offset,
+            &mut write_file_info,
+        )?;
+        fs.destroy()?;
+
+        let image = std::fs::read(&tmp_file)?;
+        let json = dump(&tmp_file, None, None)?.to_json();
+        assert!(json.contains(r#""block_size":128"#));
+        assert!(json.contains(r#""groups":[{"index":0,"free_inodes":1022,"#));
+
+        let (root, file) = (
+            dump(&tmp_file, Some(1), None)?,
+            dump(&tmp_file, None, Some("/foo.txt"))?,
+        );
+        assert!(root.to_json().contains(r#""entries":{"foo.txt":2}"#));
+        let json = file.to_json();
+        assert!(json.contains(r#""direct_blocks":[0,0,0,0,0,0,0,0,0,0,0,0]"#));
+        assert!(json.contains(r#""indirect":{"block":3,"pointers":[2]}"#));
+        assert!(json.contains(r#""double_indirect":null"#));
+        assert!(file
+            .to_text()
+            .contains("indirect:\n  block: 3\n  pointers: [2]\n"));
+        assert!(dump(&tmp_file, Some(0), None).is_err());
+        // Dumping doesn't even count as a mount
+        assert_eq!(std::fs::read(&tmp_file)?, image);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,438 @@
+use crate::gotenks::{fs::GotenksFS, types::Inode, ROOT_INODE};
+use anyhow::anyhow;
+use fuse_rs::Filesystem;
+use nix::{
+    sys::{stat, time::TimeVal, time::TimeValLike},
+    unistd::{self, FchownatFlags, Gid, Uid},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAR_BLOCK_SIZE: usize = 512;
+
+struct Entry {
+    path: PathBuf, // relative to the root
+    index: u32,
+    inode: Inode,
+}
+
+// Every file under the root, each directory before its contents. A damaged
+// image may have names that would land outside of the root, or directories
+// linked from more than one place, so these are refused.
+fn entries(fs: &GotenksFS) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut visited = HashSet::new();
+    visited.insert(ROOT_INODE);
+    collect_entries(fs, ROOT_INODE, PathBuf::new(), &mut entries, &mut visited)?;
+    Ok(entries)
+}
+
+fn collect_entries(
+    fs: &GotenksFS,
+    dir_index: u32,
+    dir_path: PathBuf,
+    entries: &mut Vec<Entry>,
+    visited: &mut HashSet<u32>,
+) -> anyhow::Result<()> {
+    let dir = fs
+        .find_dir_from_inode(dir_index)
+        .map_err(|e| anyhow!("/{}: {}", dir_path.display(), e))?;
+    for (name, index) in dir.entries {
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => {
+                return Err(anyhow!(
+                    "/{}: invalid name {:?}, run fsck first",
+                    dir_path.display(),
+                    name
+                ))
+            }
+        }
+
+        let path = dir_path.join(name);
+        let inode = fs
+            .find_inode(index)
+            .map_err(|e| anyhow!("/{}: {}", path.display(), e))?;
+        let is_dir = inode.is_dir();
+        if is_dir && !visited.insert(index) {
+            return Err(anyhow!(
+                "/{}: directory linked more than once, run fsck first",
+                path.display()
+            ));
+        }
+
+        entries.push(Entry {
+            path: path.clone(),
+            index,
+            inode,
+        });
+        if is_dir {
+            collect_entries(fs, index, path, entries, visited)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Copies the whole tree into `dest`, which is created if needed, keeping
+// modes, timestamps, symlinks, hard links and holes. Ownership is only kept
+// when allowed to change it.
+pub fn extract<P, Q>(image_path: P, dest: Q) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let fs = GotenksFS::read_only(image_path)?;
+    let dest = dest.as_ref();
+    fs::create_dir_all(dest)?;
+
+    let mut links = HashMap::new();
+    let entries = entries(&fs)?;
+    for entry in &entries {
+        let path = dest.join(&entry.path);
+        if entry.inode.is_dir() {
+            fs::create_dir(&path)?;
+            set_owner(&path, &entry.inode);
+            continue;
+        } else if entry.inode.is_symlink() {
+            let target = fs.read_link(&Path::new("/").join(&entry.path))?;
+            std::os::unix::fs::symlink(target, &path)?;
+        } else if let Some(first) = links.get(&entry.index) {
+            fs::hard_link(first, &path)?;
+            continue;
+        } else {
+            extract_file(&fs, &entry.inode, &path)?;
+            if entry.inode.hard_links > 1 {
+                links.insert(entry.index, path.clone());
+            }
+        }
+
+        set_metadata(&path, &entry.inode)?;
+    }
+
+    // Creating their contents changed the times of the directories, and
+    // needed them to be writable.
+    for entry in entries.iter().rev().filter(|e| e.inode.is_dir()) {
+        let path = dest.join(&entry.path);
+        set_mode(&path, &entry.inode)?;
+        set_times(&path, &entry.inode)?;
+    }
+
+    Ok(())
+}
+
+fn extract_file(fs: &GotenksFS, inode: &Inode, path: &Path) -> anyhow::Result<()> {
+    let mut file = File::create(path)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < inode.size {
+        let read = fs.read_inode_data(inode, &mut buf, offset)?;
+        if buf[..read].iter().any(|b| *b != 0) {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&buf[..read])?;
+        }
+        offset += read as u64;
+    }
+
+    Ok(file.set_len(inode.size)?)
+}
+
+fn set_metadata(path: &Path, inode: &Inode) -> anyhow::Result<()> {
+    set_owner(path, inode);
+    if !inode.is_symlink() {
+        set_mode(path, inode)?;
+    }
+
+    set_times(path, inode)
+}
+
+fn set_owner(path: &Path, inode: &Inode) {
+    let _ = unistd::fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(inode.user_id)),
+        Some(Gid::from_raw(inode.group_id)),
+        FchownatFlags::NoFollowSymlink,
+    );
+}
+
+fn set_mode(path: &Path, inode: &Inode) -> anyhow::Result<()> {
+    Ok(fs::set_permissions(
+        path,
+        fs::Permissions::from_mode(inode.mode & 0o7777),
+    )?)
+}
+
+fn set_times(path: &Path, inode: &Inode) -> anyhow::Result<()> {
+    let atime = TimeVal::seconds(inode.accessed_at.unwrap_or(0));
+    let mtime = TimeVal::seconds(inode.modified_at.unwrap_or(0));
+    Ok(stat::lutimes(path, &atime, &mtime)?)
+}
+
+// Writes the whole tree as an ustar archive, with GNU long names for paths
+// that don't fit in the header.
+pub fn export_tar<P, W>(image_path: P, mut w: W) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let fs = GotenksFS::read_only(image_path)?;
+    let mut links: HashMap<u32, String> = HashMap::new();
+    for entry in entries(&fs)? {
+        let mut name = entry.path.to_string_lossy().into_owned();
+        let mut header = TarHeader::new(&entry.inode);
+        if entry.inode.is_dir() {
+            name.push('/');
+            header.kind = b'5';
+        } else if entry.inode.is_symlink() {
+            let target = fs.read_link(&Path::new("/").join(&entry.path))?;
+            header.link = target.to_string_lossy().into_owned();
+            header.kind = b'2';
+        } else if let Some(first) = links.get(&entry.index) {
+            header.link = first.clone();
+            header.kind = b'1';
+        } else {
+            header.size = entry.inode.size;
+            if entry.inode.hard_links > 1 {
+                links.insert(entry.index, name.clone());
+            }
+        }
+        header.name = name;
+        header.write(&mut w)?;
+
+        if header.size > 0 {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let mut offset = 0;
+            while offset < entry.inode.size {
+                let read = fs.read_inode_data(&entry.inode, &mut buf, offset)?;
+                w.write_all(&buf[..read])?;
+                offset += read as u64;
+            }
+            write_padding(&mut w, entry.inode.size)?;
+        }
+    }
+
+    // The archive ends with two empty blocks.
+    w.write_all(&[0u8; 2 * TAR_BLOCK_SIZE])?;
+    Ok(w.flush()?)
+}
+
+struct TarHeader {
+    name: String,
+    link: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: i64,
+    kind: u8,
+}
+
+impl TarHeader {
+    fn new(inode: &Inode) -> Self {
+        Self {
+            name: String::new(),
+            link: String::new(),
+            mode: inode.mode & 0o7777,
+            uid: inode.user_id,
+            gid: inode.group_id,
+            size: 0,
+            mtime: inode.modified_at.unwrap_or(0),
+            kind: b'0',
+        }
+    }
+
+    fn write<W>(&self, w: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        // Names that don't fit come in an entry of their own right before.
+        if self.name.len() > 100 {
+            Self::write_long(w, b'L', &self.name)?;
+        }
+        if self.link.len() > 100 {
+            Self::write_long(w, b'K', &self.link)?;
+        }
+
+        let mut block = [0u8; TAR_BLOCK_SIZE];
+        put_str(&mut block[0..100], &self.name);
+        put_octal(&mut block[100..108], self.mode as u64);
+        put_octal(&mut block[108..116], self.uid as u64);
+        put_octal(&mut block[116..124], self.gid as u64);
+        put_size(&mut block[124..136], self.size);
+        put_octal(&mut block[136..148], self.mtime.max(0) as u64);
+        block[156] = self.kind;
+        put_str(&mut block[157..257], &self.link);
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field filled with spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = block.iter().map(|b| *b as u32).sum();
+        block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        Ok(w.write_all(&block)?)
+    }
+
+    fn write_long<W>(w: &mut W, kind: u8, name: &str) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let header = TarHeader {
+            name: "././@LongLink".to_string(),
+            link: String::new(),
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: name.len() as u64 + 1,
+            mtime: 0,
+            kind,
+        };
+        header.write(w)?;
+        w.write_all(name.as_bytes())?;
+        w.write_all(&[0])?;
+        write_padding(w, header.size)
+    }
+}
+
+fn write_padding<W>(w: &mut W, size: u64) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    let rem = (size % TAR_BLOCK_SIZE as u64) as usize;
+    if rem != 0 {
+        w.write_all(&vec![0u8; TAR_BLOCK_SIZE - rem])?;
+    }
+    Ok(())
+}
+
+// Truncated names are only ever stored after a long name entry.
+fn put_str(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len());
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    put_str(field, &digits);
+}
+
+// Sizes past 8 GiB don't fit in octal, GNU tar stores them in base 256.
+fn put_size(field: &mut [u8], size: u64) {
+    if size < 1 << 33 {
+        put_octal(field, size);
+        return;
+    }
+
+    field[0] = 0x80;
+    let len = field.len();
+    field[len - 8..].copy_from_slice(&size.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gotenks::util, mkfs};
+    use nix::sys::stat::Mode;
+    use std::{ffi::OsStr, os::unix::fs::MetadataExt};
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn extract_and_export() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "extract",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.create_dir(Path::new("/sub"), Mode::S_IRWXU)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        let mode = Mode::from_bits_truncate(0o640);
+        fs.create(Path::new("/sub/foo.txt"), mode, &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        fs.write(Path::new("/sub/foo.txt"), &data, 0, &mut write_file_info)?;
+        fs.hard_link(Path::new("/sub/foo.txt"), Path::new("/bar.txt"))?;
+        fs.symlink(Path::new("sub/foo.txt"), Path::new("/link"))?;
+        let long_name = format!("/d/{}", "x".repeat(100));
+        fs.create_dir(Path::new("/d"), Mode::S_IRWXU)?;
+        fs.create(Path::new(&long_name), Mode::S_IRWXU, &mut open_fi)?;
+        let d = fs.find_dir(Path::new("/d"))?;
+        let mut inode = fs.find_inode(d)?;
+        inode.mode = inode.mode & !0o7777 | 0o555;
+        fs.save_inode(inode, d)?;
+        fs.destroy()?;
+
+        let mut dest = std::env::temp_dir();
+        dest.push("extract");
+        if dest.exists() {
+            fs::remove_dir_all(&dest)?;
+        }
+        let image = fs::read(&tmp_file)?;
+        extract(&tmp_file, &dest)?;
+        assert_eq!(fs::read(dest.join("bar.txt"))?, data);
+        let meta = fs::metadata(dest.join("sub/foo.txt"))?;
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+        assert_eq!(meta.nlink(), 2);
+        assert_eq!(fs::read_link(dest.join("link"))?, Path::new("sub/foo.txt"));
+        assert!(dest.join(&long_name[1..]).exists());
+        let meta = fs::metadata(dest.join("d"))?;
+        assert_eq!(meta.mode() & 0o7777, 0o555);
+
+        let mut tar = vec![];
+        export_tar(&tmp_file, &mut tar)?;
+        assert_eq!(fs::read(&tmp_file)?, image);
+        assert_eq!(tar.len() % TAR_BLOCK_SIZE, 0);
+        // Entries come sorted by name, each directory before its contents
+        let header = &tar[..TAR_BLOCK_SIZE];
+        assert_eq!(&header[..7], b"bar.txt");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[124..135], b"00000001750");
+        assert_eq!(&header[257..263], b"ustar\0");
+        let checksum: u32 = header[..148]
+            .iter()
+            .chain(b"        ".iter())
+            .chain(header[156..].iter())
+            .map(|b| *b as u32)
+            .sum();
+        assert_eq!(&header[148..154], format!("{:06o}", checksum).as_bytes());
+        assert_eq!(
+            &tar[TAR_BLOCK_SIZE..2 * TAR_BLOCK_SIZE],
+            &data[..TAR_BLOCK_SIZE]
+        );
+        assert!(tar
+            .windows(b"././@LongLink".len())
+            .any(|w| w == b"././@LongLink"));
+        fs::set_permissions(dest.join("d"), fs::Permissions::from_mode(0o755))?;
+        fs::remove_dir_all(&dest)?;
+
+        // Damaged images
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let sub = fs.find_dir(Path::new("/sub"))?;
+        fs.add_dir_entry(sub, OsStr::new("loop"), sub)?;
+        fs.destroy()?;
+        assert!(extract(&tmp_file, &dest).is_err());
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.remove_dir_entry(sub, OsStr::new("loop"))?;
+        fs.add_dir_entry(sub, OsStr::new("../../escaped"), sub)?;
+        fs.destroy()?;
+        assert!(extract(&tmp_file, &dest).is_err());
+        assert!(export_tar(&tmp_file, &mut vec![]).is_err());
+        assert!(!dest.join("../escaped").exists());
+
+        fs::remove_dir_all(&dest)?;
+        Ok(fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,597 @@
+use crate::gotenks::{
+    fs::GotenksFS,
+    types::{Inode, Superblock},
+    ROOT_INODE, STATE_ERRORS, SUPERBLOCK_SIZE,
+};
+use anyhow::anyhow;
+use fuse_rs::Filesystem;
+use nix::sys::stat::Mode;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Problem {
+    pub description: String,
+    pub repaired: bool,
+}
+
+// Walks the file system from the root and cross-checks what it finds with the
+// bitmaps, the link counts and the superblock counters. With `repair`, fixes
+// what can be fixed without losing data: orphaned inodes are moved to
+// `/lost+found` and blocks are only freed when every inode could be read.
+// Without `repair` the image is only read. With it, an image left with no
+// problems counts as checked and cleanly unmounted.
+pub fn check<P>(path: P, repair: bool) -> anyhow::Result<Vec<Problem>>
+where
+    P: AsRef<Path>,
+{
+    let fs = if repair {
+        GotenksFS::new(path)?
+    } else {
+        GotenksFS::read_only(path)?
+    };
+    let mut checker = Checker {
+        fs,
+        repair,
+        problems: vec![],
+        reached: BTreeMap::new(),
+        owners: HashMap::new(),
+        unsure_blocks: false,
+    };
+
+    checker.run()?;
+    Ok(checker.problems)
+}
+
+// Checks the image first when it wasn't cleanly unmounted or is due for a
+// check. Fails if any problem is found, otherwise marks it as checked.
+pub fn check_if_needed<P>(path: P) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let fs = GotenksFS::read_only(path.as_ref())?;
+    let reason = match fs.superblock().check_reason() {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+    drop(fs);
+
+    eprintln!("The image {}, checking it first", reason);
+    let left = check(path.as_ref(), false)?
+        .iter()
+        .filter(|p| !p.repaired)
+        .count();
+    if left > 0 {
+        return Err(anyhow!(
+            "The image has {} problems, run fsck with --repair before using it",
+            left
+        ));
+    }
+
+    let mut fs = GotenksFS::new(path)?;
+    let sb = fs.superblock_mut();
+    sb.state = 0;
+    sb.mount_count = 0;
+    fs.destroy()?;
+
+    Ok(())
+}
+
+// An inode found while walking the directories.
+struct Reached {
+    is_dir: bool,
+    names: u32,
+    subdirs: u32,
+}
+
+struct Checker {
+    fs: GotenksFS,
+    repair: bool,
+    problems: Vec<Problem>,
+    reached: BTreeMap<u32, Reached>,
+    owners: HashMap<u32, u32>, // block -> inode, 0 for the superblock backups
+    // Set when the blocks of some inode couldn't be listed, any block might
+    // then be one of them.
+    unsure_blocks: bool,
+}
+
+impl Checker {
+    fn run(&mut self) -> anyhow::Result<()> {
+        let root = self
+            .fs
+            .find_inode(ROOT_INODE)
+            .map_err(|_| anyhow!("The root directory cannot be read"))?;
+        self.check_backups()?;
+        self.reach(ROOT_INODE, &root, 0)?;
+        self.walk(ROOT_INODE, PathBuf::from("/"))?;
+
+        self.check_orphans()?;
+        self.check_links()?;
+        self.check_blocks()?;
+        self.check_counters()?;
+
+        if self.repair {
+            let resolved = self.problems.iter().all(|p| p.repaired);
+            let sb = self.fs.superblock_mut();
+            if resolved {
+                sb.state = 0;
+                sb.mount_count = 0;
+            } else {
+                sb.state |= STATE_ERRORS;
+            }
+            self.fs.destroy()?;
+        }
+
+        Ok(())
+    }
+
+    // Long repairs are committed as they go, each one leaves the image
+    // consistent on its own.
+    fn problem(&mut self, description: String, repaired: bool) -> anyhow::Result<()> {
+        self.problems.push(Problem {
+            description,
+            repaired,
+        });
+        if repaired {
+            self.fs.commit_if_full()?;
+        }
+        Ok(())
+    }
+
+    fn reach(&mut self, index: u32, inode: &Inode, names: u32) -> anyhow::Result<()> {
+        self.reached.insert(
+            index,
+            Reached {
+                is_dir: inode.is_dir(),
+                names,
+                subdirs: 0,
+            },
+        );
+        self.claim_blocks(index, inode)
+    }
+
+    // Goes through every directory under `start`, which was already reached.
+    fn walk(&mut self, start: u32, path: PathBuf) -> anyhow::Result<()> {
+        let mut queue = VecDeque::from(vec![(start, path)]);
+        while let Some((dir_index, dir_path)) = queue.pop_front() {
+            let dir = match self.fs.find_dir_from_inode(dir_index) {
+                Ok(dir) => dir,
+                Err(_) => {
+                    let description =
+                        format!("{}: directory entries cannot be read", dir_path.display());
+                    self.problem(description, false)?;
+                    continue;
+                }
+            };
+
+            for (name, index) in dir.entries {
+                let path = dir_path.join(&name);
+                if let Some(reached) = self.reached.get_mut(&index) {
+                    reached.names += 1;
+                    if reached.is_dir {
+                        let description =
+                            format!("{}: directory {} has another name", path.display(), index);
+                        self.problem(description, false)?;
+                    }
+                    continue;
+                }
+
+                let inode = match self.entry_inode(index) {
+                    Ok(inode) => inode,
+                    Err(reason) => {
+                        let repaired =
+                            self.repair && self.fs.remove_dir_entry(dir_index, &name).is_ok();
+                        self.problem(format!("{}: {}", path.display(), reason), repaired)?;
+                        continue;
+                    }
+                };
+
+                if inode.is_dir() {
+                    self.reached.get_mut(&dir_index).unwrap().subdirs += 1;
+                    queue.push_back((index, path));
+                }
+                self.reach(index, &inode, 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The inode a directory entry points to, unless the entry is dangling.
+    fn entry_inode(&self, index: u32) -> Result<Inode, String> {
+        if index == 0 || index > self.fs.superblock().inode_count {
+            return Err(format!("inode {} is out of range", index));
+        }
+        if !self.inode_in_use(index) {
+            return Err(format!("inode {} is not in use", index));
+        }
+
+        self.fs
+            .read_inode(index)
+            .map_err(|_| format!("inode {} fails its checksum", index))
+    }
+
+    // The backups are rewritten along with the superblock, when repairing.
+    fn check_backups(&mut self) -> anyhow::Result<()> {
+        for group in self.fs.superblock().backup_groups() {
+            let offset = self.fs.superblock().backup_offset(group);
+            let mut copy = [0u8; SUPERBLOCK_SIZE as usize];
+            let read = self.fs.device().read_at(&mut copy, offset);
+            if read.is_err() || Superblock::deserialize_from(&copy[..]).is_err() {
+                let description = format!("backup superblock in group {} is damaged", group);
+                self.problem(description, self.repair)?;
+            }
+        }
+
+        let blocks = self.fs.superblock().backup_blocks();
+        self.claim(0, blocks)
+    }
+
+    fn claim_blocks(&mut self, index: u32, inode: &Inode) -> anyhow::Result<()> {
+        match self.fs.inode_blocks(inode) {
+            Ok(blocks) => self.claim(index, blocks),
+            Err(err) => {
+                self.unsure_blocks = true;
+                self.problem(format!("inode {}: {}", index, err), false)
+            }
+        }
+    }
+
+    fn claim(&mut self, index: u32, blocks: Vec<u32>) -> anyhow::Result<()> {
+        for block in blocks {
+            if let Some(owner) = self.owners.insert(block, index) {
+                if owner != index {
+                    let description = format!(
+                        "block {} is used by {} and {}",
+                        block,
+                        owner_name(owner),
+                        owner_name(index)
+                    );
+                    self.problem(description, false)?;
+                }
+            }
+
+            if !self.block_in_use(block) {
+                if self.repair {
+                    self.set_block(block, true);
+                }
+                let description =
+                    format!("block {} of {} is marked free", block, owner_name(index));
+                self.problem(description, self.repair)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Inodes in use that no directory entry leads to. Only those that aren't
+    // in an orphaned directory themselves need a new name.
+    fn check_orphans(&mut self) -> anyhow::Result<()> {
+        let mut orphans = BTreeMap::new();
+        for index in self.fs.used_inodes() {
+            if self.reached.contains_key(&index) {
+                continue;
+            }
+
+            match self.fs.read_inode(index) {
+                Ok(inode) => {
+                    orphans.insert(index, inode);
+                }
+                Err(_) => {
+                    if self.repair {
+                        self.set_inode(index, false);
+                    }
+                    let description =
+                        format!("inode {} is marked in use but fails its checksum", index);
+                    self.problem(description, self.repair)?;
+                }
+            }
+        }
+
+        let mut children = BTreeSet::new();
+        for (index, inode) in &orphans {
+            if inode.is_dir() {
+                if let Ok(dir) = self.fs.find_dir_from_inode(*index) {
+                    children.extend(dir.entries.values().copied());
+                }
+            }
+        }
+
+        let roots: Vec<u32> = orphans
+            .keys()
+            .copied()
+            .filter(|index| !children.contains(index))
+            .collect();
+        for index in &roots {
+            let inode = &orphans[index];
+            self.reach(*index, inode, 0)?;
+            if inode.is_dir() {
+                let path = Path::new("/lost+found").join(format!("#{}", index));
+                self.walk(*index, path)?;
+            }
+        }
+
+        // Orphaned directories pointing at each other.
+        for index in orphans.keys() {
+            if !self.reached.contains_key(index) {
+                self.unsure_blocks = true;
+                let description = format!("inode {} is only linked from orphans", index);
+                self.problem(description, false)?;
+            }
+        }
+
+        if roots.is_empty() {
+            return Ok(());
+        }
+
+        let lost_found = if self.repair {
+            Some(self.lost_found()?)
+        } else {
+            None
+        };
+        for index in roots {
+            let name = format!("#{}", index);
+            if let Some(lost_found) = lost_found {
+                self.fs
+                    .add_dir_entry(lost_found, OsStr::new(&name), index)?;
+                let reached = self.reached.get_mut(&index).unwrap();
+                reached.names += 1;
+                if reached.is_dir {
+                    self.reached.get_mut(&lost_found).unwrap().subdirs += 1;
+                }
+            }
+
+            let description = format!("inode {} is not linked from any directory", index);
+            self.problem(description, self.repair)?;
+        }
+
+        // New entries may have taken new blocks.
+        if let Some(lost_found) = lost_found {
+            for index in &[ROOT_INODE, lost_found] {
+                let inode = self.fs.find_inode(*index)?;
+                self.claim_blocks(*index, &inode)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lost_found(&mut self) -> anyhow::Result<u32> {
+        let name = OsStr::new("lost+found");
+        if let Ok(index) = self.fs.find_dir_entry(ROOT_INODE, name) {
+            return match self.reached.get(&index) {
+                Some(reached) if reached.is_dir => Ok(index),
+                _ => Err(anyhow!("/lost+found is not a directory")),
+            };
+        }
+
+        self.fs
+            .create_dir(Path::new("/lost+found"), Mode::S_IRWXU)?;
+        let index = self.fs.find_dir_entry(ROOT_INODE, name)?;
+        let inode = self.fs.find_inode(index)?;
+        self.reach(index, &inode, 1)?;
+        self.reached.get_mut(&ROOT_INODE).unwrap().subdirs += 1;
+
+        Ok(index)
+    }
+
+    // Directories don't store `.` and `..`, but count them as links like
+    // everywhere else.
+    fn check_links(&mut self) -> anyhow::Result<()> {
+        let expected: Vec<(u32, u32)> = self
+            .reached
+            .iter()
+            .map(|(index, r)| (*index, if r.is_dir { 2 + r.subdirs } else { r.names }))
+            .collect();
+
+        for (index, links) in expected {
+            let mut inode = self.fs.find_inode(index)?;
+            // Orphans were already reported.
+            if inode.hard_links as u32 == links || links == 0 {
+                continue;
+            }
+
+            let description = format!(
+                "inode {} has {} links instead of {}",
+                index, inode.hard_links, links
+            );
+            if self.repair {
+                inode.hard_links = links as u16;
+                self.fs.save_inode(inode, index)?;
+            }
+            self.problem(description, self.repair)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_blocks(&mut self) -> anyhow::Result<()> {
+        let unused: Vec<u32> = self
+            .fs
+            .used_blocks()
+            .into_iter()
+            .filter(|block| !self.owners.contains_key(block))
+            .collect();
+        if unused.is_empty() {
+            return Ok(());
+        }
+
+        let repaired = self.repair && !self.unsure_blocks;
+        if repaired {
+            for block in &unused {
+                self.set_block(*block, false);
+                self.fs.commit_if_full()?;
+            }
+        }
+        let description = format!(
+            "{} blocks are marked in use but no inode uses them",
+            unused.len()
+        );
+        self.problem(description, repaired)
+    }
+
+    fn check_counters(&mut self) -> anyhow::Result<()> {
+        let groups = self.fs.groups();
+        let free_blocks = groups.iter().map(|g| g.free_data_blocks()).sum::<usize>() as u32;
+        let free_inodes = groups.iter().map(|g| g.free_inodes()).sum::<usize>() as u32;
+
+        let sb = self.fs.superblock();
+        let (sb_free_blocks, sb_free_inodes) = (sb.free_blocks, sb.free_inodes);
+        if sb_free_blocks != free_blocks {
+            if self.repair {
+                self.fs.superblock_mut().free_blocks = free_blocks;
+            }
+            let description = format!(
+                "superblock counts {} free blocks instead of {}",
+                sb_free_blocks, free_blocks
+            );
+            self.problem(description, self.repair)?;
+        }
+        if sb_free_inodes != free_inodes {
+            if self.repair {
+                self.fs.superblock_mut().free_inodes = free_inodes;
+            }
+            let description = format!(
+                "superblock counts {} free inodes instead of {}",
+                sb_free_inodes, free_inodes
+            );
+            self.problem(description, self.repair)?;
+        }
+
+        Ok(())
+    }
+
+    fn inode_in_use(&self, index: u32) -> bool {
+        let (group_index, bitmap_index) = self.fs.inode_offsets(index);
+        self.fs.groups()[group_index as usize].has_inode(bitmap_index as usize + 1)
+    }
+
+    fn block_in_use(&self, block: u32) -> bool {
+        let (group_index, bitmap_index) = self.fs.data_block_offsets(block);
+        self.fs.groups()[group_index as usize].has_data_block(bitmap_index as usize + 1)
+    }
+
+    fn set_inode(&mut self, index: u32, used: bool) {
+        let (group_index, bitmap_index) = self.fs.inode_offsets(index);
+        self.fs.mark_group(group_index as usize);
+        self.fs.groups_mut()[group_index as usize].set_inode(bitmap_index as usize + 1, used);
+    }
+
+    fn set_block(&mut self, block: u32, used: bool) {
+        let (group_index, bitmap_index) = self.fs.data_block_offsets(block);
+        self.fs.mark_group(group_index as usize);
+        self.fs.groups_mut()[group_index as usize].set_data_block(bitmap_index as usize + 1, used);
+    }
+}
+
+fn owner_name(index: u32) -> String {
+    if index == 0 {
+        "a backup superblock".to_string()
+    } else {
+        format!("inode {}", index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gotenks::util, mkfs};
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn check_and_repair() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "fsck",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+        assert!(check(&tmp_file, false)?.is_empty());
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.create_dir(Path::new("/dir"), Mode::S_IRWXU)?;
+        for name in &["/dir/foo.txt", "/orphan.txt"] {
+            let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+            fs.create(Path::new(name), Mode::S_IRWXU, &mut open_fi)?;
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(open_fi.handle().unwrap());
+            let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+            fs.write(Path::new(name), &[7u8; 300], 0, &mut write_file_info)?;
+        }
+        fs.hard_link(Path::new("/dir/foo.txt"), Path::new("/bar.txt"))?;
+        fs.symlink(Path::new("/dir/foo.txt"), Path::new("/link"))?;
+        fs.destroy()?;
+        assert!(check(&tmp_file, false)?.is_empty());
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let orphan = fs.remove_dir_entry(ROOT_INODE, OsStr::new("orphan.txt"))?;
+        fs.add_dir_entry(ROOT_INODE, OsStr::new("ghost.txt"), 200)?;
+        let dir = fs.find_dir(Path::new("/dir"))?;
+        let foo = fs.find_dir_entry(dir, OsStr::new("foo.txt"))?;
+        let mut inode = fs.find_inode(foo)?;
+        inode.hard_links = 5;
+        let block = inode.direct_blocks[0];
+        fs.save_inode(inode, foo)?;
+        fs.mark_group(0);
+        fs.groups_mut()[0].set_data_block(1000, true);
+        fs.groups_mut()[0].set_data_block(block as usize, false);
+        fs.destroy()?;
+
+        let image = std::fs::read(&tmp_file)?;
+        let problems = check(&tmp_file, false)?;
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems.iter().all(|p| !p.repaired));
+        assert_eq!(std::fs::read(&tmp_file)?, image);
+
+        let problems = check(&tmp_file, true)?;
+        assert!(problems.iter().all(|p| p.repaired), "{:?}", problems);
+        assert!(check(&tmp_file, false)?.is_empty());
+
+        let fs = GotenksFS::new(&tmp_file)?;
+        let lost_found = fs.find_dir(Path::new("/lost+found"))?;
+        assert_eq!(
+            fs.find_dir_entry(lost_found, OsStr::new(&format!("#{}", orphan)))?,
+            orphan
+        );
+        assert_eq!(fs.find_inode(orphan)?.size, 300);
+        assert!(fs
+            .find_dir_entry(ROOT_INODE, OsStr::new("ghost.txt"))
+            .is_err());
+        assert_eq!(fs.find_inode(foo)?.hard_links, 2);
+        assert!(!fs.groups()[0].has_data_block(1000));
+        assert!(fs.groups()[0].has_data_block(block as usize));
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn repair_in_steps() -> anyhow::Result<()> {
+        let groups = 6;
+        let tmp_file = mkfs::tmp_image(
+            "fsck_steps",
+            util::block_group_size(BLOCK_SIZE) * groups,
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+
+        // A block nothing uses in every group, and a journal too small to
+        // free all of them in one transaction
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        for group in 0..groups as usize {
+            fs.mark_group(group);
+            fs.groups_mut()[group].set_data_block(100, true);
+        }
+        fs.superblock_mut().journal_blocks = 10;
+        fs.destroy()?;
+
+        let problems = check(&tmp_file, true)?;
+        assert!(problems.iter().all(|p| p.repaired));
+        assert!(check(&tmp_file, false)?.is_empty());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,374 @@
+use memmap::MmapMut;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileExt, FileTypeExt},
+    path::Path,
+};
+
+/// Where an image is stored. Reads and writes past the end fail: only `mkfs`
+/// and `resize` change the size of an image.
+pub trait BlockDevice: fmt::Debug + Send {
+    fn size(&self) -> u64;
+
+    /// Fills `buf` with the bytes starting at `offset`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Returns once everything written so far is durable.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// Opens the image at `path`. Block devices can't be mapped, they are always
+// read and written with pread and pwrite.
+pub fn open<P>(path: P, mmap: bool) -> io::Result<Box<dyn BlockDevice>>
+where
+    P: AsRef<Path>,
+{
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    if file.metadata()?.file_type().is_block_device() {
+        Ok(Box::new(RawDevice::new(file)?))
+    } else if mmap {
+        Ok(Box::new(MmapDevice::new(file)?))
+    } else {
+        Ok(Box::new(FileDevice::new(file)?))
+    }
+}
+
+// Opens the image at `path` for reading only, any write fails.
+pub fn open_read_only<P>(path: P) -> io::Result<Box<dyn BlockDevice>>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(path)?;
+    if file.metadata()?.file_type().is_block_device() {
+        Ok(Box::new(RawDevice::new(file)?))
+    } else {
+        Ok(Box::new(FileDevice::new(file)?))
+    }
+}
+
+fn check_range(size: u64, offset: u64, len: usize) -> io::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} bytes at {} are past the end of the image", len, offset),
+        )),
+    }
+}
+
+/// An image file mapped into memory.
+#[derive(Debug)]
+pub struct MmapDevice {
+    mmap: MmapMut,
+    // What was written since the last flush, which is all a flush writes
+    // back.
+    dirty: Option<(usize, usize)>,
+}
+
+impl MmapDevice {
+    pub fn new(file: File) -> io::Result<Self> {
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self { mmap, dirty: None })
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn size(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let start = offset as usize;
+        buf.copy_from_slice(&self.mmap[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let start = offset as usize;
+        let end = start + buf.len();
+        self.mmap[start..end].copy_from_slice(buf);
+        self.dirty = match self.dirty {
+            Some((from, to)) => Some((from.min(start), to.max(end))),
+            None => Some((start, end)),
+        };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.dirty.take() {
+            Some((from, to)) => self.mmap.flush_range(from, to - from),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An image file read and written with pread and pwrite, for file systems
+/// where mapping files isn't reliable, such as network ones.
+#[derive(Debug)]
+pub struct FileDevice {
+    file: File,
+    size: u64,
+}
+
+impl FileDevice {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// A block device, such as a loop device, holding an image.
+#[derive(Debug)]
+pub struct RawDevice {
+    file: File,
+    size: u64,
+}
+
+impl RawDevice {
+    pub fn new(mut file: File) -> io::Result<Self> {
+        if !file.metadata()?.file_type().is_block_device() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a block device",
+            ));
+        }
+
+        // The metadata of a block device has no size, its end does.
+        let size = file.seek(SeekFrom::End(0))?;
+        Ok(Self { file, size })
+    }
+}
+
+impl BlockDevice for RawDevice {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        check_range(self.size, offset, buf.len())?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    // Also flushes the write cache of the device.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// An image kept in memory, nothing is ever written to disk.
+#[derive(Debug)]
+pub struct MemoryDevice {
+    buf: Vec<u8>,
+}
+
+impl MemoryDevice {
+    pub fn new(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn size(&self) -> u64 {
+        self.buf.len() as u64
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let start = offset as usize;
+        buf.copy_from_slice(&self.buf[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        check_range(self.size(), offset, buf.len())?;
+        let start = offset as usize;
+        self.buf[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads and writes a device like a file, for what serializes into one.
+pub(crate) struct Cursor<'a> {
+    device: &'a mut dyn BlockDevice,
+    position: u64,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(device: &'a mut dyn BlockDevice) -> Self {
+        Self {
+            device,
+            position: 0,
+        }
+    }
+}
+
+impl Read for Cursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.device.size().saturating_sub(self.position) as usize);
+        self.device.read_at(&mut buf[..len], self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for Cursor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.device.write_at(buf, self.position)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+
+impl Seek for Cursor<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (self.device.size() as i64 + offset) as u64,
+            SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+        };
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gotenks::{fs::GotenksFS, util},
+        mkfs,
+    };
+    use fuse_rs::Filesystem;
+    use nix::sys::stat::Mode;
+
+    fn read_and_write(device: &mut dyn BlockDevice) -> anyhow::Result<()> {
+        assert_eq!(device.size(), 64);
+        device.write_at(&[1, 2, 3], 61)?;
+        device.flush()?;
+        let mut buf = [0u8; 4];
+        device.read_at(&mut buf, 60)?;
+        assert_eq!(buf, [0, 1, 2, 3]);
+
+        assert!(device.read_at(&mut buf, 61).is_err());
+        assert!(device.write_at(&buf, 62).is_err());
+        assert!(device.read_at(&mut buf, u64::MAX).is_err());
+
+        let mut cursor = Cursor::new(device);
+        cursor.seek(SeekFrom::Start(62))?;
+        let mut buf = vec![];
+        cursor.read_to_end(&mut buf)?;
+        assert_eq!(buf, vec![2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn devices() -> anyhow::Result<()> {
+        let mut tmp_file = std::env::temp_dir();
+        tmp_file.push("device.img");
+        std::fs::write(&tmp_file, &[0u8; 64])?;
+
+        read_and_write(&mut MemoryDevice::new(vec![0u8; 64]))?;
+        read_and_write(open(&tmp_file, true)?.as_mut())?;
+        std::fs::write(&tmp_file, &[0u8; 64])?;
+        read_and_write(open(&tmp_file, false)?.as_mut())?;
+        assert_eq!(std::fs::read(&tmp_file)?[60..], [0, 1, 2, 3]);
+
+        assert!(RawDevice::new(File::open(&tmp_file)?).is_err());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn file_system_on_devices() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "device_fs",
+            util::block_group_size(128),
+            128,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let long_target = "t".repeat(100);
+        let devices: Vec<Box<dyn BlockDevice>> = vec![
+            Box::new(MemoryDevice::new(std::fs::read(&tmp_file)?)),
+            open(&tmp_file, false)?,
+        ];
+        for device in devices {
+            let mut fs = GotenksFS::with_device(device)?;
+            let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+            fs.create(Path::new("/foo.txt"), Mode::S_IRWXU, &mut open_fi)?;
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(open_fi.handle().unwrap());
+            let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+            let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+            fs.write(Path::new("/foo.txt"), &data, 0, &mut write_file_info)?;
+
+            let mut buf = vec![0u8; 300];
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(open_fi.handle().unwrap());
+            fs.read(Path::new("/foo.txt"), &mut buf, 0, file_info)?;
+            assert_eq!(buf, data);
+
+            fs.symlink(Path::new("foo.txt"), Path::new("/short"))?;
+            fs.symlink(Path::new(&long_target), Path::new("/long"))?;
+            let short = fs.read_link(Path::new("/short"))?;
+            let long = fs.read_link(Path::new("/long"))?;
+            assert_eq!(short, "foo.txt");
+            assert_eq!(long, long_target.as_str());
+            fs.destroy()?;
+        }
+
+        // Only the second one wrote to the file
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.read_link(Path::new("/long"))?, long_target.as_str());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,143 @@
+use super::{fs::GotenksFS, image::io_error};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A file inside an image, opened with [`Image::file`](super::Image::file).
+/// Reads and writes start at the current position and move it forward, as
+/// with [`std::fs::File`]. Every write is committed before it returns.
+#[derive(Debug)]
+pub struct File<'a> {
+    fs: &'a mut GotenksFS,
+    index: u32,
+    position: u64,
+}
+
+impl<'a> File<'a> {
+    pub(crate) fn new(fs: &'a mut GotenksFS, index: u32) -> Self {
+        Self {
+            fs,
+            index,
+            position: 0,
+        }
+    }
+
+    /// The inode index of the file.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.fs.find_inode(self.index).map_err(io_error)?.size)
+    }
+}
+
+impl Read for File<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self
+            .fs
+            .read_file(self.index, buf, self.position)
+            .map_err(io_error)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for File<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let wrote = self
+            .fs
+            .write_file(self.index, buf, self.position)
+            .map_err(io_error)?;
+        self.position += wrote as u64;
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.len()?, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        let position = if offset < 0 {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        } else {
+            base.checked_add(offset as u64)
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gotenks::{util, Image},
+        mkfs,
+    };
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn read_write_seek() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "file",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut image = Image::new(&tmp_file)?;
+        let index = image.create("/foo.txt", 0o644)?;
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        let mut file = image.file(index)?;
+        assert_eq!(io::copy(&mut &data[..], &mut file)?, 1000);
+        assert_eq!(file.seek(SeekFrom::Current(0))?, 1000);
+        assert_eq!(file.seek(SeekFrom::End(-10))?, 990);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        assert_eq!(buf, &data[990..]);
+
+        // Past the end, leaving a hole
+        file.seek(SeekFrom::Start(1500))?;
+        file.write_all(b"end")?;
+        file.seek(SeekFrom::Current(-503))?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        assert_eq!(buf.len(), 503);
+        assert!(buf[..500].iter().all(|b| *b == 0));
+        assert_eq!(&buf[500..], b"end");
+        assert!(file.seek(SeekFrom::Current(-2000)).is_err());
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut out = vec![];
+        io::copy(&mut file, &mut out)?;
+        assert_eq!(&out[..1000], &data[..]);
+        assert_eq!(image.stat("/foo.txt")?.size, 1503);
+
+        assert!(image.file(1).is_err());
+        assert!(image.file(100).is_err());
+        image.close()?;
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -1,28 +1,36 @@
 use super::{
-    types::{Directory, Group, Inode, Superblock},
-    util, DIRECT_POINTERS, INODE_SIZE, ROOT_INODE, SUPERBLOCK_SIZE,
+    device::{self, BlockDevice, Cursor},
+    journal::Journal,
+    types::{Directory, DirectoryIndex, Extent, ExtentNode, Group, Inode, Superblock},
+    util, DIRECT_POINTERS, EXTENTS_FLAG, FEATURE_EXTENTS, INLINE_DATA_SIZE, INODE_SIZE, ROOT_INODE,
+    STATE_DIRTY, SUPERBLOCK_SIZE,
 };
 use anyhow::anyhow;
-use fs::OpenOptions;
 use fuse_rs::fs::FileStat;
-use io::{Cursor, SeekFrom};
-use memmap::MmapMut;
 use nix::{
     errno::Errno,
     sys::stat::{Mode, SFlag},
 };
 use std::{
-    fs,
-    io::{self, prelude::*},
-    mem,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    io, mem,
+    os::unix::ffi::OsStrExt,
     path::Path,
+    sync::{Mutex, PoisonError},
 };
 
 #[derive(Debug, Default)]
 pub struct GotenksFS {
     pub sb: Option<Superblock>,
-    pub mmap: Option<MmapMut>,
+    pub device: Option<Box<dyn BlockDevice>>,
     pub groups: Option<Vec<Group>>,
+    pub journal: Option<Journal>,
+    // `read_link` hands out a reference, which only a mapped image has memory
+    // for. Targets are kept by inode until the next update, which can't come
+    // while one is still borrowed, so reading a link again doesn't keep
+    // another copy.
+    pub(crate) link_targets: Mutex<HashMap<u32, Box<[u8]>>>,
 }
 
 impl GotenksFS {
@@ -30,22 +38,76 @@ impl GotenksFS {
     where
         P: AsRef<Path>,
     {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(image_path.as_ref())?;
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
-        let mut cursor = Cursor::new(&mmap);
-        let sb: Superblock = Superblock::deserialize_from(&mut cursor)?;
-        let groups = Group::deserialize_from(&mut cursor, sb.block_size, sb.groups as usize)?;
+        Self::with_device(device::open(image_path, true)?)
+    }
+
+    // Opens the image without writing anything to it, not even to replay the
+    // journal: what it holds is only read through. Updates fail to commit.
+    pub fn read_only<P>(image_path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let device = device::open_read_only(image_path)?;
+        let sb = Self::read_superblock(device.as_ref())?;
+        let journal = Journal::new(sb.journal_offset(), sb.journal_size(), sb.block_size);
+        let mut device: Box<dyn BlockDevice> = if sb.journal_blocks != 0 {
+            Box::new(journal.snapshot(device)?)
+        } else {
+            device
+        };
+
+        let sb = Self::read_superblock(device.as_ref())?;
+        let cursor = Cursor::new(device.as_mut());
+        let groups = Group::deserialize_from(cursor, sb.block_size, sb.groups as usize)?;
+
+        Ok(Self {
+            sb: Some(sb),
+            groups: Some(groups),
+            device: Some(device),
+            journal: Some(journal),
+            link_targets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn read_superblock(device: &dyn BlockDevice) -> anyhow::Result<Superblock> {
+        let mut buf = vec![0u8; SUPERBLOCK_SIZE as usize];
+        device.read_at(&mut buf, 0)?;
+        Superblock::deserialize_from(buf.as_slice())
+            .or_else(|err| Superblock::read_backup(device).ok_or(err))
+    }
+
+    pub fn with_device(mut device: Box<dyn BlockDevice>) -> anyhow::Result<Self> {
+        let mut sb = match Superblock::deserialize_from(Cursor::new(device.as_mut())) {
+            Ok(sb) => sb,
+            Err(err) => {
+                let mut sb = Superblock::read_backup(device.as_ref()).ok_or(err)?;
+                device.write_at(&sb.serialize()?, 0)?;
+                sb
+            }
+        };
+
+        // Whatever a crash interrupted has to be in place before reading the
+        // bitmaps, and it may have updated the superblock as well. Images
+        // made without a journal get an empty one, so updates are still
+        // written through at each commit, only not atomically.
+        let mut journal = Journal::new(sb.journal_offset(), sb.journal_size(), sb.block_size);
+        if sb.journal_blocks != 0 && journal.replay(device.as_mut())? {
+            sb = Superblock::deserialize_from(Cursor::new(device.as_mut()))?;
+        }
+
+        let cursor = Cursor::new(device.as_mut());
+        let groups = Group::deserialize_from(cursor, sb.block_size, sb.groups as usize)?;
 
         let mut fs = Self {
             sb: Some(sb),
             groups: Some(groups),
-            mmap: Some(mmap),
+            device: Some(device),
+            journal: Some(journal),
+            link_targets: Mutex::new(HashMap::new()),
         };
 
         fs.create_root()?;
+        fs.commit()?;
 
         Ok(fs)
     }
@@ -58,6 +120,7 @@ impl GotenksFS {
 
         let mut inode = Inode::new();
         inode.mode = SFlag::S_IFDIR.bits() | 0o777;
+        inode.flags = self.superblock().inode_flags;
         inode.hard_links = 2;
 
         let dir = Directory::default();
@@ -67,1006 +130,2751 @@ impl GotenksFS {
             .ok_or_else(|| anyhow!("No space left for inodes"))?;
         assert_eq!(index, ROOT_INODE);
 
-        inode.add_block(
-            self.allocate_data_block()
-                .ok_or_else(|| anyhow!("No space left for data"))?,
-            0,
-        )?;
         self.save_inode(inode, index)?;
-        self.save_dir(dir, index)
+        Ok(self.save_dir(dir, index)?)
     }
 
     #[inline]
-    fn save_inode(&mut self, mut inode: Inode, index: u32) -> anyhow::Result<()> {
+    pub(crate) fn save_inode(&mut self, mut inode: Inode, index: u32) -> anyhow::Result<()> {
         let offset = self.inode_seek_position(index);
-        let buf = self.mmap_mut().as_mut();
-        let mut cursor = Cursor::new(buf);
-        cursor.seek(SeekFrom::Start(offset))?;
+        let data = inode.serialize(self.superblock().revision)?;
+        self.log(offset, &data);
+        Ok(())
+    }
+
+    // Reads the target of the symlink at `path`, along with its inode index.
+    pub(crate) fn link_target(&self, path: &Path) -> fuse_rs::Result<(u32, Box<[u8]>)> {
+        let (inode, index) = self.find_inode_from_path(path)?;
+        if !inode.is_symlink() {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut target = vec![0u8; inode.size as usize].into_boxed_slice();
+        if inode.is_fast_symlink() {
+            let start = self.inode_seek_position(index) + inode.inline_data_offset();
+            self.read_at(&mut target, start).map_err(|_| Errno::EIO)?;
+        } else {
+            let block_index = self.lookup_data_block(&inode, 0)?.0;
+            self.read_data(&mut target, 0, block_index)
+                .map_err(|_| Errno::EIO)?;
+        }
 
-        Ok(inode.serialize_into(&mut cursor)?)
+        Ok((index, target))
     }
 
-    fn save_dir(&mut self, mut dir: Directory, index: u32) -> anyhow::Result<()> {
-        let mut inode = self.find_inode(index)?;
-        inode.update_modified_at();
-        self.save_inode(inode, index)?;
+    // Runs a FUSE operation and commits the updates it made once it
+    // succeeded. One that failed halfway is rolled back instead, so none of
+    // it reaches the image.
+    fn transaction<T, F>(&mut self, f: F) -> fuse_rs::Result<T>
+    where
+        F: FnOnce(&mut Self) -> fuse_rs::Result<T>,
+    {
+        self.link_targets
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        match f(self) {
+            Ok(value) => {
+                self.commit().map_err(|e| match e.downcast_ref::<Errno>() {
+                    Some(errno) => *errno,
+                    None => Errno::EIO,
+                })?;
+                Ok(value)
+            }
+            Err(errno) => {
+                self.rollback().map_err(|_| Errno::EIO)?;
+                Err(errno)
+            }
+        }
+    }
+
+    // Commits the updates made since the last commit, along with the
+    // superblock, the bitmaps of the groups they touched and the checksums of
+    // the blocks they wrote. If the commit fails, they are all undone.
+    pub(crate) fn commit(&mut self) -> anyhow::Result<()> {
+        if self.journal().is_empty() {
+            return Ok(());
+        }
+
+        let result = self.log_commit().and_then(|_| {
+            let journal = self.journal.as_mut().unwrap();
+            journal.commit(self.device.as_deref_mut().unwrap())
+        });
+        if result.is_err() {
+            self.rollback()?;
+        }
+        result
+    }
+
+    // Commits the pending updates once they take half the journal, for long
+    // operations that wouldn't fit in a single transaction. What was done so
+    // far has to stand on its own.
+    pub(crate) fn commit_if_full(&mut self) -> anyhow::Result<()> {
+        let journal_size = self.superblock().journal_size();
+        if journal_size != 0 && self.journal().pending_size() * 2 > journal_size {
+            return self.commit();
+        }
+        Ok(())
+    }
+
+    fn log_commit(&mut self) -> anyhow::Result<()> {
+        if let Some(checksums_offset) = self.data_checksums_offset() {
+            let blk_size = self.superblock().block_size as usize;
+            let mut block = vec![0u8; blk_size];
+            for index in self.journal_mut().take_dirty_blocks() {
+                let offset = self.data_block_seek_position(index);
+                self.read_at(&mut block, offset)?;
+                let position = checksums_offset + (index as u64 - 1) * 4;
+                self.log(position, &util::block_checksum(&block).to_le_bytes());
+            }
+        }
+
+        let data = self.superblock_mut().serialize()?;
+        self.log(0, &data);
+        for group in self.superblock().backup_groups() {
+            let offset = self.superblock().backup_offset(group);
+            self.log(offset, &data);
+        }
+        let blk_size = self.superblock().block_size;
+        for index in self.journal().dirty_groups() {
+            let group = &self.groups()[index];
+            let mut bitmaps = group.data_bitmap.as_slice().to_vec();
+            bitmaps.extend_from_slice(group.inode_bitmap.as_slice());
+            let offset = util::block_group_size(blk_size) * index as u64 + SUPERBLOCK_SIZE;
+            self.log(offset, &bitmaps);
+        }
+
+        Ok(())
+    }
+
+    // Goes back to the image as of the last commit, the superblock and the
+    // bitmaps in memory included.
+    fn rollback(&mut self) -> anyhow::Result<()> {
+        self.journal_mut().discard();
+        let sb = Superblock::deserialize_from(Cursor::new(self.device_mut()))?;
+        let cursor = Cursor::new(self.device_mut());
+        self.groups = Some(Group::deserialize_from(
+            cursor,
+            sb.block_size,
+            sb.groups as usize,
+        )?);
+        self.sb = Some(sb);
+        Ok(())
+    }
 
-        let offset = self.data_block_seek_position(index);
-        let buf = self.mmap_mut().as_mut();
-        let mut cursor = Cursor::new(buf);
-        cursor.seek(SeekFrom::Start(offset))?;
+    // Reads from the image, updates not committed yet included.
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.journal().read(self.device(), buf, offset)
+    }
+
+    #[inline]
+    fn log(&mut self, offset: u64, data: &[u8]) {
+        self.journal_mut().log(offset, data);
+    }
+
+    #[inline]
+    pub(crate) fn mark_group(&mut self, index: usize) {
+        self.journal_mut().mark_group(index);
+    }
+
+    #[inline]
+    fn journal(&self) -> &Journal {
+        self.journal.as_ref().unwrap()
+    }
+
+    #[inline]
+    fn journal_mut(&mut self) -> &mut Journal {
+        self.journal.as_mut().unwrap()
+    }
+
+    fn save_dir(&mut self, mut dir: Directory, index: u32) -> fuse_rs::Result<()> {
+        let mut inode = self.find_inode(index)?;
+        let buf = dir.serialize().map_err(|_| Errno::EIO)?;
+        self.write_inode_data(&mut inode, &buf, 0)?;
 
-        Ok(dir.serialize_into(&mut cursor)?)
+        // Like in ext2, directories never shrink and their size always covers
+        // whole blocks, so stale bytes past the serialized entries are ignored.
+        let blk_size = self.superblock().block_size as u64;
+        let size = (buf.len() as u64).div_ceil(blk_size) * blk_size;
+        inode.size = inode.size.max(size);
+        inode.update_modified_at();
+        self.save_inode(inode, index).map_err(|_| Errno::EIO)
     }
 
     #[inline]
-    fn find_inode(&self, index: u32) -> fuse_rs::Result<Inode> {
-        let (group_index, _bitmap_index) = self.inode_offsets(index);
+    pub(crate) fn find_inode(&self, index: u32) -> fuse_rs::Result<Inode> {
+        let (group_index, bitmap_index) = self.inode_offsets(index);
         if !self
             .groups()
             .get(group_index as usize)
             .unwrap()
-            .has_inode(index as usize)
+            .has_inode(bitmap_index as usize + 1)
         {
             return Err(Errno::ENOENT);
         }
 
-        let offset = self.inode_seek_position(index);
-        let buf = self.mmap();
-        let mut cursor = Cursor::new(buf);
-        cursor
-            .seek(SeekFrom::Start(offset))
-            .map_err(|_e| Errno::EIO)?;
+        self.read_inode(index).map_err(|_e| Errno::EIO)
+    }
 
-        let inode = Inode::deserialize_from(cursor).map_err(|_e| Errno::EIO)?;
-        Ok(inode)
+    // Reads the inode from the table whether the bitmap has it or not.
+    pub(crate) fn read_inode(&self, index: u32) -> anyhow::Result<Inode> {
+        let mut buf = [0u8; INODE_SIZE as usize];
+        self.read_at(&mut buf, self.inode_seek_position(index))?;
+
+        Inode::deserialize_from(&buf[..], self.superblock().revision)
     }
 
-    fn find_inode_from_path<P>(&self, path: P) -> fuse_rs::Result<(Inode, u32)>
+    pub(crate) fn find_inode_from_path<P>(&self, path: P) -> fuse_rs::Result<(Inode, u32)>
     where
         P: AsRef<Path>,
     {
         match path.as_ref().parent() {
             None => Ok((self.find_inode(ROOT_INODE)?, ROOT_INODE)),
             Some(parent) => {
-                let (parent, _) = self.find_dir(parent)?;
-                let index = parent.entry(
-                    path.as_ref()
-                        .file_name()
-                        .ok_or(Errno::EINVAL)?
-                        .to_os_string(),
+                let parent_index = self.find_dir(parent)?;
+                let index = self.find_dir_entry(
+                    parent_index,
+                    path.as_ref().file_name().ok_or(Errno::EINVAL)?,
                 )?;
                 Ok((self.find_inode(index)?, index))
             }
         }
     }
 
-    fn find_dir<P>(&self, path: P) -> fuse_rs::Result<(Directory, u32)>
+    // Returns the inode index of the directory at `path`.
+    pub(crate) fn find_dir<P>(&self, path: P) -> fuse_rs::Result<u32>
     where
         P: AsRef<Path>,
     {
-        let mut current = self.find_dir_from_inode(ROOT_INODE)?;
         let mut index = ROOT_INODE;
         for c in path.as_ref().components().skip(1) {
-            index = current.entry(c)?;
-            current = self.find_dir_from_inode(index)?;
+            index = self.find_dir_entry(index, c.as_os_str())?;
         }
+        self.find_dir_inode(index)?;
 
-        Ok((current, index))
+        Ok(index)
     }
 
-    fn find_dir_from_inode(&self, index: u32) -> fuse_rs::Result<Directory> {
-        let inode = self.find_inode(index)?;
+    fn find_dir_inode(&self, index: u32) -> fuse_rs::Result<Inode> {
+        let mut inode = self.find_inode(index)?;
         if !inode.is_dir() {
             return Err(Errno::ENOTDIR);
         }
 
-        // TODO: support more blocks
-        let block = inode.direct_blocks[0];
-        let (group_index, _) = self.data_block_offsets(index);
-        if !self
-            .groups()
-            .get(group_index as usize)
-            .unwrap()
-            .has_data_block(block as usize)
-        {
-            return Err(Errno::ENOENT);
-        }
-
-        let mut cursor = Cursor::new(self.mmap().as_ref());
-        cursor
-            .seek(SeekFrom::Start(self.data_block_seek_position(block)))
-            .map_err(|_| Errno::EIO)?;
-
-        Directory::deserialize_from(cursor).map_err(|_| Errno::EIO)
+        // Directories written before they could span multiple blocks have
+        // their size set to zero but always own their first block.
+        inode.size = inode.size.max(self.superblock().block_size as u64);
+        Ok(inode)
     }
 
-    fn find_data_block(
-        &mut self,
-        inode: &mut Inode,
-        offset: u64,
-        read: bool,
-    ) -> fuse_rs::Result<(u32, u32)> {
-        let blk_size = self.superblock().block_size as u64;
-        let index = offset / blk_size;
-
-        let pointers_per_block = blk_size / mem::size_of::<u32>() as u64;
+    // Reads every entry of the directory, whatever its format.
+    pub(crate) fn find_dir_from_inode(&self, index: u32) -> fuse_rs::Result<Directory> {
+        let inode = self.find_dir_inode(index)?;
+        let first = self.read_dir_block(&inode, 0)?;
+        if !DirectoryIndex::is_index(&first) {
+            return self.read_plain_dir(&inode);
+        }
 
-        let block = if index < DIRECT_POINTERS {
-            inode.find_direct_block(index as usize)
-        } else if index < (pointers_per_block + DIRECT_POINTERS) {
-            self.find_indirect(
-                inode.indirect_block,
-                index - DIRECT_POINTERS,
-                offset,
-                pointers_per_block,
-            )
-            .map_err(|_| Errno::EIO)?
-        } else if index
-            < (pointers_per_block * pointers_per_block + pointers_per_block + DIRECT_POINTERS)
-        {
-            self.find_indirect(
-                inode.double_indirect_block,
-                index - DIRECT_POINTERS,
-                offset,
-                pointers_per_block,
-            )
-            .map_err(|_| Errno::EIO)?
-        } else {
-            return Err(Errno::ENOSPC);
-        };
+        let mut dir = Directory::default();
+        self.collect_dir_entries(&inode, 0, &mut dir.entries)?;
+        Ok(dir)
+    }
 
-        if block != 0 {
-            return Ok((block, ((index + 1) * blk_size - offset) as u32));
+    fn collect_dir_entries(
+        &self,
+        inode: &Inode,
+        block: u32,
+        entries: &mut BTreeMap<OsString, u32>,
+    ) -> fuse_rs::Result<()> {
+        let node = self.read_dir_index(inode, block)?;
+        for (_, child) in node.children {
+            if node.level == 0 {
+                entries.append(&mut self.read_dir_leaf(inode, child)?.entries);
+            } else {
+                self.collect_dir_entries(inode, child, entries)?;
+            }
         }
 
-        if read {
-            return Err(Errno::EINVAL);
+        Ok(())
+    }
+
+    // Looks `name` up reading only the index blocks on the way to its leaf.
+    pub(crate) fn find_dir_entry(&self, dir_index: u32, name: &OsStr) -> fuse_rs::Result<u32> {
+        let inode = self.find_dir_inode(dir_index)?;
+        let first = self.read_dir_block(&inode, 0)?;
+        if !DirectoryIndex::is_index(&first) {
+            return self.read_plain_dir(&inode)?.entry(name);
         }
 
-        let mut block = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
-        if index < DIRECT_POINTERS {
-            inode
-                .add_block(block, index as usize)
-                .map_err(|_| Errno::ENOSPC)?;
-        } else if index < (pointers_per_block + DIRECT_POINTERS) {
-            if inode.indirect_block == 0 {
-                inode.indirect_block = block;
-                self.write_data(&vec![0u8; blk_size as usize], 0, block)
-                    .map_err(|_| Errno::EIO)?;
-                block = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
+        let hash = util::hash_name(name);
+        let mut node =
+            DirectoryIndex::deserialize_from(first.as_slice()).map_err(|_| Errno::EIO)?;
+        loop {
+            let (_, child) = node.children[node.position(hash)];
+            if node.level == 0 {
+                return self.read_dir_leaf(&inode, child)?.entry(name);
             }
+            node = self.read_dir_index(&inode, child)?;
+        }
+    }
 
-            self.save_indirect(
-                inode.indirect_block,
-                block,
-                index - DIRECT_POINTERS,
-                pointers_per_block,
-            )
-            .map_err(|_| Errno::EIO)?;
-        } else if index
-            < (pointers_per_block * pointers_per_block + pointers_per_block + DIRECT_POINTERS)
-        {
-            if inode.double_indirect_block == 0 {
-                inode.double_indirect_block = block;
-                self.write_data(&vec![0u8; blk_size as usize], 0, block)
-                    .map_err(|_| Errno::EIO)?;
-                block = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
-            }
+    // Adds `name` to the directory, replacing any entry with the same name.
+    pub(crate) fn add_dir_entry(
+        &mut self,
+        dir_index: u32,
+        name: &OsStr,
+        index: u32,
+    ) -> fuse_rs::Result<()> {
+        let mut inode = self.find_dir_inode(dir_index)?;
+        let first = self.read_dir_block(&inode, 0)?;
+        if DirectoryIndex::is_index(&first) {
+            self.insert_dir_entry(&mut inode, 0, name, index)?;
+        } else {
+            let mut dir = self.read_plain_dir(&inode)?;
+            dir.entries.insert(name.to_os_string(), index);
 
-            let indirect_offset = (index - DIRECT_POINTERS) / pointers_per_block - 1;
-            let indirect_block = match self
-                .find_indirect(
-                    inode.double_indirect_block,
-                    indirect_offset,
-                    0,
-                    pointers_per_block,
-                )
-                .map_err(|_| Errno::EIO)?
-            {
-                0 => {
-                    let indirect_block = block;
-                    self.save_indirect(
-                        inode.double_indirect_block,
-                        block,
-                        indirect_offset,
-                        pointers_per_block,
-                    )
+            let buf = dir.serialize().map_err(|_| Errno::EIO)?;
+            if buf.len() <= self.superblock().block_size as usize {
+                self.write_inode_data(&mut inode, &buf, 0)?;
+            } else {
+                // Too big for a single block, move the entries into leaves
+                // below a fresh index root.
+                let leaf = self.append_dir_block(&mut inode);
+                let root = DirectoryIndex::new(0, vec![(0, leaf)])
+                    .serialize()
                     .map_err(|_| Errno::EIO)?;
-                    self.write_data(&vec![0u8; blk_size as usize], 0, block)
-                        .map_err(|_| Errno::EIO)?;
-                    block = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
-                    indirect_block
+                let empty = Directory::default().serialize().map_err(|_| Errno::EIO)?;
+                self.write_dir_block(&mut inode, 0, &root)?;
+                self.write_dir_block(&mut inode, leaf, &empty)?;
+                for (name, index) in dir.entries {
+                    self.insert_dir_entry(&mut inode, 0, &name, index)?;
                 }
-                indirect_block => indirect_block,
-            };
-
-            self.save_indirect(
-                indirect_block,
-                block,
-                (index - DIRECT_POINTERS) & (pointers_per_block - 1),
-                pointers_per_block,
-            )
-            .map_err(|_| Errno::EIO)?;
-        } else {
-            return Err(Errno::ENOSPC);
+            }
         }
 
-        Ok((block, blk_size as u32))
+        inode.update_modified_at();
+        self.save_inode(inode, dir_index).map_err(|_| Errno::EIO)
     }
 
-    fn find_indirect(
-        &self,
-        pointer: u32,
-        index: u64,
-        offset: u64,
-        pointers_per_block: u64,
-    ) -> anyhow::Result<u32> {
-        if pointer == 0 {
-            return Ok(pointer);
+    // Inserts the entry below the index node at `block`. Returns whether the
+    // name is new and, when the node had to be split, the sibling its parent
+    // has to point to.
+    fn insert_dir_entry(
+        &mut self,
+        inode: &mut Inode,
+        block: u32,
+        name: &OsStr,
+        index: u32,
+    ) -> fuse_rs::Result<(bool, Option<(u64, u32)>)> {
+        let mut node = self.read_dir_index(inode, block)?;
+        let position = node.position(util::hash_name(name));
+        let child = node.children[position].1;
+
+        let (added, split) = if node.level == 0 {
+            self.insert_leaf_entry(inode, child, name, index)?
+        } else {
+            self.insert_dir_entry(inode, child, name, index)?
+        };
+
+        // Only the root keeps the entry count.
+        let is_root = block == 0;
+        if is_root && added {
+            node.count += 1;
         }
 
-        let off = if index < pointers_per_block {
-            index & (pointers_per_block - 1)
-        } else {
-            index / pointers_per_block - 1
+        let split = match split {
+            Some(split) => split,
+            None => {
+                if is_root && added {
+                    self.write_dir_block(inode, 0, &node.serialize().map_err(|_| Errno::EIO)?)?;
+                }
+                return Ok((added, None));
+            }
         };
 
-        let block = self.read_u32(off, pointer)?;
+        node.children.insert(position + 1, split);
+        let buf = node.serialize().map_err(|_| Errno::EIO)?;
+        if buf.len() <= self.superblock().block_size as usize {
+            self.write_dir_block(inode, block, &buf)?;
+            return Ok((added, None));
+        }
 
-        if block == 0 || index < pointers_per_block {
-            return Ok(block);
+        let (hash, mut sibling) = node.split_off();
+        if !is_root {
+            let sibling_block = self.append_dir_block(inode);
+            self.write_dir_block(inode, block, &node.serialize().map_err(|_| Errno::EIO)?)?;
+            self.write_dir_block(
+                inode,
+                sibling_block,
+                &sibling.serialize().map_err(|_| Errno::EIO)?,
+            )?;
+            return Ok((added, Some((hash, sibling_block))));
         }
 
-        self.find_indirect(
-            block,
-            index & (pointers_per_block - 1),
-            offset,
-            pointers_per_block,
-        )
+        // The root has to stay in the first block, so both halves move down
+        // and the tree grows by one level.
+        let left_block = self.append_dir_block(inode);
+        let right_block = self.append_dir_block(inode);
+        let mut left = DirectoryIndex::new(node.level, mem::take(&mut node.children));
+        self.write_dir_block(
+            inode,
+            left_block,
+            &left.serialize().map_err(|_| Errno::EIO)?,
+        )?;
+        self.write_dir_block(
+            inode,
+            right_block,
+            &sibling.serialize().map_err(|_| Errno::EIO)?,
+        )?;
+        node.level += 1;
+        node.children = vec![(0, left_block), (hash, right_block)];
+        self.write_dir_block(inode, 0, &node.serialize().map_err(|_| Errno::EIO)?)?;
+
+        Ok((added, None))
     }
 
-    fn save_indirect(
+    fn insert_leaf_entry(
         &mut self,
-        pointer: u32,
+        inode: &mut Inode,
         block: u32,
-        index: u64,
-        pointers_per_block: u64,
-    ) -> anyhow::Result<()> {
-        assert_ne!(pointer, 0);
-        let offset = index & (pointers_per_block - 1);
+        name: &OsStr,
+        index: u32,
+    ) -> fuse_rs::Result<(bool, Option<(u64, u32)>)> {
+        let blk_size = self.superblock().block_size as usize;
+        let mut leaf = self.read_dir_leaf(inode, block)?;
+        let added = leaf.entries.insert(name.to_os_string(), index).is_none();
+
+        let buf = leaf.serialize().map_err(|_| Errno::EIO)?;
+        if buf.len() <= blk_size {
+            self.write_dir_block(inode, block, &buf)?;
+            return Ok((added, None));
+        }
 
-        if index < pointers_per_block {
-            self.write_data(&block.to_le_bytes(), offset * 4, pointer)
-                .map(|_| ())
-        } else {
-            let indirect_offset = index / pointers_per_block - 1;
-            let new_pointer = self.read_u32(indirect_offset, pointer)?;
-            self.save_indirect(new_pointer, block, offset, pointers_per_block)
+        let (hash, mut sibling) = leaf.split_off().ok_or(Errno::ENOSPC)?;
+        let left = leaf.serialize().map_err(|_| Errno::EIO)?;
+        let right = sibling.serialize().map_err(|_| Errno::EIO)?;
+        if left.len() > blk_size || right.len() > blk_size {
+            return Err(Errno::ENOSPC);
         }
+
+        let sibling_block = self.append_dir_block(inode);
+        self.write_dir_block(inode, block, &left)?;
+        self.write_dir_block(inode, sibling_block, &right)?;
+        Ok((added, Some((hash, sibling_block))))
     }
 
-    // (group_block_index, bitmap_index)
-    #[inline]
-    fn inode_offsets(&self, index: u32) -> (u64, u64) {
-        let inodes_per_group = self.superblock().data_blocks_per_group as u64;
-        let inode_bg = (index as u64 - 1) / inodes_per_group;
-        let bitmap_index = (index as u64 - 1) & (inodes_per_group - 1);
-        (inode_bg, bitmap_index)
+    // Removes `name` from the directory and returns the inode it pointed to.
+    // Leaves are never merged back, like the directory they can only grow.
+    pub(crate) fn remove_dir_entry(
+        &mut self,
+        dir_index: u32,
+        name: &OsStr,
+    ) -> fuse_rs::Result<u32> {
+        let mut inode = self.find_dir_inode(dir_index)?;
+        let first = self.read_dir_block(&inode, 0)?;
+        let index = if DirectoryIndex::is_index(&first) {
+            let mut root =
+                DirectoryIndex::deserialize_from(first.as_slice()).map_err(|_| Errno::EIO)?;
+            let hash = util::hash_name(name);
+            let mut block = root.children[root.position(hash)].1;
+            let mut level = root.level;
+            while level > 0 {
+                let node = self.read_dir_index(&inode, block)?;
+                block = node.children[node.position(hash)].1;
+                level = node.level;
+            }
+
+            let mut leaf = self.read_dir_leaf(&inode, block)?;
+            let index = leaf.entries.remove(name).ok_or(Errno::ENOENT)?;
+            self.write_dir_block(
+                &mut inode,
+                block,
+                &leaf.serialize().map_err(|_| Errno::EIO)?,
+            )?;
+            root.count -= 1;
+            self.write_dir_block(&mut inode, 0, &root.serialize().map_err(|_| Errno::EIO)?)?;
+            index
+        } else {
+            let mut dir = self.read_plain_dir(&inode)?;
+            let index = dir.entries.remove(name).ok_or(Errno::ENOENT)?;
+            let buf = dir.serialize().map_err(|_| Errno::EIO)?;
+            self.write_inode_data(&mut inode, &buf, 0)?;
+            index
+        };
+
+        inode.update_modified_at();
+        self.save_inode(inode, dir_index).map_err(|_| Errno::EIO)?;
+        Ok(index)
     }
 
-    #[inline]
-    fn inode_seek_position(&self, index: u32) -> u64 {
-        let (group_index, bitmap_index) = self.inode_offsets(index);
-        let block_size = self.superblock().block_size;
-        group_index * util::block_group_size(block_size)
-            + 2 * block_size as u64
-            + bitmap_index * INODE_SIZE
-            + SUPERBLOCK_SIZE
+    fn is_dir_empty(&self, dir_index: u32) -> fuse_rs::Result<bool> {
+        let inode = self.find_dir_inode(dir_index)?;
+        let first = self.read_dir_block(&inode, 0)?;
+        if DirectoryIndex::is_index(&first) {
+            let root =
+                DirectoryIndex::deserialize_from(first.as_slice()).map_err(|_| Errno::EIO)?;
+            Ok(root.count == 0)
+        } else {
+            Ok(self.read_plain_dir(&inode)?.entries.is_empty())
+        }
     }
 
-    #[inline]
-    fn data_block_offsets(&self, index: u32) -> (u64, u64) {
-        let data_blocks_per_group = self.superblock().data_blocks_per_group as u64;
-        let group_index = (index as u64 - 1) / data_blocks_per_group;
-        let block_index = (index as u64 - 1) & (data_blocks_per_group - 1);
+    // Reads a directory stored in the original format, a single `Directory`
+    // spanning as many blocks as needed.
+    fn read_plain_dir(&self, inode: &Inode) -> fuse_rs::Result<Directory> {
+        let mut buf = vec![0u8; inode.size as usize];
+        self.read_inode_data(inode, &mut buf, 0)?;
 
-        (group_index, block_index)
+        Directory::deserialize_from(buf.as_slice()).map_err(|_| Errno::EIO)
     }
 
-    #[inline]
-    fn data_block_seek_position(&self, index: u32) -> u64 {
-        let (group_index, block_index) = self.data_block_offsets(index);
+    fn read_dir_index(&self, inode: &Inode, block: u32) -> fuse_rs::Result<DirectoryIndex> {
+        let buf = self.read_dir_block(inode, block)?;
+        DirectoryIndex::deserialize_from(buf.as_slice()).map_err(|_| Errno::EIO)
+    }
 
-        let block_size = self.superblock().block_size;
-        group_index * util::block_group_size(block_size)
-            + 2 * block_size as u64
-            + self.superblock().data_blocks_per_group as u64 * INODE_SIZE
-            + SUPERBLOCK_SIZE
-            + block_size as u64 * block_index
+    fn read_dir_leaf(&self, inode: &Inode, block: u32) -> fuse_rs::Result<Directory> {
+        let buf = self.read_dir_block(inode, block)?;
+        Directory::deserialize_from(buf.as_slice()).map_err(|_| Errno::EIO)
     }
 
-    fn allocate_inode(&mut self) -> Option<u32> {
-        // TODO: handle when group has run out of space
-        let group_index = self.groups().iter().position(|g| g.free_inodes() > 0)?;
-        self.superblock_mut().free_inodes -= 1;
-        let group = self.groups_mut().get_mut(group_index).unwrap();
+    // `block` is the position of the block inside the directory, not a data
+    // block index.
+    fn read_dir_block(&self, inode: &Inode, block: u32) -> fuse_rs::Result<Vec<u8>> {
+        let blk_size = self.superblock().block_size as u64;
+        let mut buf = vec![0u8; blk_size as usize];
+        self.read_inode_data(inode, &mut buf, block as u64 * blk_size)?;
+        Ok(buf)
+    }
 
-        let index = group.allocate_inode()?;
-        Some(index as u32 + group_index as u32 * self.superblock().data_blocks_per_group)
+    fn write_dir_block(
+        &mut self,
+        inode: &mut Inode,
+        block: u32,
+        buf: &[u8],
+    ) -> fuse_rs::Result<()> {
+        let blk_size = self.superblock().block_size as u64;
+        self.write_inode_data(inode, buf, block as u64 * blk_size)?;
+        Ok(())
     }
 
-    fn allocate_data_block(&mut self) -> Option<u32> {
-        // TODO: handle when group has run out of space
-        let group_index = self
-            .groups()
-            .iter()
-            .position(|g| g.free_data_blocks() > 0)?;
+    // Directories grow one whole block at a time, the block itself is only
+    // allocated once something is written to it.
+    fn append_dir_block(&mut self, inode: &mut Inode) -> u32 {
+        let blk_size = self.superblock().block_size as u64;
+        let block = inode.size / blk_size;
+        inode.size += blk_size;
+        block as u32
+    }
 
-        self.superblock_mut().free_blocks -= 1;
-        let group = self.groups_mut().get_mut(group_index).unwrap();
+    // Only regular files can be read and written, the data of a fast symlink
+    // is not made of block pointers.
+    pub(crate) fn find_file(&self, index: u32) -> fuse_rs::Result<Inode> {
+        if index == 0 {
+            return Err(Errno::EINVAL);
+        }
 
-        let index = group.allocate_data_block()?;
-        Some(index as u32 + group_index as u32 * self.superblock().data_blocks_per_group)
+        let inode = self.find_inode(index)?;
+        if inode.is_dir() {
+            Err(Errno::EISDIR)
+        } else if inode.is_symlink() {
+            Err(Errno::ELOOP)
+        } else if !inode.is_file() {
+            Err(Errno::EINVAL)
+        } else {
+            Ok(inode)
+        }
     }
 
-    #[inline]
-    fn release_data_blocks(&mut self, blocks: &[u32]) {
-        for block in blocks {
-            let (group_index, block_index) = self.data_block_offsets(*block);
-            // TODO: release multiple blocks from the same group in a single call
-            self.groups_mut()
-                .get_mut(group_index as usize)
-                .unwrap()
-                .release_data_block(1 + block_index as usize);
-        }
-        self.superblock_mut().free_blocks += blocks.len() as u32;
-    }
-
-    #[inline]
-    fn release_inode(&mut self, index: u32) {
-        let (group_index, _) = self.inode_offsets(index);
-        self.groups_mut()
-            .get_mut(group_index as usize)
-            .unwrap()
-            .release_inode(index as usize);
-        self.superblock_mut().free_inodes += 1;
-    }
+    // Chooses how a file maps its data, whatever `mkfs --extents` picked for
+    // new files. Only a file without blocks can switch.
+    pub(crate) fn set_extents(&mut self, index: u32, extents: bool) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let mut inode = fs.find_file(index)?;
+            if inode.block_count != 0 {
+                return Err(Errno::ENOTEMPTY);
+            }
+            if fs.superblock().revision == 0 {
+                return Err(Errno::EOPNOTSUPP);
+            }
 
-    fn release_indirect_block(&mut self, block: u32) -> anyhow::Result<()> {
-        let blocks = self.read_indirect_block(block)?;
-        self.release_data_blocks(&blocks);
-        Ok(())
+            if extents {
+                inode.flags |= EXTENTS_FLAG;
+                fs.superblock_mut().features |= FEATURE_EXTENTS;
+            } else {
+                inode.flags &= !EXTENTS_FLAG;
+            }
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)
+        })
     }
 
-    fn release_double_indirect_block(&mut self, block: u32) -> anyhow::Result<()> {
-        let pointers_per_block = self.superblock().block_size as usize / 4;
-        let indirect_blocks = self.read_indirect_block(block)?;
-        let mut blocks = Vec::with_capacity(indirect_blocks.len() * pointers_per_block);
-        for b in indirect_blocks.iter().filter(|x| **x != 0) {
-            blocks.append(&mut self.read_indirect_block(*b)?);
-        }
+    // Reads and writes the file behind an open handle, be it a FUSE one or a
+    // `gotenks::File`.
+    pub(crate) fn read_file(
+        &mut self,
+        index: u32,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> fuse_rs::Result<usize> {
+        let mut inode = self.find_file(index)?;
+        let total_read = self.read_inode_data(&inode, buf, offset)?;
 
-        self.release_data_blocks(&indirect_blocks);
-        self.release_data_blocks(&blocks);
+        inode.update_accessed_at();
+        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
 
-        Ok(())
+        Ok(total_read)
     }
 
-    #[inline]
-    fn write_data(&mut self, data: &[u8], offset: u64, block_index: u32) -> anyhow::Result<usize> {
-        let block_offset = self.data_block_seek_position(block_index);
-
-        let buf = self.mmap_mut().as_mut();
-        let mut cursor = Cursor::new(buf);
-        cursor.seek(SeekFrom::Start(block_offset + offset))?;
-        Ok(cursor.write(data)?)
+    pub(crate) fn write_file(
+        &mut self,
+        index: u32,
+        buf: &[u8],
+        offset: u64,
+    ) -> fuse_rs::Result<usize> {
+        self.transaction(|fs| {
+            let mut inode = fs.find_file(index)?;
+            let total_wrote = fs.write_inode_data(&mut inode, buf, offset)?;
+
+            inode.update_modified_at();
+            inode.adjust_size(offset + total_wrote as u64);
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)?;
+            Ok(total_wrote)
+        })
     }
 
-    #[inline]
-    fn read_data(&self, data: &mut [u8], offset: u64, block_index: u32) -> anyhow::Result<usize> {
-        let block_offset = self.data_block_seek_position(block_index);
-        let buf = self.mmap().as_ref();
-        let mut cursor = Cursor::new(buf);
-        cursor.seek(SeekFrom::Start(block_offset + offset))?;
+    pub(crate) fn read_inode_data(
+        &self,
+        inode: &Inode,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> fuse_rs::Result<usize> {
+        let blk_size = self.superblock().block_size as u64;
+        let len = buf.len().min(inode.size.saturating_sub(offset) as usize);
+        let mut total_read = 0;
+
+        while total_read != len {
+            let offset = offset + total_read as u64;
+            let (block_index, space_left) = self.lookup_data_block(inode, offset)?;
+
+            let max_read_len = (len - total_read).min(space_left as usize);
+            let buf = &mut buf[total_read..total_read + max_read_len];
+            // Nothing was ever written to holes, they read back as zeros.
+            let read = if block_index == 0 {
+                buf.iter_mut().for_each(|b| *b = 0);
+                buf.len()
+            } else {
+                self.read_data(buf, offset % blk_size, block_index)
+                    .map_err(|_| Errno::EIO)?
+            };
 
-        cursor.read_exact(data)?;
+            total_read += read;
+        }
 
-        Ok(data.len())
+        Ok(total_read)
     }
 
-    #[inline]
-    fn read_u32(&self, offset: u64, block_index: u32) -> anyhow::Result<u32> {
-        let mut data = [0u8; 4];
-        self.read_data(&mut data, offset * 4, block_index)?;
-        Ok(u32::from_le_bytes(data))
-    }
+    fn write_inode_data(
+        &mut self,
+        inode: &mut Inode,
+        buf: &[u8],
+        offset: u64,
+    ) -> fuse_rs::Result<usize> {
+        let blk_size = self.superblock().block_size as u64;
+        let mut total_wrote = 0;
+        let write = if inode.is_dir() {
+            Self::write_metadata
+        } else {
+            Self::write_data
+        };
 
-    fn read_indirect_block(&mut self, block: u32) -> anyhow::Result<Vec<u32>> {
-        let pointers_per_block = self.superblock().block_size as usize / 4;
-        let mut vec = Vec::with_capacity(pointers_per_block);
-        for i in 0..pointers_per_block {
-            let b = self.read_u32(i as u64, block)?;
-            if b != 0 {
-                vec.push(b);
+        while total_wrote != buf.len() {
+            let offset = offset + total_wrote as u64;
+            let (mut block_index, space_left) = match self.lookup_data_block(inode, offset) {
+                // Like write(2), whatever fits below the maximum size.
+                Err(Errno::EFBIG) if total_wrote > 0 => break,
+                result => result?,
+            };
+            let max_write_len = (buf.len() - total_wrote).min(space_left as usize);
+            if block_index == 0 {
+                block_index = self.find_data_block(inode, offset)?.0;
+                // A new block may still hold data from a previous owner, what
+                // this write doesn't cover has to read back as zeros.
+                if max_write_len < blk_size as usize {
+                    write(self, &vec![0u8; blk_size as usize], 0, block_index)
+                        .map_err(|_| Errno::EIO)?;
+                }
             }
-        }
 
-        Ok(vec)
-    }
+            let wrote = write(
+                self,
+                &buf[total_wrote..total_wrote + max_write_len],
+                offset % blk_size,
+                block_index,
+            )
+            .map_err(|_| Errno::EIO)?;
 
-    #[inline]
-    fn groups(&self) -> &[Group] {
-        self.groups.as_ref().unwrap()
-    }
+            total_wrote += wrote;
+        }
 
-    #[inline]
-    fn groups_mut(&mut self) -> &mut [Group] {
-        self.groups.as_mut().unwrap()
+        Ok(total_wrote)
     }
 
-    #[inline]
-    fn superblock(&self) -> &Superblock {
-        self.sb.as_ref().unwrap()
-    }
+    // Returns the block holding `offset` and how many bytes are left in it
+    // from that offset. The block is 0 when nothing is allocated there yet.
+    fn lookup_data_block(&self, inode: &Inode, offset: u64) -> fuse_rs::Result<(u32, u32)> {
+        let blk_size = self.superblock().block_size as u64;
+        let index = offset / blk_size;
 
-    #[inline]
-    fn superblock_mut(&mut self) -> &mut Superblock {
-        self.sb.as_mut().unwrap()
-    }
+        let block = if inode.uses_extents() {
+            if index > u32::MAX as u64 {
+                return Err(Errno::EFBIG);
+            }
+            self.lookup_extent(inode, index as u32)?
+        } else if index < DIRECT_POINTERS {
+            inode.find_direct_block(index as usize)
+        } else {
+            let (depth, index) = self.indirect_position(index).ok_or(Errno::EFBIG)?;
+            self.find_indirect(inode.indirect_root(depth), index, depth)
+                .map_err(|_| Errno::EIO)?
+        };
 
-    #[inline]
-    fn mmap(&self) -> &MmapMut {
-        self.mmap.as_ref().unwrap()
+        Ok((block, ((index + 1) * blk_size - offset) as u32))
     }
 
-    #[inline]
-    fn mmap_mut(&mut self) -> &mut MmapMut {
-        self.mmap.as_mut().unwrap()
-    }
-}
+    fn find_data_block(&mut self, inode: &mut Inode, offset: u64) -> fuse_rs::Result<(u32, u32)> {
+        let (block, space_left) = self.lookup_data_block(inode, offset)?;
+        if block != 0 {
+            return Ok((block, space_left));
+        }
 
-impl fuse_rs::Filesystem for GotenksFS {
-    fn metadata(&self, path: &Path) -> fuse_rs::Result<FileStat> {
-        let (inode, index) = self.find_inode_from_path(path)?;
-        Ok(inode.to_stat(index))
-    }
+        let blk_size = self.superblock().block_size as u64;
+        let index = offset / blk_size;
 
-    fn read_dir(
-        &mut self,
-        path: &Path,
-        _offset: u64,
-        _file_info: fuse_rs::fs::FileInfo,
-    ) -> fuse_rs::Result<Vec<fuse_rs::fs::DirEntry>> {
-        // TODO: check permissions
-        let (dir, _index) = self.find_dir(path)?;
+        let free_blocks = self.superblock().free_blocks;
+        let block = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
+        if inode.uses_extents() {
+            self.insert_extent(inode, index as u32, block)?;
+        } else if index < DIRECT_POINTERS {
+            inode
+                .add_block(block, index as usize)
+                .map_err(|_| Errno::ENOSPC)?;
+        } else {
+            let (depth, index) = self.indirect_position(index).ok_or(Errno::EFBIG)?;
+            let mut root = inode.indirect_root(depth);
+            if root == 0 {
+                root = self.allocate_indirect_block()?;
+                inode.set_indirect_root(depth, root);
+            }
 
-        let mut entries = Vec::with_capacity(dir.entries.len());
-        for (name, index) in dir.entries {
-            let inode = self.find_inode(index)?;
-            let stat = inode.to_stat(index);
-            entries.push(fuse_rs::fs::DirEntry {
-                name,
-                metadata: Some(stat),
-                offset: None,
-            });
+            self.save_indirect(root, block, index, depth)?;
         }
 
-        Ok(entries)
+        // Indirect blocks allocated on the way count as well.
+        inode.block_count += free_blocks - self.superblock().free_blocks;
+
+        Ok((block, space_left))
     }
 
-    fn create(
-        &mut self,
-        path: &Path,
-        permissions: Mode,
-        file_info: &mut fuse_rs::fs::OpenFileInfo,
-    ) -> fuse_rs::Result<()> {
-        let index = self.allocate_inode().ok_or_else(|| Errno::ENOSPC)?;
-        let mut inode = Inode::new();
-        inode.mode = permissions.bits();
-        inode.user_id = self.superblock().uid;
-        inode.group_id = self.superblock().gid;
-
-        let (mut parent, parent_index) = self.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
-        parent.entries.insert(
-            path.file_name()
-                .map(|p| p.to_os_string())
-                .ok_or(Errno::EINVAL)?,
-            index,
-        );
+    // The depth of the indirect tree mapping the `index`th block of a file and
+    // the position of the block within that tree, or `None` when `index` is
+    // past what the triple indirect tree maps.
+    fn indirect_position(&self, index: u64) -> Option<(u32, u64)> {
+        let pointers_per_block = self.superblock().block_size as u64 / mem::size_of::<u32>() as u64;
+        // Inodes had no triple indirect pointer before revisions.
+        let max_depth = if self.superblock().revision == 0 {
+            2
+        } else {
+            3
+        };
+        let mut index = index - DIRECT_POINTERS;
+        for depth in 1..=max_depth {
+            let span = pointers_per_block.pow(depth);
+            if index < span {
+                return Some((depth, index));
+            }
+            index -= span;
+        }
 
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
-        self.save_dir(parent, parent_index)
-            .map_err(|_| Errno::EIO)?;
+        None
+    }
 
-        file_info.set_handle(index as u64);
-        Ok(())
+    // A zeroed block to hold pointers.
+    fn allocate_indirect_block(&mut self) -> fuse_rs::Result<u32> {
+        let block = self.allocate_data_block().ok_or(Errno::ENOSPC)?;
+        let blk_size = self.superblock().block_size as usize;
+        self.write_metadata(&vec![0u8; blk_size], 0, block)
+            .map_err(|_| Errno::EIO)?;
+        Ok(block)
     }
 
-    fn statfs(&self, path: &Path) -> fuse_rs::Result<libc::statvfs> {
-        if path == Path::new("/") {
-            let sb = self.superblock();
-            let stat = libc::statvfs {
-                f_bsize: sb.block_size as u64,
-                f_frsize: sb.block_size as u64,
-                f_blocks: sb.block_count,
-                f_bfree: sb.free_blocks,
-                f_bavail: sb.free_blocks,
-                f_files: sb.inode_count,
-                f_ffree: sb.free_inodes,
-                f_favail: sb.free_inodes,
-                f_namemax: 255,
-                f_fsid: 0, // ignored by fuse
-                f_flag: 0, // ignored by fuse
+    fn lookup_extent(&self, inode: &Inode, logical: u32) -> fuse_rs::Result<u32> {
+        let mut node = inode.extent_root();
+        loop {
+            let extent = match node.position(logical) {
+                Some(i) => node.extents[i],
+                None => return Ok(0),
             };
 
-            Ok(stat)
-        } else {
-            Err(Errno::ENOENT)
+            if node.depth == 0 {
+                return Ok(if extent.contains(logical) {
+                    extent.physical + (logical - extent.logical)
+                } else {
+                    0
+                });
+            }
+
+            node = self
+                .read_extent_node(extent.physical)
+                .map_err(|_| Errno::EIO)?;
         }
     }
 
-    fn open(
+    fn insert_extent(
         &mut self,
-        path: &Path,
-        file_info: &mut fuse_rs::fs::OpenFileInfo,
+        inode: &mut Inode,
+        logical: u32,
+        physical: u32,
     ) -> fuse_rs::Result<()> {
-        // TODO: check permissions
-        let (mut inode, index) = self.find_inode_from_path(path)?;
-        inode.update_accessed_at();
-
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
-        file_info.set_handle(index as u64);
+        let mut root = inode.extent_root();
+        self.insert_extent_below(&mut root, logical, physical)?;
+
+        // The root doesn't fit in the inode anymore: its entries move to a
+        // new block and the tree grows a level.
+        if root.extents.len() > ExtentNode::capacity(DIRECT_POINTERS as usize) {
+            let block = self.allocate_data_block().ok_or(Errno::ENOSPC)?;
+            self.write_extent_node(block, &root)
+                .map_err(|_| Errno::EIO)?;
+            root = ExtentNode {
+                depth: root.depth + 1,
+                extents: vec![Extent {
+                    logical: root.extents[0].logical,
+                    physical: block,
+                    len: 0,
+                }],
+            };
+        }
 
+        inode.set_extent_root(&root);
         Ok(())
     }
 
-    fn write(
+    // Inserts into the subtree under `node`, splitting the child nodes that
+    // end up with more entries than a block holds.
+    fn insert_extent_below(
         &mut self,
-        _path: &Path,
-        buf: &[u8],
-        offset: u64,
-        file_info: &mut fuse_rs::fs::WriteFileInfo,
-    ) -> fuse_rs::Result<usize> {
-        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
-        if index == 0 {
-            return Err(Errno::EINVAL);
+        node: &mut ExtentNode,
+        logical: u32,
+        physical: u32,
+    ) -> fuse_rs::Result<()> {
+        if node.depth == 0 {
+            node.insert(logical, physical);
+            return Ok(());
         }
-        let mut total_wrote = 0;
-        let mut inode = self.find_inode(index)?;
-        let overwrite = inode.size > offset;
-        let mut offset = offset;
-        let blk_size = self.superblock().block_size;
-
-        while total_wrote != buf.len() {
-            let direct_block_index = offset / blk_size as u64;
-            let (block_index, space_left) = self.find_data_block(&mut inode, offset, false)?;
 
-            let max_write_len = buf.len().min(space_left as usize);
-            let offset_in_block = if total_wrote != 0 {
-                0
-            } else {
-                offset - direct_block_index * blk_size as u64
-            };
-            let wrote = self
-                .write_data(
-                    &buf[total_wrote..buf.len().min(max_write_len + total_wrote)],
-                    offset_in_block,
-                    block_index,
-                )
+        let i = node.position(logical).unwrap_or(0);
+        let block = node.extents[i].physical;
+        let mut child = self.read_extent_node(block).map_err(|_| Errno::EIO)?;
+        self.insert_extent_below(&mut child, logical, physical)?;
+        node.extents[i].logical = child.extents[0].logical;
+
+        let capacity = ExtentNode::capacity(self.superblock().block_size as usize / 4);
+        if child.extents.len() > capacity {
+            let sibling = child.split_off();
+            let sibling_block = self.allocate_data_block().ok_or(Errno::ENOSPC)?;
+            self.write_extent_node(sibling_block, &sibling)
                 .map_err(|_| Errno::EIO)?;
-
-            total_wrote += wrote;
-            offset += wrote as u64;
+            node.extents.insert(
+                i + 1,
+                Extent {
+                    logical: sibling.extents[0].logical,
+                    physical: sibling_block,
+                    len: 0,
+                },
+            );
         }
 
-        inode.update_modified_at();
-        if overwrite {
-            inode.adjust_size(total_wrote as u64);
-        } else {
-            inode.increment_size(total_wrote as u64);
-        }
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
-        Ok(total_wrote)
+        self.write_extent_node(block, &child)
+            .map_err(|_| Errno::EIO)
     }
 
-    fn read(
-        &mut self,
-        _path: &Path,
-        buf: &mut [u8],
-        offset: u64,
-        file_info: fuse_rs::fs::FileInfo,
-    ) -> fuse_rs::Result<usize> {
-        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
-        if index == 0 {
-            return Err(Errno::EINVAL);
-        }
-        let mut inode = self.find_inode(index)?;
-        let mut total_read: usize = 0;
-        let mut offset = offset;
-        let blk_size = self.superblock().block_size;
-
-        let should_read = buf.len().min(inode.size as usize);
-        while total_read != should_read as usize {
-            let direct_block_index = offset / blk_size as u64;
-            let (block_index, space_left) = self.find_data_block(&mut inode, offset, true)?;
-
-            let max_read_len = buf.len().min(space_left as usize);
-            let max_read_len = buf.len().min(max_read_len + total_read);
-            let offset_in_block = if total_read != 0 {
-                0
-            } else {
-                offset - direct_block_index * blk_size as u64
-            };
+    pub(crate) fn read_extent_node(&self, block: u32) -> anyhow::Result<ExtentNode> {
+        let mut buf = vec![0u8; self.superblock().block_size as usize];
+        self.read_data(&mut buf, 0, block)?;
+        Ok(ExtentNode::from_bytes(&buf))
+    }
 
-            let read = self
-                .read_data(
-                    &mut buf[total_read..max_read_len],
-                    offset_in_block,
-                    block_index,
-                )
-                .map_err(|_| Errno::EIO)?;
+    fn write_extent_node(&mut self, block: u32, node: &ExtentNode) -> anyhow::Result<()> {
+        let buf = node.to_bytes(self.superblock().block_size as usize);
+        self.write_metadata(&buf, 0, block).map(|_| ())
+    }
 
-            total_read += read;
-            offset += read as u64;
+    // Follows the indirect tree of `depth` levels under `pointer` down to
+    // its `index`th data block.
+    fn find_indirect(&self, pointer: u32, index: u64, depth: u32) -> anyhow::Result<u32> {
+        let pointers_per_block = self.superblock().block_size as u64 / 4;
+        let mut block = pointer;
+        for depth in (0..depth).rev() {
+            if block == 0 {
+                break;
+            }
+            let span = pointers_per_block.pow(depth);
+            block = self.read_u32((index / span) % pointers_per_block, block)?;
         }
 
-        inode.update_accessed_at();
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
-
-        Ok(total_read)
+        Ok(block)
     }
 
-    fn ftruncate(
+    // Points the `index`th entry of the indirect tree under `pointer` to
+    // `block`, allocating the missing indirect blocks on the way.
+    fn save_indirect(
         &mut self,
-        _path: &Path,
-        _len: u64,
-        file_info: fuse_rs::fs::FileInfo,
+        pointer: u32,
+        block: u32,
+        index: u64,
+        depth: u32,
     ) -> fuse_rs::Result<()> {
-        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
-        if index == 0 {
-            return Err(Errno::EINVAL);
+        assert_ne!(pointer, 0);
+        let pointers_per_block = self.superblock().block_size as u64 / 4;
+        let mut pointer = pointer;
+        for depth in (1..depth).rev() {
+            let span = pointers_per_block.pow(depth);
+            let offset = (index / span) % pointers_per_block;
+            let mut next = self.read_u32(offset, pointer).map_err(|_| Errno::EIO)?;
+            if next == 0 {
+                next = self.allocate_indirect_block()?;
+                self.write_metadata(&next.to_le_bytes(), offset * 4, pointer)
+                    .map_err(|_| Errno::EIO)?;
+            }
+            pointer = next;
         }
-        let mut inode = self.find_inode(index)?;
-
-        // TODO: truncate using the length arg
-        let blocks = inode.truncate();
-        self.release_data_blocks(&blocks);
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
 
-        Ok(())
+        let offset = index % pointers_per_block;
+        self.write_metadata(&block.to_le_bytes(), offset * 4, pointer)
+            .map(|_| ())
+            .map_err(|_| Errno::EIO)
     }
 
-    fn fmetadata(
-        &self,
-        _path: &Path,
-        file_info: fuse_rs::fs::FileInfo,
-    ) -> fuse_rs::Result<FileStat> {
-        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
-        if index == 0 {
-            return Err(Errno::EINVAL);
-        }
-        let inode = self.find_inode(index)?;
-        Ok(inode.to_stat(index))
+    // (group_block_index, bitmap_index)
+    #[inline]
+    pub(crate) fn inode_offsets(&self, index: u32) -> (u64, u64) {
+        let inodes_per_group = self.superblock().data_blocks_per_group as u64;
+        let inode_bg = (index as u64 - 1) / inodes_per_group;
+        let bitmap_index = (index as u64 - 1) & (inodes_per_group - 1);
+        (inode_bg, bitmap_index)
     }
 
-    fn set_permissions(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
-        let (mut inode, index) = self.find_inode_from_path(path)?;
-        inode.mode |= mode.bits();
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)
+    #[inline]
+    pub(crate) fn inode_seek_position(&self, index: u32) -> u64 {
+        let (group_index, bitmap_index) = self.inode_offsets(index);
+        let block_size = self.superblock().block_size;
+        group_index * util::block_group_size(block_size)
+            + 2 * block_size as u64
+            + bitmap_index * INODE_SIZE
+            + SUPERBLOCK_SIZE
     }
 
-    fn remove_file(&mut self, path: &Path) -> fuse_rs::Result<()> {
-        let (mut parent, parent_index) = self.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
-        match parent
-            .entries
-            .remove(path.file_name().ok_or(Errno::EINVAL)?)
-        {
-            None => Err(Errno::ENOENT),
-            Some(index) => {
-                // TODO: handle when links > 1
-                let inode = self.find_inode(index)?;
-                self.release_data_blocks(&inode.direct_blocks());
-                if inode.indirect_block != 0 {
-                    self.release_indirect_block(inode.indirect_block)
-                        .map_err(|_| Errno::EIO)?;
+    #[inline]
+    pub(crate) fn data_block_offsets(&self, index: u32) -> (u64, u64) {
+        let data_blocks_per_group = self.superblock().data_blocks_per_group as u64;
+        let group_index = (index as u64 - 1) / data_blocks_per_group;
+        let block_index = (index as u64 - 1) & (data_blocks_per_group - 1);
+
+        (group_index, block_index)
+    }
+
+    #[inline]
+    pub(crate) fn data_block_seek_position(&self, index: u32) -> u64 {
+        let (group_index, block_index) = self.data_block_offsets(index);
+
+        let block_size = self.superblock().block_size;
+        group_index * util::block_group_size(block_size)
+            + 2 * block_size as u64
+            + self.superblock().data_blocks_per_group as u64 * INODE_SIZE
+            + SUPERBLOCK_SIZE
+            + block_size as u64 * block_index
+    }
+
+    pub(crate) fn used_inodes(&self) -> Vec<u32> {
+        let per_group = self.superblock().data_blocks_per_group;
+        let mut used = vec![];
+        for (g, group) in self.groups().iter().enumerate() {
+            for (i, bit) in group.inode_bitmap.iter().enumerate() {
+                if *bit {
+                    used.push(g as u32 * per_group + i as u32 + 1);
                 }
-                if inode.double_indirect_block != 0 {
-                    self.release_double_indirect_block(inode.double_indirect_block)
-                        .map_err(|_| Errno::EIO)?;
+            }
+        }
+        used
+    }
+
+    pub(crate) fn used_blocks(&self) -> Vec<u32> {
+        let per_group = self.superblock().data_blocks_per_group;
+        let mut used = vec![];
+        for (g, group) in self.groups().iter().enumerate() {
+            for (i, bit) in group.data_bitmap.iter().enumerate() {
+                if *bit {
+                    used.push(g as u32 * per_group + i as u32 + 1);
                 }
-                self.save_dir(parent, parent_index)
-                    .map_err(|_| Errno::EIO)?;
-                self.release_inode(index);
-                Ok(())
             }
         }
+        used
     }
 
-    fn create_dir(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
-        let index = self.allocate_inode().ok_or_else(|| Errno::ENOSPC)?;
-        let (mut parent, parent_index) = self.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
-        parent.entries.insert(
-            path.file_name()
-                .map(|p| p.to_os_string())
-                .ok_or(Errno::EINVAL)?,
-            index,
-        );
+    fn allocate_inode(&mut self) -> Option<u32> {
+        // TODO: handle when group has run out of space
+        let group_index = self.groups().iter().position(|g| g.free_inodes() > 0)?;
+        self.superblock_mut().free_inodes -= 1;
+        self.mark_group(group_index);
+        let group = self.groups_mut().get_mut(group_index).unwrap();
 
-        let mut inode = Inode::new();
-        inode.mode = SFlag::S_IFDIR.bits() | mode.bits();
-        inode.hard_links = 2;
-        inode.user_id = self.superblock().uid;
-        inode.group_id = self.superblock().gid;
+        let index = group.allocate_inode()?;
+        Some(index as u32 + group_index as u32 * self.superblock().data_blocks_per_group)
+    }
 
-        let data_block_index = self.allocate_data_block().ok_or_else(|| Errno::ENOSPC)?;
-        let dir = Directory::default();
+    fn allocate_data_block(&mut self) -> Option<u32> {
+        // TODO: handle when group has run out of space
+        let group_index = self
+            .groups()
+            .iter()
+            .position(|g| g.free_data_blocks() > 0)?;
 
-        inode
-            .add_block(data_block_index, 0)
-            .map_err(|_| Errno::EIO)?;
+        self.superblock_mut().free_blocks -= 1;
+        self.mark_group(group_index);
+        let group = self.groups_mut().get_mut(group_index).unwrap();
 
-        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
-        self.save_dir(dir, data_block_index)
-            .map_err(|_| Errno::EIO)?;
-        self.save_dir(parent, parent_index)
-            .map_err(|_| Errno::EIO)?;
+        let index = group.allocate_data_block()?;
+        Some(index as u32 + group_index as u32 * self.superblock().data_blocks_per_group)
+    }
 
-        Ok(())
+    #[inline]
+    pub(crate) fn release_data_blocks(&mut self, blocks: &[u32]) {
+        for block in blocks {
+            let (group_index, block_index) = self.data_block_offsets(*block);
+            // TODO: release multiple blocks from the same group in a single call
+            self.mark_group(group_index as usize);
+            self.groups_mut()
+                .get_mut(group_index as usize)
+                .unwrap()
+                .release_data_block(1 + block_index as usize);
+        }
+        self.superblock_mut().free_blocks += blocks.len() as u32;
     }
 
-    fn init(&mut self, _connection_info: &mut fuse_rs::fs::ConnectionInfo) -> fuse_rs::Result<()> {
-        let sb = self.superblock_mut();
-        sb.update_last_mounted_at();
-        sb.update_modified_at();
+    #[inline]
+    fn release_inode(&mut self, index: u32) {
+        let (group_index, bitmap_index) = self.inode_offsets(index);
+        self.mark_group(group_index as usize);
+        self.groups_mut()
+            .get_mut(group_index as usize)
+            .unwrap()
+            .release_inode(bitmap_index as usize + 1);
+        self.superblock_mut().free_inodes += 1;
+    }
 
+    fn release_file(&mut self, mut inode: Inode, index: u32) -> anyhow::Result<()> {
+        self.release_blocks_from(&mut inode, index, 0)?;
+        self.release_inode(index);
         Ok(())
     }
 
-    fn destroy(&mut self) -> fuse_rs::Result<()> {
-        let mut mmap = mem::replace(&mut self.mmap, None).unwrap();
-        let buf = mmap.as_mut();
-        let mut cursor = Cursor::new(buf);
+    fn truncate_file(&mut self, index: u32, len: u64) -> fuse_rs::Result<()> {
+        let mut inode = self.find_inode(index)?;
+        if inode.is_dir() {
+            return Err(Errno::EISDIR);
+        }
 
-        self.superblock_mut()
-            .serialize_into(&mut cursor)
-            .map_err(|_| Errno::EIO)?;
+        let blk_size = self.superblock().block_size as u64;
+        if len < inode.size {
+            self.release_blocks_from(&mut inode, index, len)
+                .map_err(|e| match e.downcast_ref::<Errno>() {
+                    Some(errno) => *errno,
+                    None => Errno::EIO,
+                })?;
+
+            // Whatever was past the new end of the last block has to read
+            // back as zeros if the file grows again.
+            let tail = len % blk_size;
+            if tail != 0 {
+                let (block, _) = self.lookup_data_block(&inode, len)?;
+                if block != 0 {
+                    self.write_data(&vec![0u8; (blk_size - tail) as usize], tail, block)
+                        .map_err(|_| Errno::EIO)?;
+                }
+            }
+        } else {
+            // Growing only leaves a hole after the current end, whose last
+            // block was already zeroed past it.
+            inode.update_modified_at();
+            inode.adjust_size(len);
+        }
+
+        self.save_inode(inode, index).map_err(|_| Errno::EIO)
+    }
+
+    // Sets the size to `len` and releases every block past it. A file whose
+    // blocks belong to more groups than a transaction can hold the bitmaps of
+    // is cut down from the end in several steps, each one committed as a
+    // shorter file.
+    fn release_blocks_from(
+        &mut self,
+        inode: &mut Inode,
+        index: u32,
+        len: u64,
+    ) -> anyhow::Result<()> {
+        let blk_size = self.superblock().block_size as u64;
+        let keep = len.div_ceil(blk_size);
+        let max_groups = self.max_dirty_groups();
+        while inode.block_count as usize > max_groups {
+            let cut = self.release_cut(inode, keep, max_groups)?;
+            let block_count = inode.block_count;
+            if cut <= keep {
+                break;
+            }
 
-        Group::serialize_into(&mut cursor, self.groups()).map_err(|_| Errno::EIO)?;
+            self.release_blocks_past(inode, cut * blk_size)?;
+            self.save_inode(inode.clone(), index)?;
+            self.commit()?;
+            if inode.block_count == block_count {
+                break;
+            }
+        }
 
-        Ok(mmap.flush().map_err(|_| Errno::EIO)?)
+        self.release_blocks_past(inode, len)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        gotenks::{types::Superblock, util, INODE_SIZE, ROOT_INODE},
-        mkfs,
-    };
-    use fuse_rs::{fs::FileStat, Filesystem};
-    use std::{ffi::OsString, path::PathBuf};
+    // How many groups a transaction may change the bitmaps of, both of them
+    // taking a block each. A few blocks are left for the rest of it: the
+    // superblocks, the inode and the partly released indirect blocks.
+    fn max_dirty_groups(&self) -> usize {
+        match self.superblock().journal_blocks {
+            0 => usize::MAX,
+            blocks => (blocks as usize / 2).saturating_sub(4).max(1),
+        }
+    }
 
-    const BLOCK_SIZE: u32 = 128;
+    // Where to cut the file for the blocks past the cut to belong to at most
+    // `max_groups` groups, going back from its end to the logical block
+    // `keep`. Indirect and extent blocks count like data blocks.
+    fn release_cut(&self, inode: &Inode, keep: u64, max_groups: usize) -> anyhow::Result<u64> {
+        let blk_size = self.superblock().block_size as u64;
+        let pointers_per_block = blk_size / mem::size_of::<u32>() as u64;
+        let end = inode.size.div_ceil(blk_size);
+        let mut groups = HashSet::new();
+        let mut cut = end;
+        let mut visit = |block: u32, logical: Option<u64>| {
+            groups.insert(self.data_block_offsets(block).0);
+            if groups.len() > max_groups && cut < end {
+                return false;
+            }
+            if let Some(logical) = logical {
+                cut = logical;
+            }
+            true
+        };
 
-    #[test]
-    fn inode_offsets() {
-        let mut fs = GotenksFS::default();
-        fs.sb = Some(Superblock::new(1024, 3, 0, 0));
-        fs.superblock_mut().data_blocks_per_group = 1024 * 8;
+        if inode.uses_extents() {
+            self.walk_back_extents(&inode.extent_root(), keep, &mut visit)?;
+            return Ok(cut.max(keep));
+        }
 
-        let (group_index, offset) = fs.inode_offsets(1);
-        assert_eq!(group_index, 0);
-        assert_eq!(offset, 0);
+        let mut starts = vec![DIRECT_POINTERS];
+        for depth in 1..3 {
+            starts.push(starts[depth - 1] + pointers_per_block.pow(depth as u32));
+        }
+        for depth in (1..=3).rev() {
+            let root = inode.indirect_root(depth);
+            if root == 0 {
+                continue;
+            }
+            let start = starts[depth as usize - 1];
+            if start + pointers_per_block.pow(depth) <= keep {
+                break;
+            }
+            if !visit(root, None)
+                || !self.walk_back_indirect(root, start, depth, keep, &mut visit)?
+            {
+                return Ok(cut.max(keep));
+            }
+        }
 
-        let (group_index, offset) = fs.inode_offsets(1024 * 8);
-        assert_eq!(group_index, 0);
-        assert_eq!(offset, 8191);
+        for i in (keep.min(DIRECT_POINTERS)..DIRECT_POINTERS).rev() {
+            let block = inode.direct_blocks[i as usize];
+            if block != 0 && !visit(block, Some(i)) {
+                break;
+            }
+        }
 
-        let (group_index, offset) = fs.inode_offsets(1024 * 8 - 1);
-        assert_eq!(group_index, 0);
-        assert_eq!(offset, 8190);
+        Ok(cut.max(keep))
+    }
 
-        let (group_index, offset) = fs.inode_offsets(2 * 1024 * 8 - 1);
-        assert_eq!(group_index, 1);
-        assert_eq!(offset, 8190);
+    // Hands `visit` the blocks mapped under the indirect `block` from the
+    // logical block `keep` on, last first, with the logical block of the data
+    // blocks. Returns false once `visit` did.
+    fn walk_back_indirect(
+        &self,
+        block: u32,
+        base: u64,
+        depth: u32,
+        keep: u64,
+        visit: &mut dyn FnMut(u32, Option<u64>) -> bool,
+    ) -> anyhow::Result<bool> {
+        let pointers_per_block = self.superblock().block_size as u64 / 4;
+        let span = pointers_per_block.pow(depth - 1);
+
+        for i in (0..pointers_per_block).rev() {
+            let start = base + i * span;
+            if start + span <= keep {
+                break;
+            }
+
+            let pointer = self.read_u32(i, block)?;
+            if pointer == 0 {
+                continue;
+            }
+
+            let more = if depth > 1 {
+                visit(pointer, None)
+                    && self.walk_back_indirect(pointer, start, depth - 1, keep, visit)?
+            } else {
+                visit(pointer, Some(start))
+            };
+            if !more {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
     }
 
-    #[test]
-    fn inode_seek_position() {
-        let mut fs = GotenksFS::default();
-        fs.sb = Some(Superblock::new(1024, 3, 0, 0));
-        fs.superblock_mut().data_blocks_per_group = 1024 * 8;
+    // Like `walk_back_indirect`, for the subtree under an extent node.
+    fn walk_back_extents(
+        &self,
+        node: &ExtentNode,
+        keep: u64,
+        visit: &mut dyn FnMut(u32, Option<u64>) -> bool,
+    ) -> anyhow::Result<bool> {
+        for (i, extent) in node.extents.iter().enumerate().rev() {
+            if node.depth == 0 {
+                for j in (0..extent.len).rev() {
+                    let logical = extent.logical as u64 + j as u64;
+                    if logical < keep {
+                        break;
+                    }
+                    if !visit(extent.physical + j, Some(logical)) {
+                        return Ok(false);
+                    }
+                }
+                continue;
+            }
 
-        let offset = fs.inode_seek_position(1);
-        assert_eq!(3072, offset);
+            // Everything under this child comes before `keep`.
+            if let Some(next) = node.extents.get(i + 1) {
+                if next.logical as u64 <= keep {
+                    break;
+                }
+            }
 
-        let offset = fs.inode_seek_position(2);
-        assert_eq!(3072 + INODE_SIZE, offset);
+            let child = self.read_extent_node(extent.physical)?;
+            if !visit(extent.physical, None) || !self.walk_back_extents(&child, keep, visit)? {
+                return Ok(false);
+            }
+        }
 
-        let offset = fs.inode_seek_position(8192);
-        assert_eq!(3072 + 8191 * INODE_SIZE, offset); // superblock + data bitmap + inode bitmap + 8191 inodes
+        Ok(true)
+    }
 
-        let offset = fs.inode_seek_position(8193);
-        assert_eq!(3072 + 8192 * INODE_SIZE + 1024 * 1024 * 8 + 2048, offset); // superblock + data bitmap + inode bitmap + inode table + data blocks + data bitmap + inode bitmap
+    // Sets the size to `len` and releases every block past it, including the
+    // indirect blocks left without any pointer.
+    fn release_blocks_past(&mut self, inode: &mut Inode, len: u64) -> anyhow::Result<()> {
+        let blk_size = self.superblock().block_size as u64;
+        let pointers_per_block = blk_size / mem::size_of::<u32>() as u64;
+        let keep = len.div_ceil(blk_size);
+
+        let free_blocks = self.superblock().free_blocks;
+        let blocks = inode.truncate(len, blk_size);
+        self.release_data_blocks(&blocks);
+
+        if inode.uses_extents() {
+            let mut root = inode.extent_root();
+            self.truncate_extents(&mut root, keep)?;
+            if root.extents.is_empty() {
+                root.depth = 0;
+            }
+            inode.set_extent_root(&root);
+        }
+
+        let mut start = DIRECT_POINTERS;
+        for depth in 1..=3 {
+            let root = inode.indirect_root(depth);
+            if root != 0 && self.truncate_indirect(root, keep.saturating_sub(start), depth)? {
+                self.release_data_blocks(&[root]);
+                inode.set_indirect_root(depth, 0);
+            }
+            start += pointers_per_block.pow(depth);
+        }
+
+        let released = self.superblock().free_blocks - free_blocks;
+        inode.block_count = inode.block_count.saturating_sub(released);
+
+        Ok(())
     }
 
-    #[test]
-    fn new_fs() -> anyhow::Result<()> {
-        let tmp_file = make_fs("new_fs")?;
-        let fs = GotenksFS::new(&tmp_file)?;
-        let inode = fs.find_inode(ROOT_INODE)?;
+    // Releases what the indirect `block` maps from its `from`th data block
+    // on. `depth` is the number of indirect levels below and including
+    // `block`. Returns whether `block` itself is no longer needed.
+    fn truncate_indirect(&mut self, block: u32, from: u64, depth: u32) -> anyhow::Result<bool> {
+        let pointers_per_block = self.superblock().block_size as u64 / 4;
+        let span = pointers_per_block.pow(depth - 1);
 
-        assert_eq!(inode.mode, SFlag::S_IFDIR.bits() | 0o777);
-        assert_eq!(inode.hard_links, 2);
+        for i in 0..pointers_per_block {
+            let start = i * span;
+            if start + span <= from {
+                continue;
+            }
 
-        assert!(fs.groups().get(0).unwrap().has_inode(ROOT_INODE as _));
-        assert!(fs.groups().get(0).unwrap().has_data_block(ROOT_INODE as _));
+            let pointer = self.read_u32(i, block)?;
+            if pointer == 0 {
+                continue;
+            }
 
-        assert_eq!(fs.superblock().groups, fs.groups().len() as u32);
-        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+            // Only part of what this pointer maps is past `from`.
+            if depth > 1
+                && !self.truncate_indirect(pointer, from.saturating_sub(start), depth - 1)?
+            {
+                continue;
+            }
 
-        Ok(std::fs::remove_file(&tmp_file)?)
+            self.release_data_blocks(&[pointer]);
+            // A block released as a whole keeps its stale pointers.
+            if from > 0 {
+                self.write_metadata(&0u32.to_le_bytes(), i * 4, block)?;
+            }
+        }
+
+        Ok(from == 0)
     }
 
-    #[test]
-    fn init_destroy() -> anyhow::Result<()> {
-        let tmp_file = make_fs("init_destroy")?;
-        let mut fs = GotenksFS::new(&tmp_file)?;
+    // Releases what the subtree under `node` maps from the logical block
+    // `from` on, along with the nodes left without entries.
+    fn truncate_extents(&mut self, node: &mut ExtentNode, from: u64) -> anyhow::Result<()> {
+        if node.depth == 0 {
+            for extent in node.extents.iter_mut() {
+                let keep = from
+                    .saturating_sub(extent.logical as u64)
+                    .min(extent.len as u64) as u32;
+                let blocks: Vec<u32> =
+                    (extent.physical + keep..extent.physical + extent.len).collect();
+                self.release_data_blocks(&blocks);
+                extent.len = keep;
+            }
+            node.extents.retain(|e| e.len > 0);
+            return Ok(());
+        }
 
-        assert_eq!(fs.superblock().last_mounted_at, None);
+        for i in 0..node.extents.len() {
+            // Everything under this child comes before `from`.
+            if let Some(next) = node.extents.get(i + 1) {
+                if next.logical as u64 <= from {
+                    continue;
+                }
+            }
 
-        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
-        fs.destroy()?;
+            let block = node.extents[i].physical;
+            let mut child = self.read_extent_node(block)?;
+            self.truncate_extents(&mut child, from)?;
+            if child.extents.is_empty() {
+                self.release_data_blocks(&[block]);
+                node.extents[i].physical = 0;
+            } else {
+                self.write_extent_node(block, &child)?;
+            }
+        }
+        node.extents.retain(|e| e.physical != 0);
+
+        Ok(())
+    }
+
+    // Every block the inode uses, holding data or pointers to other blocks.
+    pub(crate) fn inode_blocks(&self, inode: &Inode) -> anyhow::Result<Vec<u32>> {
+        let mut blocks = vec![];
+        if inode.is_fast_symlink() {
+            return Ok(blocks);
+        }
+
+        if inode.uses_extents() {
+            self.collect_extent_blocks(&inode.extent_root(), &mut blocks)?;
+        } else {
+            blocks.extend(inode.direct_blocks.iter().filter(|b| **b != 0));
+            for depth in 1..=3 {
+                let root = inode.indirect_root(depth);
+                if root != 0 {
+                    self.collect_indirect_blocks(root, depth, &mut blocks)?;
+                }
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    fn collect_indirect_blocks(
+        &self,
+        block: u32,
+        depth: u32,
+        blocks: &mut Vec<u32>,
+    ) -> anyhow::Result<()> {
+        self.check_block(block)?;
+        blocks.push(block);
+        for i in 0..self.superblock().block_size as u64 / 4 {
+            let pointer = self.read_u32(i, block)?;
+            if pointer == 0 {
+                continue;
+            }
+
+            if depth > 1 {
+                self.collect_indirect_blocks(pointer, depth - 1, blocks)?;
+            } else {
+                self.check_block(pointer)?;
+                blocks.push(pointer);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn collect_extent_blocks(
+        &self,
+        node: &ExtentNode,
+        blocks: &mut Vec<u32>,
+    ) -> anyhow::Result<()> {
+        for extent in &node.extents {
+            if node.depth == 0 {
+                let last = extent.physical as u64 + extent.len as u64;
+                if extent.len != 0 && last - 1 > self.superblock().block_count as u64 {
+                    return Err(anyhow!(
+                        "Extent at block {} is out of range",
+                        extent.physical
+                    ));
+                }
+                self.check_block(extent.physical)?;
+                blocks.extend(extent.physical..last as u32);
+            } else {
+                self.check_block(extent.physical)?;
+                blocks.push(extent.physical);
+                let child = self.read_extent_node(extent.physical)?;
+                self.collect_extent_blocks(&child, blocks)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn check_block(&self, block: u32) -> anyhow::Result<()> {
+        if block == 0 || block > self.superblock().block_count {
+            return Err(anyhow!("Block {} is out of range", block));
+        }
+        Ok(())
+    }
+
+    // Drops one of the names pointing at the inode. Its blocks are only
+    // released once no other directory entry refers to it.
+    fn remove_link(&mut self, mut inode: Inode, index: u32) -> anyhow::Result<()> {
+        if inode.is_dir() || inode.hard_links <= 1 {
+            return self.release_file(inode, index);
+        }
+
+        inode.hard_links -= 1;
+        inode.update_changed_at();
+        self.save_inode(inode, index)
+    }
+
+    fn adjust_hard_links(&mut self, index: u32, delta: i16) -> anyhow::Result<()> {
+        let mut inode = self.find_inode(index)?;
+        inode.hard_links = (inode.hard_links as i16 + delta) as u16;
+        inode.update_changed_at();
+        self.save_inode(inode, index)
+    }
+
+    #[inline]
+    fn write_data(&mut self, data: &[u8], offset: u64, block_index: u32) -> anyhow::Result<usize> {
+        let position = self.data_block_seek_position(block_index) + offset;
+        self.journal_mut().log_data(position, data);
+        if self.superblock().data_checksums() {
+            self.journal_mut().mark_block(block_index);
+        }
+
+        Ok(data.len())
+    }
+
+    // Blocks holding directories or pointers to other blocks go through the
+    // journal, unlike file contents.
+    #[inline]
+    fn write_metadata(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        block_index: u32,
+    ) -> anyhow::Result<usize> {
+        let position = self.data_block_seek_position(block_index) + offset;
+        self.log(position, data);
+        if self.superblock().data_checksums() {
+            self.journal_mut().mark_block(block_index);
+        }
+
+        Ok(data.len())
+    }
+
+    #[inline]
+    fn data_checksums_offset(&self) -> Option<u64> {
+        let sb = self.superblock();
+        if !sb.data_checksums() {
+            return None;
+        }
+
+        Some(sb.checksums_offset())
+    }
+
+    #[inline]
+    pub(crate) fn read_data(
+        &self,
+        data: &mut [u8],
+        offset: u64,
+        block_index: u32,
+    ) -> anyhow::Result<usize> {
+        let block_offset = self.data_block_seek_position(block_index);
+        self.read_at(data, block_offset + offset)?;
+
+        // The checksum of a block written since the last commit is only
+        // computed by the next one.
+        let checksums_offset = self
+            .data_checksums_offset()
+            .filter(|_| !self.journal().is_dirty_block(block_index));
+        if let Some(checksums_offset) = checksums_offset {
+            let mut block = vec![0u8; self.superblock().block_size as usize];
+            self.read_at(&mut block, block_offset)?;
+            let mut checksum = [0u8; 4];
+            let position = checksums_offset + (block_index as u64 - 1) * 4;
+            self.read_at(&mut checksum, position)?;
+            if checksum != util::block_checksum(&block).to_le_bytes() {
+                return Err(anyhow!(
+                    "Data block {} checksum verification failed",
+                    block_index
+                ));
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    #[inline]
+    pub(crate) fn read_u32(&self, offset: u64, block_index: u32) -> anyhow::Result<u32> {
+        let mut data = [0u8; 4];
+        self.read_data(&mut data, offset * 4, block_index)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    #[inline]
+    pub(crate) fn groups(&self) -> &[Group] {
+        self.groups.as_ref().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn groups_mut(&mut self) -> &mut [Group] {
+        self.groups.as_mut().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn superblock(&self) -> &Superblock {
+        self.sb.as_ref().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn superblock_mut(&mut self) -> &mut Superblock {
+        self.sb.as_mut().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn device(&self) -> &dyn BlockDevice {
+        self.device.as_deref().unwrap()
+    }
+
+    #[inline]
+    pub(crate) fn device_mut(&mut self) -> &mut dyn BlockDevice {
+        self.device.as_deref_mut().unwrap()
+    }
+
+    // Until unmounted.
+    #[inline]
+    pub(crate) fn is_open(&self) -> bool {
+        self.device.is_some()
+    }
+}
+
+impl fuse_rs::Filesystem for GotenksFS {
+    fn metadata(&self, path: &Path) -> fuse_rs::Result<FileStat> {
+        let (inode, index) = self.find_inode_from_path(path)?;
+        Ok(inode.to_stat(index, self.superblock().block_size))
+    }
+
+    fn read_dir(
+        &mut self,
+        path: &Path,
+        _offset: u64,
+        _file_info: fuse_rs::fs::FileInfo,
+    ) -> fuse_rs::Result<Vec<fuse_rs::fs::DirEntry>> {
+        // TODO: check permissions
+        let dir = self.find_dir_from_inode(self.find_dir(path)?)?;
+
+        let mut entries = Vec::with_capacity(dir.entries.len());
+        for (name, index) in dir.entries {
+            let inode = self.find_inode(index)?;
+            let stat = inode.to_stat(index, self.superblock().block_size);
+            entries.push(fuse_rs::fs::DirEntry {
+                name,
+                metadata: Some(stat),
+                offset: None,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn create(
+        &mut self,
+        path: &Path,
+        permissions: Mode,
+        file_info: &mut fuse_rs::fs::OpenFileInfo,
+    ) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let name = path.file_name().ok_or(Errno::EINVAL)?;
+            let parent_index = fs.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
+            if fs.find_dir_entry(parent_index, name).is_ok() {
+                return Err(Errno::EEXIST);
+            }
+
+            let index = fs.allocate_inode().ok_or_else(|| Errno::ENOSPC)?;
+            let mut inode = Inode::new();
+            inode.mode = permissions.bits();
+            inode.flags = fs.superblock().inode_flags;
+            inode.user_id = fs.superblock().uid;
+            inode.group_id = fs.superblock().gid;
+
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)?;
+            fs.add_dir_entry(parent_index, name, index)?;
+
+            file_info.set_handle(index as u64);
+            Ok(())
+        })
+    }
+
+    fn statfs(&self, path: &Path) -> fuse_rs::Result<libc::statvfs> {
+        if path == Path::new("/") {
+            let sb = self.superblock();
+            let stat = libc::statvfs {
+                f_bsize: sb.block_size as u64,
+                f_frsize: sb.block_size as u64,
+                f_blocks: sb.block_count,
+                f_bfree: sb.free_blocks,
+                f_bavail: sb.free_blocks,
+                f_files: sb.inode_count,
+                f_ffree: sb.free_inodes,
+                f_favail: sb.free_inodes,
+                f_namemax: 255,
+                f_fsid: 0, // ignored by fuse
+                f_flag: 0, // ignored by fuse
+            };
+
+            Ok(stat)
+        } else {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    fn open(
+        &mut self,
+        path: &Path,
+        file_info: &mut fuse_rs::fs::OpenFileInfo,
+    ) -> fuse_rs::Result<()> {
+        // TODO: check permissions
+        let (mut inode, index) = self.find_inode_from_path(path)?;
+        inode.update_accessed_at();
+
+        self.save_inode(inode, index).map_err(|_| Errno::EIO)?;
+        file_info.set_handle(index as u64);
+
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _path: &Path,
+        buf: &[u8],
+        offset: u64,
+        file_info: &mut fuse_rs::fs::WriteFileInfo,
+    ) -> fuse_rs::Result<usize> {
+        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
+        self.write_file(index, buf, offset)
+    }
+
+    fn read(
+        &mut self,
+        _path: &Path,
+        buf: &mut [u8],
+        offset: u64,
+        file_info: fuse_rs::fs::FileInfo,
+    ) -> fuse_rs::Result<usize> {
+        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
+        self.read_file(index, buf, offset)
+    }
+
+    fn ftruncate(
+        &mut self,
+        _path: &Path,
+        len: u64,
+        file_info: fuse_rs::fs::FileInfo,
+    ) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
+            if index == 0 {
+                return Err(Errno::EINVAL);
+            }
+            fs.truncate_file(index, len)
+        })
+    }
+
+    fn set_len(&mut self, path: &Path, len: u64) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let (_, index) = fs.find_inode_from_path(path)?;
+            fs.truncate_file(index, len)
+        })
+    }
+
+    fn fmetadata(
+        &self,
+        _path: &Path,
+        file_info: fuse_rs::fs::FileInfo,
+    ) -> fuse_rs::Result<FileStat> {
+        let index = file_info.handle().ok_or(Errno::EINVAL)? as u32;
+        if index == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let inode = self.find_inode(index)?;
+        Ok(inode.to_stat(index, self.superblock().block_size))
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let (mut inode, index) = fs.find_inode_from_path(path)?;
+            inode.mode |= mode.bits();
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn remove_file(&mut self, path: &Path) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let parent_index = fs.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
+            let index =
+                fs.remove_dir_entry(parent_index, path.file_name().ok_or(Errno::EINVAL)?)?;
+            let inode = fs.find_inode(index)?;
+            fs.remove_link(inode, index).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn create_dir(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let name = path.file_name().ok_or(Errno::EINVAL)?;
+            let parent_index = fs.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
+            if fs.find_dir_entry(parent_index, name).is_ok() {
+                return Err(Errno::EEXIST);
+            }
+            let index = fs.allocate_inode().ok_or_else(|| Errno::ENOSPC)?;
+
+            let mut inode = Inode::new();
+            inode.mode = SFlag::S_IFDIR.bits() | mode.bits();
+            inode.flags = fs.superblock().inode_flags;
+            inode.hard_links = 2;
+            inode.user_id = fs.superblock().uid;
+            inode.group_id = fs.superblock().gid;
+
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)?;
+            fs.save_dir(Directory::default(), index)?;
+            fs.add_dir_entry(parent_index, name, index)?;
+            fs.adjust_hard_links(parent_index, 1)
+                .map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let name = path.file_name().ok_or(Errno::EINVAL)?;
+            let parent_index = fs.find_dir(path.parent().ok_or(Errno::EINVAL)?)?;
+            let index = fs.find_dir_entry(parent_index, name)?;
+            if !fs.is_dir_empty(index)? {
+                return Err(Errno::ENOTEMPTY);
+            }
+
+            let inode = fs.find_inode(index)?;
+            fs.remove_dir_entry(parent_index, name)?;
+            fs.release_file(inode, index).map_err(|_| Errno::EIO)?;
+            fs.adjust_hard_links(parent_index, -1)
+                .map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            if from == to {
+                return Ok(());
+            }
+
+            let from_name = from.file_name().ok_or(Errno::EINVAL)?;
+            let to_name = to.file_name().ok_or(Errno::EINVAL)?;
+            let from_parent_index = fs.find_dir(from.parent().ok_or(Errno::EINVAL)?)?;
+            let index = fs.find_dir_entry(from_parent_index, from_name)?;
+            let mut inode = fs.find_inode(index)?;
+
+            // A directory cannot become a descendant of itself.
+            if inode.is_dir() && to.starts_with(from) {
+                return Err(Errno::EINVAL);
+            }
+
+            let to_parent_index = fs.find_dir(to.parent().ok_or(Errno::EINVAL)?)?;
+            let replaced = match fs.find_dir_entry(to_parent_index, to_name) {
+                Ok(target_index) if target_index == index => return Ok(()),
+                Ok(target_index) => {
+                    let target = fs.find_inode(target_index)?;
+                    match (inode.is_dir(), target.is_dir()) {
+                        (true, false) => return Err(Errno::ENOTDIR),
+                        (false, true) => return Err(Errno::EISDIR),
+                        (true, true) => {
+                            if !fs.is_dir_empty(target_index)? {
+                                return Err(Errno::ENOTEMPTY);
+                            }
+                        }
+                        (false, false) => {}
+                    }
+                    Some((target, target_index))
+                }
+                Err(_) => None,
+            };
+
+            fs.add_dir_entry(to_parent_index, to_name, index)?;
+            fs.remove_dir_entry(from_parent_index, from_name)?;
+
+            // Directories don't store a `..` entry, the link a subdirectory holds
+            // on its parent only shows up in the parent's `hard_links`.
+            let replaced_dir = match replaced {
+                Some((target, target_index)) => {
+                    let is_dir = target.is_dir();
+                    fs.remove_link(target, target_index)
+                        .map_err(|_| Errno::EIO)?;
+                    is_dir
+                }
+                None => false,
+            };
+            if inode.is_dir() && from_parent_index != to_parent_index {
+                fs.adjust_hard_links(from_parent_index, -1)
+                    .map_err(|_| Errno::EIO)?;
+                if !replaced_dir {
+                    fs.adjust_hard_links(to_parent_index, 1)
+                        .map_err(|_| Errno::EIO)?;
+                }
+            } else if replaced_dir {
+                fs.adjust_hard_links(to_parent_index, -1)
+                    .map_err(|_| Errno::EIO)?;
+            }
+
+            inode.update_changed_at();
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn symlink(&mut self, src: &Path, dst: &Path) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let target = src.as_os_str().as_bytes();
+            if target.len() > fs.superblock().block_size as usize {
+                return Err(Errno::ENAMETOOLONG);
+            }
+
+            let name = dst.file_name().ok_or(Errno::EINVAL)?;
+            let parent_index = fs.find_dir(dst.parent().ok_or(Errno::EINVAL)?)?;
+            if fs.find_dir_entry(parent_index, name).is_ok() {
+                return Err(Errno::EEXIST);
+            }
+
+            let index = fs.allocate_inode().ok_or(Errno::ENOSPC)?;
+            let mut inode = Inode::new();
+            inode.mode = SFlag::S_IFLNK.bits() | 0o777;
+            inode.user_id = fs.superblock().uid;
+            inode.group_id = fs.superblock().gid;
+
+            if target.len() as u64 <= INLINE_DATA_SIZE {
+                inode.set_inline_data(target);
+            } else {
+                fs.write_inode_data(&mut inode, target, 0)?;
+                inode.increment_size(target.len() as u64);
+            }
+
+            fs.save_inode(inode, index).map_err(|_| Errno::EIO)?;
+            fs.add_dir_entry(parent_index, name, index)
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> fuse_rs::Result<&OsStr> {
+        let (index, target) = self.link_target(path)?;
+        let mut targets = self
+            .link_targets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // The box doesn't move when the map grows, and stays there until
+        // `&mut self` is borrowed again.
+        let ptr: *const [u8] = &**targets.entry(index).or_insert(target);
+        Ok(OsStr::from_bytes(unsafe { &*ptr }))
+    }
+
+    fn hard_link(&mut self, src: &Path, dst: &Path) -> fuse_rs::Result<()> {
+        self.transaction(|fs| {
+            let (inode, index) = fs.find_inode_from_path(src)?;
+            if inode.is_dir() {
+                return Err(Errno::EPERM);
+            }
+            if inode.hard_links == u16::MAX {
+                return Err(Errno::EMLINK);
+            }
+
+            let name = dst.file_name().ok_or(Errno::EINVAL)?;
+            let parent_index = fs.find_dir(dst.parent().ok_or(Errno::EINVAL)?)?;
+            if fs.find_dir_entry(parent_index, name).is_ok() {
+                return Err(Errno::EEXIST);
+            }
+
+            fs.add_dir_entry(parent_index, name, index)?;
+            fs.adjust_hard_links(index, 1).map_err(|_| Errno::EIO)
+        })
+    }
+
+    fn init(&mut self, _connection_info: &mut fuse_rs::fs::ConnectionInfo) -> fuse_rs::Result<()> {
+        let sb = self.superblock_mut();
+        sb.update_last_mounted_at();
+        sb.update_modified_at();
+        sb.state |= STATE_DIRTY;
+        sb.mount_count = sb.mount_count.saturating_add(1);
+
+        // Nothing else may write the superblock until the first update.
+        let data = sb.serialize().map_err(|_| Errno::EIO)?;
+        self.log(0, &data);
+        self.commit().map_err(|_| Errno::EIO)
+    }
+
+    fn destroy(&mut self) -> fuse_rs::Result<()> {
+        let sb = self.superblock_mut();
+        sb.state &= !STATE_DIRTY;
+        let data = sb.serialize().map_err(|_| Errno::EIO)?;
+        self.log(0, &data);
+        self.commit().map_err(|_| Errno::EIO)?;
+        self.device = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fsck,
+        gotenks::{
+            journal::Transaction, types::Superblock, util, INODE_SIZE, REVISION, ROOT_INODE,
+        },
+        mkfs,
+    };
+    use fuse_rs::{fs::FileStat, Filesystem};
+    use std::{ffi::OsString, path::PathBuf};
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn inode_offsets() {
+        let mut fs = GotenksFS::default();
+        fs.sb = Some(Superblock::new(1024, 3, 0, 0));
+        fs.superblock_mut().data_blocks_per_group = 1024 * 8;
+
+        let (group_index, offset) = fs.inode_offsets(1);
+        assert_eq!(group_index, 0);
+        assert_eq!(offset, 0);
+
+        let (group_index, offset) = fs.inode_offsets(1024 * 8);
+        assert_eq!(group_index, 0);
+        assert_eq!(offset, 8191);
+
+        let (group_index, offset) = fs.inode_offsets(1024 * 8 - 1);
+        assert_eq!(group_index, 0);
+        assert_eq!(offset, 8190);
+
+        let (group_index, offset) = fs.inode_offsets(2 * 1024 * 8 - 1);
+        assert_eq!(group_index, 1);
+        assert_eq!(offset, 8190);
+    }
+
+    #[test]
+    fn inode_seek_position() {
+        let mut fs = GotenksFS::default();
+        fs.sb = Some(Superblock::new(1024, 3, 0, 0));
+        fs.superblock_mut().data_blocks_per_group = 1024 * 8;
+
+        let offset = fs.inode_seek_position(1);
+        assert_eq!(3072, offset);
+
+        let offset = fs.inode_seek_position(2);
+        assert_eq!(3072 + INODE_SIZE, offset);
+
+        let offset = fs.inode_seek_position(8192);
+        assert_eq!(3072 + 8191 * INODE_SIZE, offset); // superblock + data bitmap + inode bitmap + 8191 inodes
+
+        let offset = fs.inode_seek_position(8193);
+        assert_eq!(3072 + 8192 * INODE_SIZE + 1024 * 1024 * 8 + 2048, offset); // superblock + data bitmap + inode bitmap + inode table + data blocks + data bitmap + inode bitmap
+    }
+
+    #[test]
+    fn new_fs() -> anyhow::Result<()> {
+        let tmp_file = make_fs("new_fs")?;
+        let fs = GotenksFS::new(&tmp_file)?;
+        let inode = fs.find_inode(ROOT_INODE)?;
+
+        assert_eq!(inode.mode, SFlag::S_IFDIR.bits() | 0o777);
+        assert_eq!(inode.hard_links, 2);
+
+        assert!(fs.groups().get(0).unwrap().has_inode(ROOT_INODE as _));
+        assert!(fs.groups().get(0).unwrap().has_data_block(ROOT_INODE as _));
+
+        assert_eq!(fs.superblock().groups, fs.groups().len() as u32);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn init_destroy() -> anyhow::Result<()> {
+        let tmp_file = make_fs("init_destroy")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        assert_eq!(fs.superblock().last_mounted_at, None);
+
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
+        drop(fs);
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().state, STATE_DIRTY);
+        assert_eq!(fs.superblock().mount_count, 1);
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
+        fs.destroy()?;
+
+        let fs = GotenksFS::new(&tmp_file)?;
+
+        assert_eq!(fs.superblock().state, 0);
+        assert_eq!(fs.superblock().mount_count, 2);
+        assert_ne!(fs.superblock().last_mounted_at, None);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn metadata() -> anyhow::Result<()> {
+        let tmp_file = make_fs("metadata")?;
+        let fs = GotenksFS::new(&tmp_file)?;
+        let inode = fs.metadata(Path::new("/"))?;
+
+        assert_eq!(inode.st_ino, ROOT_INODE as u64);
+        assert_eq!(inode.st_mode, SFlag::S_IFDIR.bits() | 0o777);
+        assert_eq!(inode.st_nlink, 2);
+        assert_ne!(inode.st_mtime, 0);
+        assert_ne!(inode.st_ctime, 0);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn data_block_seek_position() {
+        let mut fs = GotenksFS::default();
+        let block_size = 1024;
+        fs.sb = Some(Superblock::new(block_size, 3, 0, 0));
+        fs.superblock_mut().data_blocks_per_group = block_size as u32 * 8;
+
+        let prefix = SUPERBLOCK_SIZE + 2 * block_size as u64 + block_size as u64 * INODE_SIZE * 8;
+        let offset = fs.data_block_seek_position(1);
+        assert_eq!(prefix, offset);
+
+        let offset = fs.data_block_seek_position(2);
+        assert_eq!(prefix + block_size as u64, offset);
+
+        let offset = fs.data_block_seek_position(8192);
+        assert_eq!(prefix + 8191 * block_size as u64, offset);
+
+        let offset = fs.data_block_seek_position(8193);
+        assert_eq!(
+            2 * prefix - SUPERBLOCK_SIZE + (block_size * block_size) as u64 * 8,
+            offset
+        );
+    }
+
+    #[test]
+    fn save_dir() -> anyhow::Result<()> {
+        let tmp_file = make_fs("save_dir")?;
+        let fs = GotenksFS::new(&tmp_file)?;
+        let dir = fs.find_dir_from_inode(ROOT_INODE)?;
+
+        assert_eq!(dir.entries.len(), 0);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn find_dir() -> anyhow::Result<()> {
+        let tmp_file = make_fs("find_dir")?;
+        let fs = GotenksFS::new(&tmp_file)?;
+
+        assert_eq!(fs.find_dir("/not-a-dir").err(), Some(Errno::ENOENT));
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn read_dir() -> anyhow::Result<()> {
+        let tmp_file = make_fs("read_dir")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let inode = fs.find_inode(ROOT_INODE)?;
+
+        assert_ne!(inode.accessed_at, None);
+
+        let file_info = fuse_rs::fs::FileInfo::default();
+        let entries = fs.read_dir(Path::new("/"), 0, file_info)?;
+        assert_eq!(entries.len(), 0);
+
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXO,
+            &mut fuse_rs::fs::OpenFileInfo::default(),
+        )?;
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut fuse_rs::fs::OpenFileInfo::default(),
+        )?;
+
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 3);
+
+        let file_info = fuse_rs::fs::FileInfo::default();
+        let entries = fs.read_dir(Path::new("/"), 0, file_info)?;
+        assert_eq!(entries.len(), 2);
+
+        let bar = entries.first().unwrap();
+        let mut stat = FileStat::default();
+        let mode = nix::sys::stat::Mode::S_IRWXU.bits();
+        stat.st_mode = mode;
+        stat.st_ino = 3;
+        assert_eq!(bar.name, OsString::from("bar.txt"));
+        assert_eq!(bar.metadata.as_ref().unwrap().st_ino, 3);
+        assert_eq!(bar.metadata.as_ref().unwrap().st_mode, mode);
+
+        let foo = entries.last().unwrap();
+        let mut stat = FileStat::default();
+        let mode = nix::sys::stat::Mode::S_IRWXO.bits();
+        stat.st_mode = mode;
+        stat.st_ino = 2;
+        assert_eq!(foo.name, OsString::from("foo.txt"));
+        assert_eq!(foo.metadata.as_ref().unwrap().st_ino, 2);
+        assert_eq!(foo.metadata.as_ref().unwrap().st_mode, mode);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn open() -> anyhow::Result<()> {
+        let tmp_file = make_fs("open")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut file_info = fuse_rs::fs::OpenFileInfo::default();
+        assert_eq!(
+            fs.open(Path::new("/hello.txt"), &mut file_info).err(),
+            Some(Errno::ENOENT)
+        );
+
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut file_info,
+        )?;
+
+        fs.open(Path::new("/bar.txt"), &mut file_info)?;
+
+        assert_eq!(file_info.handle(), Some(2));
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn write() -> anyhow::Result<()> {
+        let tmp_file = make_fs("write")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        let handle = open_fi.handle().unwrap();
+
+        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = std::iter::repeat(3).take(125).collect::<Vec<u8>>();
+
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, 125);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 125);
+        assert_eq!(stat.st_blocks, 1); // a single block
+
+        assert_eq!(read(&mut fs, 125, 0, handle)?, buf);
+
+        // Overwriting with larger buffer
+        let buf = std::iter::repeat(4).take(126).collect::<Vec<u8>>();
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, 126);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 126);
+        assert_eq!(stat.st_blocks, 1); // a single block
+
+        assert_eq!(read(&mut fs, 126, 0, handle)?, buf);
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+
+        let modified_at = inode.modified_at;
+        let changed_at = inode.changed_at;
+
+        // Overwriting with shorter buffer
+        let buf = std::iter::repeat(5).take(120).collect::<Vec<u8>>();
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, 120);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 126);
+        assert_eq!(stat.st_blocks, 1); // a single block
+
+        assert_eq!(read(&mut fs, 120, 0, handle)?, buf);
+        assert_eq!(
+            read(&mut fs, 6, 120, handle)?,
+            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+        );
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+
+        // Appending
+        let buf = std::iter::repeat(7).take(125).collect::<Vec<u8>>();
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 126, &mut write_file_info)?;
+        assert_eq!(wrote, 125);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 251);
+        assert_eq!(stat.st_blocks, 1); // two blocks of 128 bytes, in 512 bytes units
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+        assert_eq!(inode.direct_blocks[1], 3);
+
+        assert_eq!(
+            read(&mut fs, 120, 0, handle)?,
+            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            read(&mut fs, 6, 120, handle)?,
+            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+        );
+        assert_eq!(read(&mut fs, 125, 126, handle)?, buf);
+
+        // Appending again
+        let buf = std::iter::repeat(8).take(125).collect::<Vec<u8>>();
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 251, &mut write_file_info)?;
+        assert_eq!(wrote, 125);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 376);
+        assert_eq!(stat.st_blocks, 1);
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.block_count, 3);
+        assert_eq!(inode.direct_blocks[0], 2);
+        assert_eq!(inode.direct_blocks[1], 3);
+        assert_eq!(inode.direct_blocks[2], 4);
+
+        assert_eq!(
+            read(&mut fs, 120, 0, handle)?,
+            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            read(&mut fs, 6, 120, handle)?,
+            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            read(&mut fs, 125, 126, handle)?,
+            std::iter::repeat(7).take(125).collect::<Vec<u8>>()
+        );
+        assert_eq!(read(&mut fs, 125, 251, handle)?, buf);
+
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        // Overwriting in the middle
+        let buf = std::iter::repeat(9).take(125).collect::<Vec<u8>>();
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 126, &mut write_file_info)?;
+        assert_eq!(wrote, 125);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, 376);
+        assert_eq!(stat.st_blocks, 1);
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+        assert_eq!(inode.direct_blocks[1], 3);
+        assert_eq!(inode.direct_blocks[2], 4);
+
+        assert_ne!(inode.modified_at, modified_at);
+        assert_ne!(inode.changed_at, changed_at);
+
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
+
+        assert_eq!(
+            read(&mut fs, 120, 0, handle)?,
+            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            read(&mut fs, 6, 120, handle)?,
+            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+        );
+        assert_eq!(read(&mut fs, 125, 126, handle)?, buf);
+        assert_eq!(
+            read(&mut fs, 125, 251, handle)?,
+            std::iter::repeat(8).take(125).collect::<Vec<u8>>()
+        );
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn append_only() -> anyhow::Result<()> {
+        let tmp_file = make_fs("append_only")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+
+        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = std::iter::repeat(3)
+            .take(2 * BLOCK_SIZE as usize)
+            .collect::<Vec<u8>>();
+
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, buf.len());
+        assert_eq!(read(&mut fs, 2 * BLOCK_SIZE as usize, 0, handle)?, buf);
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, buf.len() as _);
+        assert_eq!(stat.st_blocks, 1);
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+        assert_eq!(inode.direct_blocks[1], 3);
+
+        let buf = std::iter::repeat(4)
+            .take(BLOCK_SIZE as _)
+            .collect::<Vec<u8>>();
+
+        let wrote = fs.write(
+            Path::new("/ignored.txt"),
+            &buf,
+            2 * BLOCK_SIZE as u64,
+            &mut write_file_info,
+        )?;
+        assert_eq!(wrote, BLOCK_SIZE as _);
+        assert_eq!(
+            read(&mut fs, BLOCK_SIZE as usize, 2 * BLOCK_SIZE as u64, handle)?,
+            buf
+        );
+
+        let stat = fs.metadata(Path::new("/bar.txt"))?;
+        assert_eq!(stat.st_size, BLOCK_SIZE as i64 * 3);
+        assert_eq!(stat.st_blocks, 1);
+
+        let inode = fs.find_inode(2)?;
+        assert_eq!(inode.direct_blocks[0], 2);
+        assert_eq!(inode.direct_blocks[1], 3);
+        assert_eq!(inode.direct_blocks[2], 4);
+
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn remove_file() -> anyhow::Result<()> {
+        let tmp_file = make_fs("remove_file")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+
+        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = std::iter::repeat(3)
+            .take(2 * BLOCK_SIZE as usize)
+            .collect::<Vec<u8>>();
+
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, buf.len());
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
+
+        let (inode, index) = fs.find_inode_from_path(Path::new("/bar.txt"))?;
+        assert_eq!(&inode.direct_blocks[..3], &[2, 3, 0]);
+        assert_eq!(index, 2);
+
+        fs.remove_file(Path::new("/bar.txt"))?;
+
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+        assert_eq!(
+            Errno::ENOENT,
+            fs.metadata(Path::new("/bar.txt")).unwrap_err()
+        );
+
+        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 0);
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/baz.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+
+        fs.open(Path::new("/baz.txt"), &mut open_fi)?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = std::iter::repeat(3)
+            .take(2 * BLOCK_SIZE as usize)
+            .collect::<Vec<u8>>();
+
+        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(wrote, buf.len());
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
+
+        // Check that it reuses previously freed blocks
+        let (inode, index) = fs.find_inode_from_path(Path::new("/baz.txt"))?;
+        assert_eq!(&inode.direct_blocks[..3], &[2, 3, 0]);
+        assert_eq!(index, 2);
+
+        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 1);
+
+        let bar = entries.first().unwrap();
+        assert_eq!(bar.name, OsString::from("baz.txt"));
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn rename() -> anyhow::Result<()> {
+        let tmp_file = make_fs("rename")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        fs.create_dir(Path::new("/dir"), nix::sys::stat::Mode::S_IRWXU)?;
+        fs.create_dir(Path::new("/dir/sub"), nix::sys::stat::Mode::S_IRWXU)?;
+        assert_eq!(fs.metadata(Path::new("/"))?.st_nlink, 3);
+        assert_eq!(fs.metadata(Path::new("/dir"))?.st_nlink, 3);
 
-        let fs = GotenksFS::new(&tmp_file)?;
+        // Within the same directory
+        fs.rename(Path::new("/foo.txt"), Path::new("/baz.txt"))?;
+        assert_eq!(
+            Errno::ENOENT,
+            fs.metadata(Path::new("/foo.txt")).unwrap_err()
+        );
+        assert_eq!(fs.metadata(Path::new("/baz.txt"))?.st_ino, 2);
 
-        assert_ne!(fs.superblock().last_mounted_at, None);
-        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+        // Replacing an existing file releases its inode
+        let free_inodes = fs.superblock().free_inodes;
+        fs.rename(Path::new("/baz.txt"), Path::new("/bar.txt"))?;
+        assert_eq!(fs.metadata(Path::new("/bar.txt"))?.st_ino, 2);
+        assert_eq!(fs.superblock().free_inodes, free_inodes + 1);
+        assert!(!fs.groups().first().unwrap().has_inode(3));
 
-        Ok(std::fs::remove_file(&tmp_file)?)
-    }
+        // Across directories
+        fs.rename(Path::new("/bar.txt"), Path::new("/dir/bar.txt"))?;
+        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs.metadata(Path::new("/dir/bar.txt"))?.st_ino, 2);
 
-    #[test]
-    fn metadata() -> anyhow::Result<()> {
-        let tmp_file = make_fs("metadata")?;
-        let fs = GotenksFS::new(&tmp_file)?;
-        let inode = fs.metadata(Path::new("/"))?;
+        // Moving a directory updates the link count of both parents
+        fs.rename(Path::new("/dir/sub"), Path::new("/sub"))?;
+        assert_eq!(fs.metadata(Path::new("/"))?.st_nlink, 4);
+        assert_eq!(fs.metadata(Path::new("/dir"))?.st_nlink, 2);
+        fs.rename(Path::new("/sub"), Path::new("/dir/sub"))?;
+        assert_eq!(fs.metadata(Path::new("/"))?.st_nlink, 3);
+        assert_eq!(fs.metadata(Path::new("/dir"))?.st_nlink, 3);
 
-        assert_eq!(inode.st_ino, ROOT_INODE as u64);
-        assert_eq!(inode.st_mode, SFlag::S_IFDIR.bits() | 0o777);
-        assert_eq!(inode.st_nlink, 2);
-        assert_ne!(inode.st_mtime, 0);
-        assert_ne!(inode.st_ctime, 0);
+        assert_eq!(
+            fs.rename(Path::new("/dir"), Path::new("/dir/sub/dir")),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            fs.rename(Path::new("/dir/bar.txt"), Path::new("/dir/sub")),
+            Err(Errno::EISDIR)
+        );
+        assert_eq!(
+            fs.rename(Path::new("/dir/sub"), Path::new("/dir/bar.txt")),
+            Err(Errno::ENOTDIR)
+        );
+        assert_eq!(
+            fs.rename(Path::new("/missing"), Path::new("/dir/missing")),
+            Err(Errno::ENOENT)
+        );
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn data_block_seek_position() {
-        let mut fs = GotenksFS::default();
-        let block_size = 1024;
-        fs.sb = Some(Superblock::new(block_size, 3, 0, 0));
-        fs.superblock_mut().data_blocks_per_group = block_size as u32 * 8;
+    fn hard_link() -> anyhow::Result<()> {
+        let tmp_file = make_fs("hard_link")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
 
-        let prefix = SUPERBLOCK_SIZE + 2 * block_size as u64 + block_size as u64 * INODE_SIZE * 8;
-        let offset = fs.data_block_seek_position(1);
-        assert_eq!(prefix, offset);
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
 
-        let offset = fs.data_block_seek_position(2);
-        assert_eq!(prefix + block_size as u64, offset);
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = vec![3u8; 2 * BLOCK_SIZE as usize];
+        fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
 
-        let offset = fs.data_block_seek_position(8192);
-        assert_eq!(prefix + 8191 * block_size as u64, offset);
+        fs.create_dir(Path::new("/dir"), nix::sys::stat::Mode::S_IRWXU)?;
+        fs.hard_link(Path::new("/bar.txt"), Path::new("/dir/baz.txt"))?;
+
+        let stat = fs.metadata(Path::new("/dir/baz.txt"))?;
+        assert_eq!(stat.st_ino, handle);
+        assert_eq!(stat.st_nlink, 2);
+        assert_eq!(fs.metadata(Path::new("/bar.txt"))?.st_nlink, 2);
 
-        let offset = fs.data_block_seek_position(8193);
         assert_eq!(
-            2 * prefix - SUPERBLOCK_SIZE + (block_size * block_size) as u64 * 8,
-            offset
+            fs.hard_link(Path::new("/bar.txt"), Path::new("/dir/baz.txt")),
+            Err(Errno::EEXIST)
+        );
+        assert_eq!(
+            fs.hard_link(Path::new("/dir"), Path::new("/dir2")),
+            Err(Errno::EPERM)
         );
-    }
 
-    #[test]
-    fn save_dir() -> anyhow::Result<()> {
-        let tmp_file = make_fs("save_dir")?;
-        let fs = GotenksFS::new(&tmp_file)?;
-        let dir = fs.find_dir_from_inode(ROOT_INODE)?;
+        // The data survives as long as one name is left
+        fs.remove_file(Path::new("/bar.txt"))?;
+        assert_eq!(fs.metadata(Path::new("/dir/baz.txt"))?.st_nlink, 1);
+        assert!(fs.groups().first().unwrap().has_inode(handle as _));
+        assert_eq!(read(&mut fs, buf.len(), 0, handle)?, buf);
 
-        assert_eq!(dir.entries.len(), 0);
+        fs.remove_file(Path::new("/dir/baz.txt"))?;
+        assert!(!fs.groups().first().unwrap().has_inode(handle as _));
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 2);
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn find_dir() -> anyhow::Result<()> {
-        let tmp_file = make_fs("find_dir")?;
-        let fs = GotenksFS::new(&tmp_file)?;
+    fn symlink() -> anyhow::Result<()> {
+        let tmp_file = make_fs("symlink")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
 
-        assert_eq!(fs.find_dir("/not-a-dir").err(), Some(Errno::ENOENT));
+        // Fits in the inode
+        fs.symlink(Path::new("../foo.txt"), Path::new("/short"))?;
+        let stat = fs.metadata(Path::new("/short"))?;
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(stat.st_size, 10);
+        assert_eq!(stat.st_blocks, 0);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+        assert_eq!(fs.read_link(Path::new("/short"))?, OsStr::new("../foo.txt"));
+
+        // Needs a data block
+        let target = "a/".repeat(40);
+        fs.symlink(Path::new(&target), Path::new("/long"))?;
+        assert_eq!(fs.metadata(Path::new("/long"))?.st_size, 80);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 2);
+        assert_eq!(fs.read_link(Path::new("/long"))?, OsStr::new(&target));
+        // Reading a link again doesn't keep another copy of its target
+        fs.read_link(Path::new("/short"))?;
+        fs.read_link(Path::new("/long"))?;
+        assert_eq!(fs.link_targets.lock().unwrap().len(), 2);
+
+        let target = "a".repeat(BLOCK_SIZE as usize + 1);
+        assert_eq!(
+            fs.symlink(Path::new(&target), Path::new("/too-long")),
+            Err(Errno::ENAMETOOLONG)
+        );
+        assert_eq!(
+            fs.symlink(Path::new("foo.txt"), Path::new("/short")),
+            Err(Errno::EEXIST)
+        );
+        assert_eq!(fs.read_link(Path::new("/")), Err(Errno::EINVAL));
+
+        fs.remove_file(Path::new("/short"))?;
+        fs.remove_file(Path::new("/long"))?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn read_dir() -> anyhow::Result<()> {
-        let tmp_file = make_fs("read_dir")?;
+    fn remove_dir() -> anyhow::Result<()> {
+        let tmp_file = make_fs("remove_dir")?;
         let mut fs = GotenksFS::new(&tmp_file)?;
-        let inode = fs.find_inode(ROOT_INODE)?;
-
-        assert_ne!(inode.accessed_at, None);
-
-        let file_info = fuse_rs::fs::FileInfo::default();
-        let entries = fs.read_dir(Path::new("/"), 0, file_info)?;
-        assert_eq!(entries.len(), 0);
 
-        fs.create(
-            Path::new("/foo.txt"),
-            nix::sys::stat::Mode::S_IRWXO,
-            &mut fuse_rs::fs::OpenFileInfo::default(),
-        )?;
+        fs.create_dir(Path::new("/dir"), nix::sys::stat::Mode::S_IRWXU)?;
+        fs.create_dir(Path::new("/dir/sub"), nix::sys::stat::Mode::S_IRWXU)?;
         fs.create(
             Path::new("/bar.txt"),
             nix::sys::stat::Mode::S_IRWXU,
             &mut fuse_rs::fs::OpenFileInfo::default(),
         )?;
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 4);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
 
-        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 3);
+        assert_eq!(fs.remove_dir(Path::new("/dir")), Err(Errno::ENOTEMPTY));
+        assert_eq!(fs.remove_dir(Path::new("/bar.txt")), Err(Errno::ENOTDIR));
+        assert_eq!(fs.remove_dir(Path::new("/missing")), Err(Errno::ENOENT));
 
-        let file_info = fuse_rs::fs::FileInfo::default();
-        let entries = fs.read_dir(Path::new("/"), 0, file_info)?;
-        assert_eq!(entries.len(), 2);
+        fs.remove_dir(Path::new("/dir/sub"))?;
+        assert_eq!(fs.metadata(Path::new("/dir"))?.st_nlink, 2);
+        assert_eq!(
+            Errno::ENOENT,
+            fs.metadata(Path::new("/dir/sub")).unwrap_err()
+        );
 
-        let bar = entries.first().unwrap();
-        let mut stat = FileStat::default();
-        let mode = nix::sys::stat::Mode::S_IRWXU.bits();
-        stat.st_mode = mode;
-        stat.st_ino = 3;
-        assert_eq!(bar.name, OsString::from("bar.txt"));
-        assert_eq!(bar.metadata.as_ref().unwrap().st_ino, 3);
-        assert_eq!(bar.metadata.as_ref().unwrap().st_mode, mode);
+        fs.remove_dir(Path::new("/dir"))?;
+        assert_eq!(fs.metadata(Path::new("/"))?.st_nlink, 2);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 2);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        let foo = entries.last().unwrap();
-        let mut stat = FileStat::default();
-        let mode = nix::sys::stat::Mode::S_IRWXO.bits();
-        stat.st_mode = mode;
-        stat.st_ino = 2;
-        assert_eq!(foo.name, OsString::from("foo.txt"));
-        assert_eq!(foo.metadata.as_ref().unwrap().st_ino, 2);
-        assert_eq!(foo.metadata.as_ref().unwrap().st_mode, mode);
+        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 1);
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn open() -> anyhow::Result<()> {
-        let tmp_file = make_fs("open")?;
+    fn large_dir() -> anyhow::Result<()> {
+        let tmp_file = make_fs("large_dir")?;
         let mut fs = GotenksFS::new(&tmp_file)?;
 
-        let mut file_info = fuse_rs::fs::OpenFileInfo::default();
+        fs.create_dir(Path::new("/dir"), nix::sys::stat::Mode::S_IRWXU)?;
+        // Enough entries to need several levels of index and to go through
+        // the direct, indirect and double indirect blocks of the directory.
+        for i in 0..400 {
+            fs.create(
+                &Path::new("/dir").join(format!("file-{}.txt", i)),
+                nix::sys::stat::Mode::S_IRWXU,
+                &mut fuse_rs::fs::OpenFileInfo::default(),
+            )?;
+        }
+
+        let entries = fs.read_dir(Path::new("/dir"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 400);
         assert_eq!(
-            fs.open(Path::new("/hello.txt"), &mut file_info).err(),
-            Some(Errno::ENOENT)
+            fs.metadata(Path::new("/dir/file-399.txt"))?.st_ino,
+            fs.metadata(Path::new("/dir/file-0.txt"))?.st_ino + 399
         );
 
-        fs.create(
-            Path::new("/bar.txt"),
-            nix::sys::stat::Mode::S_IRWXU,
-            &mut file_info,
-        )?;
+        let (inode, index) = fs.find_inode_from_path(Path::new("/dir"))?;
+        assert!(DirectoryIndex::is_index(&fs.read_dir_block(&inode, 0)?));
+        assert!(fs.read_dir_index(&inode, 0)?.level > 0);
+        assert_eq!(fs.read_dir_index(&inode, 0)?.count, 400);
+        assert_eq!(inode.size % BLOCK_SIZE as u64, 0);
+        // Besides its own blocks, the directory holds the indirect ones.
+        let used_blocks = BLOCK_SIZE * 8 - 1 - fs.superblock().free_blocks;
+        assert_eq!(inode.block_count, used_blocks);
+        assert!(inode.block_count as u64 > inode.size / BLOCK_SIZE as u64);
+        assert_ne!(inode.indirect_block, 0);
+        assert_ne!(inode.double_indirect_block, 0);
+
+        for i in (0..400).step_by(2) {
+            fs.remove_file(&Path::new("/dir").join(format!("file-{}.txt", i)))?;
+        }
+        assert_eq!(fs.remove_dir(Path::new("/dir")), Err(Errno::ENOTEMPTY));
+        assert_eq!(fs.find_dir_from_inode(index)?.entries.len(), 200);
+        assert_eq!(
+            fs.metadata(Path::new("/dir/file-0.txt")).err(),
+            Some(Errno::ENOENT)
+        );
+        assert_eq!(
+            fs.metadata(Path::new("/dir/file-1.txt"))?.st_ino,
+            fs.metadata(Path::new("/dir/file-3.txt"))?.st_ino - 2
+        );
 
-        fs.open(Path::new("/bar.txt"), &mut file_info)?;
+        for i in (1..400).step_by(2) {
+            fs.remove_file(&Path::new("/dir").join(format!("file-{}.txt", i)))?;
+        }
+        fs.remove_dir(Path::new("/dir"))?;
 
-        assert_eq!(file_info.handle(), Some(2));
+        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
+        assert_eq!(entries.len(), 0);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 1);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn write() -> anyhow::Result<()> {
-        let tmp_file = make_fs("write")?;
+    fn truncate() -> anyhow::Result<()> {
+        let tmp_file = make_fs("truncate")?;
         let mut fs = GotenksFS::new(&tmp_file)?;
 
         let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
@@ -1076,301 +2884,697 @@ mod tests {
             &mut open_fi,
         )?;
         let handle = open_fi.handle().unwrap();
-
-        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
         let mut file_info = fuse_rs::fs::FileInfo::default();
         file_info.set_handle(handle);
 
+        // 60 data blocks, which takes the indirect block plus the double
+        // indirect one and one of its indirect blocks.
         let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
-        let buf = std::iter::repeat(3).take(125).collect::<Vec<u8>>();
-
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, 125);
+        let buf = (0..60 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<u8>>();
+        fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 64);
 
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 125);
-        assert_eq!(stat.st_blocks, 1);
+        // Back inside the indirect block's range
+        let len = 20 * BLOCK_SIZE as u64 + 10;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+        fs.ftruncate(Path::new("/ignored.txt"), len, file_info)?;
+        assert_eq!(fs.metadata(Path::new("/bar.txt"))?.st_size, len as i64);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 23);
+        let inode = fs.find_inode(handle as u32)?;
+        assert_ne!(inode.indirect_block, 0);
+        assert_eq!(inode.double_indirect_block, 0);
+        assert_eq!(
+            read(&mut fs, len as usize, 0, handle)?,
+            &buf[..len as usize]
+        );
 
-        assert_eq!(read(&mut fs, 125, 0, handle)?, buf);
+        // Growing reads back zeros past the old end
+        let grown = len + 2 * BLOCK_SIZE as u64;
+        fs.set_len(Path::new("/bar.txt"), grown)?;
+        assert_eq!(fs.metadata(Path::new("/bar.txt"))?.st_size, grown as i64);
+        assert_eq!(
+            read(&mut fs, len as usize, 0, handle)?,
+            &buf[..len as usize]
+        );
+        assert_eq!(
+            read(&mut fs, (grown - len) as usize, len, handle)?,
+            vec![0u8; (grown - len) as usize]
+        );
 
-        // Overwriting with larger buffer
-        let buf = std::iter::repeat(4).take(126).collect::<Vec<u8>>();
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, 126);
+        // Within the direct blocks, the tail of the last block is zeroed
+        fs.set_len(Path::new("/bar.txt"), 5)?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 2);
+        assert_eq!(fs.find_inode(handle as u32)?.indirect_block, 0);
+        fs.set_len(Path::new("/bar.txt"), 10)?;
+        assert_eq!(read(&mut fs, 5, 0, handle)?, &buf[..5]);
+        assert_eq!(read(&mut fs, 5, 5, handle)?, vec![0u8; 5]);
 
+        fs.set_len(Path::new("/bar.txt"), 0)?;
         let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 126);
-        assert_eq!(stat.st_blocks, 1); // 126 / 512 + 1
+        assert_eq!(stat.st_size, 0);
+        assert_eq!(stat.st_blocks, 0);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        assert_eq!(read(&mut fs, 126, 0, handle)?, buf);
+        assert_eq!(fs.set_len(Path::new("/"), 0), Err(Errno::EISDIR));
 
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
+        // Removing a file releases its indirect blocks too
+        fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        fs.remove_file(Path::new("/bar.txt"))?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        let modified_at = inode.modified_at;
-        let changed_at = inode.changed_at;
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        // Overwriting with shorter buffer
-        let buf = std::iter::repeat(5).take(120).collect::<Vec<u8>>();
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, 120);
+    #[test]
+    fn sparse() -> anyhow::Result<()> {
+        let tmp_file = make_fs("sparse")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/bar.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+
+        // Only the last block, past the indirect block's range, is written
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let offset = 50 * BLOCK_SIZE as u64 + 10;
+        let buf = vec![7u8; 20];
+        fs.write(
+            Path::new("/ignored.txt"),
+            &buf,
+            offset,
+            &mut write_file_info,
+        )?;
 
         let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 126);
-        assert_eq!(stat.st_blocks, 1); // 126 / 512 + 1
+        assert_eq!(stat.st_size, offset as i64 + 20);
+        // The data block, the double indirect block and one indirect block
+        assert_eq!(fs.find_inode(handle as u32)?.block_count, 3);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
 
-        assert_eq!(read(&mut fs, 120, 0, handle)?, buf);
         assert_eq!(
-            read(&mut fs, 6, 120, handle)?,
-            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+            read(&mut fs, offset as usize, 0, handle)?,
+            vec![0u8; offset as usize]
         );
+        assert_eq!(read(&mut fs, 20, offset, handle)?, buf);
+
+        let inode = fs.find_inode(handle as u32)?;
+        assert_eq!(fs.lookup_data_block(&inode, 0)?.0, 0);
+        assert_eq!(fs.lookup_data_block(&inode, 49 * BLOCK_SIZE as u64)?.0, 0);
+        assert_ne!(fs.lookup_data_block(&inode, offset)?.0, 0);
+
+        // Writing into a hole only allocates the blocks it covers
+        fs.write(Path::new("/ignored.txt"), &buf, 5, &mut write_file_info)?;
+        assert_eq!(fs.find_inode(handle as u32)?.block_count, 4);
+        assert_eq!(read(&mut fs, 5, 0, handle)?, vec![0u8; 5]);
+        assert_eq!(read(&mut fs, 20, 5, handle)?, buf);
+        assert_eq!(
+            read(&mut fs, BLOCK_SIZE as usize - 25, 25, handle)?,
+            vec![0u8; BLOCK_SIZE as usize - 25]
+        );
+        let inode = fs.find_inode(handle as u32)?;
+        assert_ne!(fs.lookup_data_block(&inode, 0)?.0, 0);
+        assert_eq!(fs.lookup_data_block(&inode, BLOCK_SIZE as u64)?.0, 0);
 
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
+        // Growing leaves a hole as well
+        fs.set_len(Path::new("/bar.txt"), 100 * BLOCK_SIZE as u64)?;
+        assert_eq!(fs.find_inode(handle as u32)?.block_count, 4);
+        let inode = fs.find_inode(handle as u32)?;
+        assert_eq!(fs.lookup_data_block(&inode, 51 * BLOCK_SIZE as u64)?.0, 0);
 
-        // Appending
-        let buf = std::iter::repeat(7).take(125).collect::<Vec<u8>>();
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 126, &mut write_file_info)?;
-        assert_eq!(wrote, 125);
+        fs.remove_file(Path::new("/bar.txt"))?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 251);
-        assert_eq!(stat.st_blocks, 1); // 251 / 512 + 1
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn journal() -> anyhow::Result<()> {
+        let tmp_file = make_fs("journal")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        fs.write(
+            Path::new("/ignored.txt"),
+            &[7u8; 300],
+            0,
+            &mut write_file_info,
+        )?;
+        fs.create_dir(Path::new("/dir"), nix::sys::stat::Mode::S_IRWXU)?;
+
+        // Crash without unmounting, the bitmaps still made it to the image
+        drop(fs);
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 3);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 5);
+        assert!(fs.groups()[0].has_inode(handle as usize));
+        assert!(fs.groups()[0].has_data_block(5));
+        assert_eq!(fs.find_dir(Path::new("/dir"))?, 3);
+
+        // Crash after a transaction reached the journal but before any of it
+        // was written in place
+        let mut inode = fs.find_inode(handle as u32)?;
+        inode.size = 10;
+        let mut fs = fs;
+        let mut sb = Superblock::deserialize_from(Cursor::new(fs.device_mut()))?;
+        sb.free_inodes -= 1;
+        let mut transaction = Transaction::new(vec![
+            (
+                fs.inode_seek_position(handle as u32),
+                inode.serialize(REVISION)?,
+            ),
+            (0, sb.serialize()?),
+        ]);
+        let buf = transaction.serialize()?;
+        let offset = fs.superblock().journal_offset();
+        fs.device_mut().write_at(&buf, offset)?;
+        drop(fs);
+
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.find_inode(handle as u32)?.size, 10);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 4);
+        let start = fs.superblock().journal_offset() as usize;
+        let mut journal = vec![0u8; fs.superblock().journal_size() as usize];
+        fs.device().read_at(&mut journal, start as u64)?;
+        assert!(Transaction::deserialize_from(&journal)?.records.is_empty());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
-        assert_eq!(inode.direct_blocks[1], 3);
+    #[test]
+    fn failed_operations_roll_back() -> anyhow::Result<()> {
+        let tmp_file = make_fs("roll_back")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let free_inodes = fs.superblock().free_inodes;
+        let free_blocks = fs.superblock().free_blocks;
 
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        let mode = nix::sys::stat::Mode::S_IRWXU;
         assert_eq!(
-            read(&mut fs, 120, 0, handle)?,
-            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
+            fs.create(Path::new("/nope/foo.txt"), mode, &mut open_fi),
+            Err(Errno::ENOENT)
         );
         assert_eq!(
-            read(&mut fs, 6, 120, handle)?,
-            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+            fs.create_dir(Path::new("/nope/dir"), mode),
+            Err(Errno::ENOENT)
         );
-        assert_eq!(read(&mut fs, 125, 126, handle)?, buf);
-
-        // Appending again
-        let buf = std::iter::repeat(8).take(125).collect::<Vec<u8>>();
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 251, &mut write_file_info)?;
-        assert_eq!(wrote, 125);
-
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 376);
-        assert_eq!(stat.st_blocks, 1); // 376 / 512 + 1
-
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
-        assert_eq!(inode.direct_blocks[1], 3);
-        assert_eq!(inode.direct_blocks[2], 4);
+        assert_eq!(fs.superblock().free_inodes, free_inodes);
 
+        // Existing names are left alone
+        fs.create_dir(Path::new("/dir"), mode)?;
+        fs.create(Path::new("/dir/foo.txt"), mode, &mut open_fi)?;
+        let foo = fs.find_inode_from_path("/dir/foo.txt")?.1;
         assert_eq!(
-            read(&mut fs, 120, 0, handle)?,
-            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
+            fs.create(Path::new("/dir/foo.txt"), mode, &mut open_fi),
+            Err(Errno::EEXIST)
         );
+        assert_eq!(fs.create_dir(Path::new("/dir"), mode), Err(Errno::EEXIST));
         assert_eq!(
-            read(&mut fs, 6, 120, handle)?,
-            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
+            fs.create_dir(Path::new("/dir/foo.txt"), mode),
+            Err(Errno::EEXIST)
         );
+        assert_eq!(fs.find_inode_from_path("/dir/foo.txt")?.1, foo);
+        assert_eq!(fs.find_inode_from_path("/dir")?.0.hard_links, 2);
+        assert_eq!(fs.superblock().free_inodes, free_inodes - 2);
+        fs.remove_file(Path::new("/dir/foo.txt"))?;
+        fs.remove_dir(Path::new("/dir"))?;
+
+        // More than the image holds: none of the blocks stay allocated
+        fs.create(Path::new("/foo.txt"), mode, &mut open_fi)?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let buf = vec![7u8; (free_blocks as usize + 1) * BLOCK_SIZE as usize];
         assert_eq!(
-            read(&mut fs, 125, 126, handle)?,
-            std::iter::repeat(7).take(125).collect::<Vec<u8>>()
+            fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info),
+            Err(Errno::ENOSPC)
         );
-        assert_eq!(read(&mut fs, 125, 251, handle)?, buf);
+        assert_eq!(fs.superblock().free_blocks, free_blocks);
+        assert_eq!(fs.find_inode(handle as u32)?.size, 0);
+        drop(fs);
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().free_inodes, free_inodes - 1);
+        assert_eq!(fs.superblock().free_blocks, free_blocks);
+        assert_eq!(fs.find_inode(handle as u32)?.block_count, 0);
 
-        // Overwriting in the middle
-        let buf = std::iter::repeat(9).take(125).collect::<Vec<u8>>();
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 126, &mut write_file_info)?;
-        assert_eq!(wrote, 125);
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, 376);
-        assert_eq!(stat.st_blocks, 1); // 376 / 512 + 1
+    #[test]
+    fn remove_in_steps() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "remove_in_steps",
+            util::block_group_size(BLOCK_SIZE) * 6,
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let free_blocks = fs.superblock().free_blocks;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        let mode = nix::sys::stat::Mode::S_IRWXU;
+        fs.create(Path::new("/foo.txt"), mode, &mut open_fi)?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        // Past the double indirect block, over four groups
+        let buf = vec![7u8; 4000 * BLOCK_SIZE as usize];
+        fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
+        let triple = fs.find_inode(handle as u32)?.triple_indirect_block;
+        assert_ne!(triple, 0);
+
+        // A journal too small for the bitmaps of all of them at once
+        fs.superblock_mut().journal_blocks = 10;
+        fs.destroy()?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.max_dirty_groups(), 1);
+        fs.set_len(Path::new("/foo.txt"), 10 * BLOCK_SIZE as u64)?;
+        assert_eq!(fs.find_inode(handle as u32)?.block_count, 10);
+        fs.remove_file(Path::new("/foo.txt"))?;
+        assert_eq!(fs.superblock().free_blocks, free_blocks);
+        // Released as a whole, nothing was written to it
+        assert_ne!(fs.read_u32(0, triple)?, 0);
+        fs.destroy()?;
 
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
-        assert_eq!(inode.direct_blocks[1], 3);
-        assert_eq!(inode.direct_blocks[2], 4);
+        assert!(fsck::check(&tmp_file, false)?.is_empty());
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        assert_ne!(inode.modified_at, modified_at);
-        assert_ne!(inode.changed_at, changed_at);
+    #[test]
+    fn write_through() -> anyhow::Result<()> {
+        let tmp_file = make_fs("write_through")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.superblock_mut().journal_blocks = 0;
+        fs.destroy()?;
 
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
 
-        assert_eq!(
-            read(&mut fs, 120, 0, handle)?,
-            std::iter::repeat(5).take(120).collect::<Vec<u8>>()
-        );
-        assert_eq!(
-            read(&mut fs, 6, 120, handle)?,
-            std::iter::repeat(4).take(6).collect::<Vec<u8>>()
-        );
-        assert_eq!(read(&mut fs, 125, 126, handle)?, buf);
-        assert_eq!(
-            read(&mut fs, 125, 251, handle)?,
-            std::iter::repeat(8).take(125).collect::<Vec<u8>>()
-        );
+        // Without a journal, the updates still reach the image at each
+        // operation
+        drop(fs);
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().journal_blocks, 0);
+        assert_ne!(fs.superblock().last_mounted_at, None);
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 - 2);
+        assert!(fs.groups()[0].has_inode(open_fi.handle().unwrap() as usize));
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn append_only() -> anyhow::Result<()> {
-        let tmp_file = make_fs("append_only")?;
+    fn backup_superblock() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "backup_superblock",
+            util::block_group_size(BLOCK_SIZE) * 4,
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+
+        // Groups 1 and 3 keep a copy in their first 8 blocks
         let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().backup_groups(), vec![1, 3]);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 * 4 - 16 - 1);
+        assert!(fs.groups()[1].has_data_block(8));
+        assert!(!fs.groups()[1].has_data_block(9));
 
         let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
         fs.create(
-            Path::new("/bar.txt"),
+            Path::new("/foo.txt"),
             nix::sys::stat::Mode::S_IRWXU,
             &mut open_fi,
         )?;
+        let mut byte = [0u8; 1];
+        fs.device().read_at(&mut byte, 10)?;
+        fs.device_mut().write_at(&[byte[0] ^ 0xff], 10)?;
+        drop(fs);
 
-        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().free_inodes, BLOCK_SIZE * 8 * 4 - 2);
+        assert!(Superblock::deserialize_from(Cursor::new(fs.device_mut())).is_ok());
+        assert!(fs.find_dir_entry(ROOT_INODE, OsStr::new("foo.txt")).is_ok());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn data_checksums() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "data_checksums",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
         let handle = open_fi.handle().unwrap();
         let mut file_info = fuse_rs::fs::FileInfo::default();
         file_info.set_handle(handle);
-
         let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
-        let buf = std::iter::repeat(3)
-            .take(2 * BLOCK_SIZE as usize)
-            .collect::<Vec<u8>>();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        fs.write(Path::new("/foo.txt"), &data, 0, &mut write_file_info)?;
+        let target = "a/".repeat(40);
+        fs.symlink(Path::new(&target), Path::new("/link"))?;
+        fs.destroy()?;
 
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, buf.len());
-        assert_eq!(read(&mut fs, 2 * BLOCK_SIZE as usize, 0, handle)?, buf);
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(read(&mut fs, 300, 0, handle)?, data);
+
+        // Flip a bit in the second block of the file
+        let block = fs.find_inode(handle as u32)?.direct_blocks[1];
+        let position = fs.data_block_seek_position(block) as usize;
+        let mut byte = [0u8; 1];
+        fs.device().read_at(&mut byte, position as u64 + 5)?;
+        fs.device_mut()
+            .write_at(&[byte[0] ^ 0x1], position as u64 + 5)?;
+        assert_eq!(read(&mut fs, 10, 0, handle)?, &data[..10]);
+        let err = read(&mut fs, 10, 200, handle).unwrap_err();
+        assert_eq!(err.downcast::<Errno>()?, Errno::EIO);
+
+        // Symlink targets kept in a data block are checked too
+        let block = fs.find_inode_from_path(Path::new("/link"))?.0.direct_blocks[0];
+        let position = fs.data_block_seek_position(block);
+        fs.device_mut().write_at(b"b", position)?;
+        assert_eq!(fs.read_link(Path::new("/link")), Err(Errno::EIO));
 
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, buf.len() as _);
-        assert_eq!(stat.st_blocks, 1);
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
-        assert_eq!(inode.direct_blocks[1], 3);
+    #[test]
+    fn triple_indirect() -> anyhow::Result<()> {
+        let tmp_file = make_fs("triple_indirect")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
 
-        let buf = std::iter::repeat(4)
-            .take(BLOCK_SIZE as _)
-            .collect::<Vec<u8>>();
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(
+            Path::new("/foo.txt"),
+            nix::sys::stat::Mode::S_IRWXU,
+            &mut open_fi,
+        )?;
+        let handle = open_fi.handle().unwrap();
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(handle);
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
 
-        let wrote = fs.write(
+        // Past the double indirect tree, which maps up to this block
+        let pointers_per_block = BLOCK_SIZE as u64 / 4;
+        let triple_start = DIRECT_POINTERS + pointers_per_block + pointers_per_block.pow(2);
+        let offset = (triple_start + 5) * BLOCK_SIZE as u64 + 3;
+        let buf = vec![7u8; 20];
+        fs.write(
             Path::new("/ignored.txt"),
             &buf,
-            2 * BLOCK_SIZE as u64,
+            offset,
             &mut write_file_info,
         )?;
-        assert_eq!(wrote, BLOCK_SIZE as _);
+
+        let inode = fs.find_inode(handle as u32)?;
+        assert_ne!(inode.triple_indirect_block, 0);
+        assert_eq!(inode.double_indirect_block, 0);
+        // The data block and one block for each level of pointers
+        assert_eq!(inode.block_count, 4);
+        assert_eq!(read(&mut fs, 20, offset, handle)?, buf);
+
+        let max_size = (triple_start + pointers_per_block.pow(3)) * BLOCK_SIZE as u64;
         assert_eq!(
-            read(&mut fs, BLOCK_SIZE as usize, 2 * BLOCK_SIZE as u64, handle)?,
-            buf
+            fs.write(
+                Path::new("/ignored.txt"),
+                &buf,
+                max_size - 20,
+                &mut write_file_info
+            )?,
+            20
         );
+        // Crossing the maximum size writes what fits, then nothing fits
+        assert_eq!(
+            fs.write(
+                Path::new("/ignored.txt"),
+                &buf,
+                max_size - 10,
+                &mut write_file_info
+            )?,
+            10
+        );
+        assert_eq!(fs.find_inode(handle as u32)?.size, max_size);
+        assert_eq!(
+            fs.write(
+                Path::new("/ignored.txt"),
+                &buf,
+                max_size,
+                &mut write_file_info
+            ),
+            Err(Errno::EFBIG)
+        );
+        assert_eq!(read(&mut fs, 20, max_size - 20, handle)?, buf);
 
-        let stat = fs.metadata(Path::new("/bar.txt"))?;
-        assert_eq!(stat.st_size, BLOCK_SIZE as i64 * 3);
-        assert_eq!(stat.st_blocks, 1);
-
-        let inode = fs.find_inode(2)?;
-        assert_eq!(inode.direct_blocks[0], 2);
-        assert_eq!(inode.direct_blocks[1], 3);
-        assert_eq!(inode.direct_blocks[2], 4);
+        fs.set_len(Path::new("/foo.txt"), triple_start * BLOCK_SIZE as u64)?;
+        let inode = fs.find_inode(handle as u32)?;
+        assert_eq!(inode.triple_indirect_block, 0);
+        assert_eq!(inode.block_count, 0);
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
+        fs.remove_file(Path::new("/foo.txt"))?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     #[test]
-    fn remove_file() -> anyhow::Result<()> {
-        let tmp_file = make_fs("remove_file")?;
+    fn revision_0() -> anyhow::Result<()> {
+        let tmp_file = make_fs("revision_0")?;
+
+        // Back to the layout of images made before revisions
         let mut fs = GotenksFS::new(&tmp_file)?;
+        let root = fs.find_inode(ROOT_INODE)?;
+        fs.superblock_mut().revision = 0;
+        fs.superblock_mut().journal_blocks = 0;
+        fs.save_inode(root, ROOT_INODE)?;
+        fs.destroy()?;
+        let image = std::fs::read(&tmp_file)?;
+        assert_eq!(image[4..8], BLOCK_SIZE.to_le_bytes());
 
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().revision, 0);
+        assert!(fs.superblock().backup_groups().is_empty());
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
         let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
         fs.create(
-            Path::new("/bar.txt"),
+            Path::new("/foo.txt"),
             nix::sys::stat::Mode::S_IRWXU,
             &mut open_fi,
         )?;
-
-        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
         let handle = open_fi.handle().unwrap();
         let mut file_info = fuse_rs::fs::FileInfo::default();
         file_info.set_handle(handle);
-
         let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
-        let buf = std::iter::repeat(3)
-            .take(2 * BLOCK_SIZE as usize)
-            .collect::<Vec<u8>>();
+        fs.write(Path::new("/foo.txt"), b"hello", 0, &mut write_file_info)?;
 
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, buf.len());
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
+        // There is no triple indirect pointer to go past the double one
+        let pointers_per_block = BLOCK_SIZE as u64 / 4;
+        let max_size =
+            (DIRECT_POINTERS + pointers_per_block + pointers_per_block.pow(2)) * BLOCK_SIZE as u64;
+        assert_eq!(
+            fs.write(Path::new("/foo.txt"), b"!", max_size, &mut write_file_info),
+            Err(Errno::EFBIG)
+        );
+        drop(fs);
 
-        let (inode, index) = fs.find_inode_from_path(Path::new("/bar.txt"))?;
-        let blocks = vec![2u32, 3u32];
-        assert_eq!(blocks, inode.direct_blocks());
-        assert_eq!(index, 2);
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().check_reason(), None);
+        assert_eq!(read(&mut fs, 5, 0, handle)?, b"hello");
 
-        fs.remove_file(Path::new("/bar.txt"))?;
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
+    #[test]
+    fn extents() -> anyhow::Result<()> {
+        let tmp_file = make_fs_with("extents", true)?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
+
+        let mut handles = vec![];
+        for name in &["/foo.bin", "/bar.bin"] {
+            let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+            fs.create(Path::new(name), nix::sys::stat::Mode::S_IRWXU, &mut open_fi)?;
+            handles.push(open_fi.handle().unwrap());
+        }
+        let write = |fs: &mut GotenksFS, handle: u64, buf: &[u8], offset: u64| {
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(handle);
+            let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+            fs.write(Path::new("/ignored.bin"), buf, offset, &mut write_file_info)
+        };
+
+        // Consecutive blocks end up in a single extent
+        let data: Vec<u8> = (0..100 * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        write(&mut fs, handles[0], &data, 0)?;
+        let inode = fs.find_inode(handles[0] as u32)?;
+        assert!(inode.uses_extents());
+        let root = inode.extent_root();
+        assert_eq!(root.depth, 0);
+        assert_eq!(root.extents.len(), 1);
+        assert_eq!(root.extents[0].len, 100);
+        assert_eq!(inode.block_count, 100);
+        assert_eq!(read(&mut fs, data.len(), 0, handles[0])?, data);
+
+        // Interleaving both files breaks every run, the tree has to grow
+        for i in 0..30 {
+            let buf = vec![i as u8; BLOCK_SIZE as usize];
+            write(&mut fs, handles[1], &buf, i * BLOCK_SIZE as u64)?;
+            write(&mut fs, handles[0], &buf, (100 + i) * BLOCK_SIZE as u64)?;
+        }
+        let inode = fs.find_inode(handles[1] as u32)?;
+        assert!(inode.extent_root().depth > 0);
+        let tree_blocks = inode.block_count - 30;
+        assert!(tree_blocks > 0);
+        for i in 0..30 {
+            assert_eq!(
+                read(
+                    &mut fs,
+                    BLOCK_SIZE as usize,
+                    i * BLOCK_SIZE as u64,
+                    handles[1]
+                )?,
+                vec![i as u8; BLOCK_SIZE as usize]
+            );
+        }
+
+        // Files can go past what indirect pointers could map, up to the last
+        // logical block
+        let offset = 5 * 1024 * 1024 * 1024;
+        write(&mut fs, handles[1], &[1, 2, 3], offset)?;
+        assert_eq!(read(&mut fs, 3, offset, handles[1])?, vec![1, 2, 3]);
+        let offset = (u32::MAX as u64 + 1) * BLOCK_SIZE as u64;
+        assert_eq!(write(&mut fs, handles[1], &[1], offset), Err(Errno::EFBIG));
+
+        fs.set_len(Path::new("/bar.bin"), 10 * BLOCK_SIZE as u64 + 5)?;
+        let inode = fs.find_inode(handles[1] as u32)?;
+        assert!(inode.block_count < 11 + tree_blocks);
+        let len = 10 * BLOCK_SIZE + 5;
         assert_eq!(
-            Errno::ENOENT,
-            fs.metadata(Path::new("/bar.txt")).unwrap_err()
+            read(&mut fs, len as usize, 0, handles[1])?,
+            (0..len)
+                .map(|i| (i / BLOCK_SIZE) as u8)
+                .collect::<Vec<u8>>()
         );
 
-        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
-        assert_eq!(entries.len(), 0);
-
-        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
-        fs.create(
-            Path::new("/baz.txt"),
-            nix::sys::stat::Mode::S_IRWXU,
-            &mut open_fi,
-        )?;
+        // Growing again reads back zeros past the old end
+        fs.set_len(Path::new("/bar.bin"), 11 * BLOCK_SIZE as u64)?;
+        assert_eq!(
+            read(&mut fs, BLOCK_SIZE as usize - 5, len as u64, handles[1])?,
+            vec![0u8; BLOCK_SIZE as usize - 5]
+        );
 
-        fs.open(Path::new("/baz.txt"), &mut open_fi)?;
-        let handle = open_fi.handle().unwrap();
-        let mut file_info = fuse_rs::fs::FileInfo::default();
-        file_info.set_handle(handle);
+        fs.set_len(Path::new("/foo.bin"), 50 * BLOCK_SIZE as u64)?;
+        assert_eq!(
+            fs.find_inode(handles[0] as u32)?
+                .extent_root()
+                .extents
+                .len(),
+            1
+        );
+        assert_eq!(
+            read(&mut fs, 50 * BLOCK_SIZE as usize, 0, handles[0])?,
+            data[..50 * BLOCK_SIZE as usize].to_vec()
+        );
 
-        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
-        let buf = std::iter::repeat(3)
-            .take(2 * BLOCK_SIZE as usize)
-            .collect::<Vec<u8>>();
+        fs.remove_file(Path::new("/foo.bin"))?;
+        fs.remove_file(Path::new("/bar.bin"))?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 1);
 
-        let wrote = fs.write(Path::new("/ignored.txt"), &buf, 0, &mut write_file_info)?;
-        assert_eq!(wrote, buf.len());
-        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 3);
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
 
-        // Check that it reuses previously freed blocks
-        let (inode, index) = fs.find_inode_from_path(Path::new("/baz.txt"))?;
-        let blocks = vec![2u32, 3u32];
-        assert_eq!(blocks, inode.direct_blocks());
-        assert_eq!(index, 2);
+    #[test]
+    fn extents_per_file() -> anyhow::Result<()> {
+        let tmp_file = make_fs("extents_per_file")?;
+        let mut fs = GotenksFS::new(&tmp_file)?;
 
-        let entries = fs.read_dir(Path::new("/"), 0, fuse_rs::fs::FileInfo::default())?;
-        assert_eq!(entries.len(), 1);
+        let mut handles = vec![];
+        for name in &["/foo.bin", "/bar.bin"] {
+            let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+            fs.create(Path::new(name), nix::sys::stat::Mode::S_IRWXU, &mut open_fi)?;
+            handles.push(open_fi.handle().unwrap());
+        }
+        assert_eq!(fs.superblock().features & FEATURE_EXTENTS, 0);
+        fs.set_extents(handles[0] as u32, true)?;
+        assert_ne!(fs.superblock().features & FEATURE_EXTENTS, 0);
+
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+        for &handle in &handles {
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(handle);
+            let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+            fs.write(Path::new("/ignored.bin"), &data, 0, &mut write_file_info)?;
+        }
+        assert_eq!(
+            fs.set_extents(handles[1] as u32, true),
+            Err(Errno::ENOTEMPTY)
+        );
+        assert_eq!(fs.set_extents(ROOT_INODE, true), Err(Errno::EISDIR));
+        fs.destroy()?;
 
-        let bar = entries.first().unwrap();
-        assert_eq!(bar.name, OsString::from("baz.txt"));
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let inode = fs.find_inode(handles[0] as u32)?;
+        assert!(inode.uses_extents());
+        assert_eq!(inode.extent_root().extents.len(), 1);
+        assert!(!fs.find_inode(handles[1] as u32)?.uses_extents());
+        for &handle in &handles {
+            assert_eq!(read(&mut fs, data.len(), 0, handle)?, data);
+        }
 
         Ok(std::fs::remove_file(&tmp_file)?)
     }
 
     fn make_fs(name: &str) -> anyhow::Result<PathBuf> {
-        let mut tmp_file = std::env::temp_dir();
-        tmp_file.push(name);
-        tmp_file.set_extension("img");
-        if tmp_file.exists() {
-            std::fs::remove_file(&tmp_file)?;
-        }
-
-        let block_group_size = util::block_group_size(BLOCK_SIZE);
-        mkfs::make(&tmp_file, block_group_size, BLOCK_SIZE)?;
+        make_fs_with(name, false)
+    }
 
-        Ok(tmp_file)
+    fn make_fs_with(name: &str, extents: bool) -> anyhow::Result<PathBuf> {
+        mkfs::tmp_image(
+            name,
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options {
+                extents,
+                ..mkfs::Options::default()
+            },
+        )
     }
 
     fn read(
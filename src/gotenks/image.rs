@@ -0,0 +1,382 @@
+use super::{device::BlockDevice, file::File, fs::GotenksFS, types::Inode};
+use anyhow::anyhow;
+use fuse_rs::{fs::FileInfo, Filesystem};
+use nix::{errno::Errno, sys::stat::Mode};
+use std::{ffi::OsString, io, path::Path};
+
+/// An open file system image. Files and directories are reached by absolute
+/// paths inside the image, such as `/docs/hello.txt`.
+///
+/// The image counts as mounted until [`close`](Image::close) is called or the
+/// `Image` is dropped, so an image left open by a crash gets checked by the
+/// next mount.
+#[derive(Debug)]
+pub struct Image {
+    fs: Option<GotenksFS>,
+}
+
+/// What [`Image::stat`] and [`Image::readdir`] return about an inode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub inode: u32,
+    pub mode: u32,
+    pub hard_links: u16,
+    pub user_id: u32,
+    pub group_id: u32,
+    pub size: u64,
+    pub extents: bool,
+    pub created_at: u64,
+    pub accessed_at: Option<i64>,
+    pub modified_at: Option<i64>,
+    pub changed_at: Option<i64>,
+}
+
+impl Metadata {
+    fn new(inode: &Inode, index: u32) -> Self {
+        Self {
+            inode: index,
+            mode: inode.mode,
+            hard_links: inode.hard_links,
+            user_id: inode.user_id,
+            group_id: inode.group_id,
+            size: inode.size,
+            extents: inode.uses_extents(),
+            created_at: inode.created_at,
+            accessed_at: inode.accessed_at,
+            modified_at: inode.modified_at,
+            changed_at: inode.changed_at,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFLNK
+    }
+}
+
+/// An entry of a directory, see [`Image::readdir`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub metadata: Metadata,
+}
+
+impl Image {
+    /// Opens the image at `image_path`. An image that wasn't cleanly
+    /// unmounted, was left with errors or is due for a check is refused, see
+    /// [`needs_check`](Image::needs_check).
+    pub fn new<P>(image_path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::mount(GotenksFS::new(image_path)?)
+    }
+
+    /// Opens an image stored on `device`, such as a
+    /// [`MemoryDevice`](super::MemoryDevice). Images that need checking are
+    /// refused like with [`new`](Image::new).
+    pub fn with_device(device: Box<dyn BlockDevice>) -> anyhow::Result<Self> {
+        Self::mount(GotenksFS::with_device(device)?)
+    }
+
+    /// Returns why the image at `image_path` should be checked with `fsck`
+    /// before it can be opened, or `None` when it can be opened as it is.
+    pub fn needs_check<P>(image_path: P) -> anyhow::Result<Option<String>>
+    where
+        P: AsRef<Path>,
+    {
+        let fs = GotenksFS::read_only(image_path)?;
+        Ok(fs.superblock().check_reason())
+    }
+
+    fn mount(mut fs: GotenksFS) -> anyhow::Result<Self> {
+        if let Some(reason) = fs.superblock().check_reason() {
+            return Err(anyhow!("The image {}, run fsck before using it", reason));
+        }
+
+        fs.init(&mut fuse_rs::fs::ConnectionInfo::default())?;
+        Ok(Self { fs: Some(fs) })
+    }
+
+    /// Writes everything back and marks the image as cleanly unmounted.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        let mut fs = self.fs.take().unwrap();
+        Ok(fs.destroy()?)
+    }
+
+    /// Creates an empty file with the permissions in `mode` and returns its
+    /// inode index.
+    pub fn create<P>(&mut self, path: P, mode: u32) -> io::Result<u32>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        let mut file_info = fuse_rs::fs::OpenFileInfo::default();
+        self.fs_mut()
+            .create(path, Mode::from_bits_truncate(mode), &mut file_info)
+            .map_err(io_error)?;
+
+        Ok(file_info.handle().unwrap() as u32)
+    }
+
+    /// Returns the inode index of the file at `path`, updating its access time.
+    pub fn open<P>(&mut self, path: P) -> io::Result<u32>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        let mut file_info = fuse_rs::fs::OpenFileInfo::default();
+        self.fs_mut().open(path, &mut file_info).map_err(io_error)?;
+
+        Ok(file_info.handle().unwrap() as u32)
+    }
+
+    /// Reads into `buf` from `offset` and returns how many bytes were read,
+    /// fewer than asked for past the end of the file.
+    pub fn read<P>(&mut self, path: P, buf: &mut [u8], offset: u64) -> io::Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        let (path, file_info) = self.file_info(path.as_ref())?;
+        self.fs_mut()
+            .read(path, buf, offset, file_info)
+            .map_err(io_error)
+    }
+
+    /// Writes `buf` at `offset`, growing the file when needed, and returns how
+    /// many bytes were written.
+    pub fn write<P>(&mut self, path: P, buf: &[u8], offset: u64) -> io::Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        let (path, file_info) = self.file_info(path.as_ref())?;
+        let mut file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        self.fs_mut()
+            .write(path, buf, offset, &mut file_info)
+            .map_err(io_error)
+    }
+
+    /// Makes the file at `path` map its data with extents, or with block
+    /// pointers when `extents` is false, instead of the way the image was
+    /// made to map new files. The file must not have any blocks yet.
+    pub fn set_extents<P>(&mut self, path: P, extents: bool) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        let (_, index) = self.fs().find_inode_from_path(path).map_err(io_error)?;
+        self.fs_mut().set_extents(index, extents).map_err(io_error)
+    }
+
+    /// Creates a directory with the permissions in `mode`.
+    pub fn mkdir<P>(&mut self, path: P, mode: u32) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        self.fs_mut()
+            .create_dir(path, Mode::from_bits_truncate(mode))
+            .map_err(io_error)
+    }
+
+    /// Lists the entries of the directory at `path`, sorted by name.
+    pub fn readdir<P>(&self, path: P) -> io::Result<Vec<DirEntry>>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        let fs = self.fs();
+        let index = fs.find_dir(path).map_err(io_error)?;
+        let dir = fs.find_dir_from_inode(index).map_err(io_error)?;
+
+        let mut entries = Vec::with_capacity(dir.entries.len());
+        for (name, index) in dir.entries {
+            let inode = fs.find_inode(index).map_err(io_error)?;
+            entries.push(DirEntry {
+                name,
+                metadata: Metadata::new(&inode, index),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes the file or symlink at `path`. Its data goes away with the last
+    /// link to it.
+    pub fn unlink<P>(&mut self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        if self.stat(path)?.is_dir() {
+            return Err(io_error(Errno::EISDIR));
+        }
+
+        self.fs_mut().remove_file(path).map_err(io_error)
+    }
+
+    /// Returns the metadata of the inode at `path`, without following symlinks.
+    pub fn stat<P>(&self, path: P) -> io::Result<Metadata>
+    where
+        P: AsRef<Path>,
+    {
+        let path = absolute(path.as_ref())?;
+        let (inode, index) = self.fs().find_inode_from_path(path).map_err(io_error)?;
+        Ok(Metadata::new(&inode, index))
+    }
+
+    /// Opens the file with inode index `index`, as returned by
+    /// [`create`](Image::create) and [`open`](Image::open), for reading and
+    /// writing through [`std::io`].
+    pub fn file(&mut self, index: u32) -> io::Result<File<'_>> {
+        if index > self.fs().superblock().inode_count {
+            return Err(io_error(Errno::EINVAL));
+        }
+        self.fs().find_file(index).map_err(io_error)?;
+
+        Ok(File::new(self.fs_mut(), index))
+    }
+
+    fn file_info<'a>(&self, path: &'a Path) -> io::Result<(&'a Path, FileInfo)> {
+        let path = absolute(path)?;
+        let (_, index) = self.fs().find_inode_from_path(path).map_err(io_error)?;
+        self.fs().find_file(index).map_err(io_error)?;
+
+        let mut file_info = FileInfo::default();
+        file_info.set_handle(index as u64);
+        Ok((path, file_info))
+    }
+
+    fn fs(&self) -> &GotenksFS {
+        self.fs.as_ref().unwrap()
+    }
+
+    fn fs_mut(&mut self) -> &mut GotenksFS {
+        self.fs.as_mut().unwrap()
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if let Some(mut fs) = self.fs.take() {
+            let _ = fs.destroy();
+        }
+    }
+}
+
+fn absolute(path: &Path) -> io::Result<&Path> {
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(io_error(Errno::EINVAL))
+    }
+}
+
+pub(super) fn io_error(err: Errno) -> io::Error {
+    io::Error::from_raw_os_error(err as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fsck,
+        gotenks::{util, STATE_DIRTY},
+        mkfs,
+    };
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn path_api() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "image",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options::default(),
+        )?;
+
+        let mut image = Image::new(&tmp_file)?;
+        assert!(image.fs().superblock().state & STATE_DIRTY != 0);
+        image.mkdir("/docs", 0o755)?;
+        let index = image.create("/docs/foo.txt", 0o644)?;
+        assert_eq!(image.open("/docs/foo.txt")?, index);
+        assert!(!image.stat("/docs/foo.txt")?.extents);
+        image.set_extents("/docs/foo.txt", true)?;
+        assert!(image.stat("/docs/foo.txt")?.extents);
+
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(image.write("/docs/foo.txt", &data, 0)?, 300);
+        assert_eq!(image.write("/docs/foo.txt", b"end", 300)?, 3);
+        let mut buf = vec![0u8; 400];
+        assert_eq!(image.read("/docs/foo.txt", &mut buf, 290)?, 13);
+        assert_eq!(
+            &buf[..13],
+            &[34, 35, 36, 37, 38, 39, 40, 41, 42, 43, b'e', b'n', b'd']
+        );
+
+        let err = image.create("/docs/foo.txt", 0o644).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = image.mkdir("/docs", 0o755).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(image.stat("/docs/foo.txt")?.size, 303);
+        let err = image.set_extents("/docs/foo.txt", false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+        let err = image.create("docs/bar.txt", 0o644).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = image.stat("/nope").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = image.unlink("/docs").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+        let err = image.read("/docs", &mut buf, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+        image
+            .fs_mut()
+            .symlink(Path::new("docs/foo.txt"), Path::new("/link"))?;
+        let err = image.write("/link", b"oops", 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        let err = image.read("/link", &mut buf, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(image.fs().read_link(Path::new("/link"))?, "docs/foo.txt");
+        let err = image.file(image.stat("/link")?.inode).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        let err = image.file(image.stat("/docs")?.inode).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+        image.unlink("/link")?;
+        image.close()?;
+        assert_eq!(Image::needs_check(&tmp_file)?, None);
+
+        // An image left open by a crash is refused until it's checked
+        let mut image = Image::new(&tmp_file)?;
+        drop(image.fs.take());
+        assert_eq!(
+            Image::needs_check(&tmp_file)?.unwrap(),
+            "was not cleanly unmounted"
+        );
+        assert!(Image::new(&tmp_file).is_err());
+        fsck::check_if_needed(&tmp_file)?;
+
+        let mut image = Image::new(&tmp_file)?;
+        let entries = image.readdir("/docs")?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "foo.txt");
+        assert_eq!(entries[0].metadata.inode, index);
+        assert_eq!(entries[0].metadata.size, 303);
+        assert!(image.stat("/docs")?.is_dir());
+        assert!(!image.stat("/docs/foo.txt")?.is_dir());
+
+        image.unlink("/docs/foo.txt")?;
+        assert!(image.readdir("/docs")?.is_empty());
+        drop(image);
+
+        // Dropping closes the image too
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().state & STATE_DIRTY, 0);
+        assert_eq!(fs.superblock().free_inodes, fs.superblock().inode_count - 2);
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -0,0 +1,462 @@
+use super::{device::BlockDevice, util};
+use anyhow::anyhow;
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io, mem,
+};
+
+// Metadata updates committed together, as the bytes to write at each offset
+// of the image. The checksums of the data blocks written along with them are
+// records like any other.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+pub struct Transaction {
+    pub records: Vec<(u64, Vec<u8>)>,
+    checksum: u32,
+}
+
+impl Transaction {
+    #[cfg(test)]
+    pub fn new(records: Vec<(u64, Vec<u8>)>) -> Self {
+        Self {
+            records,
+            ..Default::default()
+        }
+    }
+
+    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.checksum();
+        bincode::serialize(self).map_err(|e| e.into())
+    }
+
+    // `buf` is the whole journal, the size limit keeps a garbage length from
+    // turning into a huge allocation.
+    pub fn deserialize_from(buf: &[u8]) -> anyhow::Result<Self> {
+        let mut transaction: Self = bincode::config().limit(buf.len() as u64).deserialize(buf)?;
+        if !transaction.verify_checksum() {
+            return Err(anyhow!("Transaction checksum verification failed"));
+        }
+
+        Ok(transaction)
+    }
+
+    pub fn apply(&self, device: &mut dyn BlockDevice) -> anyhow::Result<()> {
+        for (offset, data) in &self.records {
+            if offset + data.len() as u64 > device.size() {
+                return Err(anyhow!("Journal record past the end of the image"));
+            }
+            device.write_at(data, *offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = util::calculate_checksum(&self);
+    }
+
+    fn verify_checksum(&mut self) -> bool {
+        let checksum = self.checksum;
+        self.checksum = 0;
+        let ok = checksum == util::calculate_checksum(&self);
+        self.checksum = checksum;
+
+        ok
+    }
+}
+
+#[derive(Debug)]
+struct Update {
+    data: Vec<u8>,
+    // File contents are written in place without going through the journal.
+    journaled: bool,
+}
+
+// The journal region of the image and the updates waiting for the next
+// commit. Nothing is written to the image before the commit, reads have to go
+// through `read` to see the updates. The region holds at most one
+// transaction: the last one committed but maybe not yet fully written in
+// place.
+#[derive(Debug, Default)]
+pub struct Journal {
+    offset: u64,
+    size: u64,
+    block_size: u32,
+    // By offset in the image, they never overlap.
+    updates: BTreeMap<u64, Update>,
+    dirty_groups: BTreeSet<usize>,
+    dirty_blocks: BTreeSet<u32>,
+}
+
+impl Journal {
+    pub fn new(offset: u64, size: u64, block_size: u32) -> Self {
+        Self {
+            offset,
+            size,
+            block_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn log(&mut self, offset: u64, data: &[u8]) {
+        self.update(offset, data, true);
+    }
+
+    pub fn log_data(&mut self, offset: u64, data: &[u8]) {
+        self.update(offset, data, false);
+    }
+
+    // Merges the update with the ones it overlaps or touches. Together they
+    // cover a single range, so nothing has to be read from the image.
+    fn update(&mut self, offset: u64, data: &[u8], journaled: bool) {
+        let end = offset + data.len() as u64;
+        let merged: Vec<u64> = self
+            .updates
+            .range(..=end)
+            .rev()
+            .take_while(|(start, update)| *start + update.data.len() as u64 >= offset)
+            .filter(|(start, update)| {
+                let overlaps = **start < end && *start + update.data.len() as u64 > offset;
+                overlaps || update.journaled == journaled
+            })
+            .map(|(start, _)| *start)
+            .collect();
+
+        let start = merged.last().map_or(offset, |first| offset.min(*first));
+        let mut update = Update {
+            data: vec![],
+            journaled,
+        };
+        for key in merged.iter().rev() {
+            let old = self.updates.remove(key).unwrap();
+            let at = (key - start) as usize;
+            if update.data.len() < at + old.data.len() {
+                update.data.resize(at + old.data.len(), 0);
+            }
+            update.data[at..at + old.data.len()].copy_from_slice(&old.data);
+            update.journaled |= old.journaled;
+        }
+
+        let at = (offset - start) as usize;
+        if update.data.len() < at + data.len() {
+            update.data.resize(at + data.len(), 0);
+        }
+        update.data[at..at + data.len()].copy_from_slice(data);
+        self.updates.insert(start, update);
+    }
+
+    // Reads from the image as it will be once the pending updates are
+    // committed.
+    pub fn read(&self, device: &dyn BlockDevice, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        device.read_at(buf, offset)?;
+
+        let end = offset + buf.len() as u64;
+        for (start, update) in self.updates.range(..end).rev() {
+            let update_end = start + update.data.len() as u64;
+            if update_end <= offset {
+                break;
+            }
+
+            let from = offset.max(*start);
+            let to = end.min(update_end);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&update.data[(from - start) as usize..(to - start) as usize]);
+        }
+
+        Ok(())
+    }
+
+    // Bitmaps are only logged at commit, once per group.
+    pub fn mark_group(&mut self, index: usize) {
+        self.dirty_groups.insert(index);
+    }
+
+    pub fn dirty_groups(&self) -> Vec<usize> {
+        self.dirty_groups.iter().copied().collect()
+    }
+
+    // Checksums are also computed at commit, once per block.
+    pub fn mark_block(&mut self, index: u32) {
+        self.dirty_blocks.insert(index);
+    }
+
+    pub fn is_dirty_block(&self, index: u32) -> bool {
+        self.dirty_blocks.contains(&index)
+    }
+
+    pub fn take_dirty_blocks(&mut self) -> BTreeSet<u32> {
+        mem::take(&mut self.dirty_blocks)
+    }
+
+    // About how much of the journal the pending updates would take, the
+    // bitmaps of the groups marked so far included.
+    pub fn pending_size(&self) -> u64 {
+        // The offset and length of each record come first.
+        let header = 2 * mem::size_of::<u64>() as u64;
+        let records: u64 = self
+            .updates
+            .values()
+            .filter(|update| update.journaled)
+            .map(|update| header + update.data.len() as u64)
+            .sum();
+        let bitmaps = self.dirty_groups.len() as u64 * (header + 2 * self.block_size as u64);
+        let checksums = self.dirty_blocks.len() as u64 * (header + 4);
+        records + bitmaps + checksums
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty() && self.dirty_groups.is_empty()
+    }
+
+    // Forgets the pending updates, leaving the image as of the last commit.
+    pub fn discard(&mut self) {
+        self.updates.clear();
+        self.dirty_groups.clear();
+        self.dirty_blocks.clear();
+    }
+
+    // Writes the file contents in place first, then the metadata updates to
+    // the journal and, once they are on disk, to their place in the image: a
+    // transaction in the journal never points at data that isn't there yet.
+    // The checksum of the transaction makes it its own commit record, one
+    // that didn't make it whole fails it. A transaction too big for the
+    // journal fails with ENOSPC and has to be discarded, images made without
+    // a journal get every update written in place directly.
+    pub fn commit(&mut self, device: &mut dyn BlockDevice) -> anyhow::Result<()> {
+        let mut transaction = Transaction {
+            records: self
+                .updates
+                .iter()
+                .filter(|(_, update)| update.journaled)
+                .map(|(offset, update)| (*offset, update.data.clone()))
+                .collect(),
+            checksum: 0,
+        };
+        let buf = transaction.serialize()?;
+        let journaled = self.size != 0;
+        if journaled {
+            if buf.len() as u64 > self.size {
+                return Err(Errno::ENOSPC.into());
+            }
+
+            for (offset, update) in self.updates.iter().filter(|(_, u)| !u.journaled) {
+                device.write_at(&update.data, *offset)?;
+            }
+            device.flush()?;
+            device.write_at(&buf, self.offset)?;
+            device.flush()?;
+        }
+
+        for (offset, update) in &self.updates {
+            if update.journaled || !journaled {
+                device.write_at(&update.data, *offset)?;
+            }
+        }
+        device.flush()?;
+        self.discard();
+
+        if journaled {
+            self.clear(device)?;
+        }
+
+        Ok(())
+    }
+
+    // Finishes writing in place the transaction left by an interrupted
+    // commit, if any. A transaction that didn't make it whole to the journal
+    // fails its checksum and is ignored.
+    pub fn replay(&mut self, device: &mut dyn BlockDevice) -> anyhow::Result<bool> {
+        let mut buf = vec![0u8; self.size as usize];
+        device.read_at(&mut buf, self.offset)?;
+        let transaction = match Transaction::deserialize_from(&buf) {
+            Ok(transaction) if !transaction.records.is_empty() => transaction,
+            _ => return Ok(false),
+        };
+
+        transaction.apply(device)?;
+        device.flush()?;
+        self.clear(device)?;
+
+        Ok(true)
+    }
+
+    // The image as a replay would leave it, without writing to it.
+    pub fn snapshot(&self, device: Box<dyn BlockDevice>) -> anyhow::Result<Snapshot> {
+        let mut buf = vec![0u8; self.size as usize];
+        device.read_at(&mut buf, self.offset)?;
+        let mut journal = Journal::new(self.offset, self.size, self.block_size);
+        if let Ok(transaction) = Transaction::deserialize_from(&buf) {
+            for (offset, data) in &transaction.records {
+                if offset + data.len() as u64 > device.size() {
+                    return Err(anyhow!("Journal record past the end of the image"));
+                }
+                journal.log(*offset, data);
+            }
+        }
+
+        Ok(Snapshot { device, journal })
+    }
+
+    // Once everything is in place the transaction must not be replayed over
+    // later updates. No need to wait for it: replaying the same transaction
+    // again writes the same bytes, and the next commit overwrites it before
+    // writing anything in place.
+    fn clear(&self, device: &mut dyn BlockDevice) -> anyhow::Result<()> {
+        let buf = Transaction::default().serialize()?;
+        Ok(device.write_at(&buf, self.offset)?)
+    }
+}
+
+// An image read through the transaction left in its journal. It can't be
+// written to.
+#[derive(Debug)]
+pub struct Snapshot {
+    device: Box<dyn BlockDevice>,
+    journal: Journal,
+}
+
+impl BlockDevice for Snapshot {
+    fn size(&self) -> u64 {
+        self.device.size()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.journal.read(self.device.as_ref(), buf, offset)
+    }
+
+    fn write_at(&mut self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EROFS))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gotenks::device::MemoryDevice;
+
+    #[test]
+    fn transaction_serialization() -> anyhow::Result<()> {
+        let mut transaction = Transaction::new(vec![(2, vec![1, 2]), (6, vec![3])]);
+        let mut buf = transaction.serialize()?;
+        buf.resize(64, 0);
+        assert_eq!(Transaction::deserialize_from(&buf)?, transaction);
+
+        let mut image = MemoryDevice::new(vec![0u8; 8]);
+        transaction.apply(&mut image)?;
+        assert_eq!(image.as_slice(), &[0, 0, 1, 2, 0, 0, 3, 0]);
+
+        // A torn write
+        buf[10] ^= 0xff;
+        assert!(Transaction::deserialize_from(&buf).is_err());
+        // An empty journal
+        assert!(Transaction::deserialize_from(&[0u8; 64]).is_err());
+        // A length way past the journal
+        assert!(Transaction::deserialize_from(&[0xffu8; 64]).is_err());
+
+        let transaction = Transaction::new(vec![(7, vec![1, 2])]);
+        assert!(transaction.apply(&mut image).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_ahead() -> anyhow::Result<()> {
+        let mut image = MemoryDevice::new(vec![0u8; 256]);
+        let mut journal = Journal::new(128, 128, 16);
+        journal.log(4, &[1, 2, 3]);
+        journal.log_data(7, &[4, 4]);
+        journal.log(6, &[5, 5]);
+        journal.log(20, &[6]);
+
+        // Nothing reaches the image before the commit, but reads see it all
+        assert!(image.as_slice().iter().all(|b| *b == 0));
+        let mut buf = [0u8; 8];
+        journal.read(&image, &mut buf, 2)?;
+        assert_eq!(buf, [0, 0, 1, 2, 5, 5, 4, 0]);
+        assert_eq!(journal.updates.len(), 2);
+        assert!(journal.updates[&4].journaled);
+
+        journal.commit(&mut image)?;
+        assert_eq!(&image.as_slice()[..10], &[0, 0, 0, 0, 1, 2, 5, 5, 4, 0]);
+        assert_eq!(image.as_slice()[20], 6);
+        assert!(journal.is_empty());
+        assert!(!journal.replay(&mut image)?);
+
+        // Too big for the journal
+        journal.log(0, &[7u8; 120]);
+        assert!(journal.commit(&mut image).is_err());
+        journal.discard();
+        assert_eq!(image.as_slice()[4], 1);
+
+        Ok(())
+    }
+
+    // Keeps a copy of the image as of every flush.
+    #[derive(Debug)]
+    struct Flushes {
+        image: MemoryDevice,
+        copies: Vec<Vec<u8>>,
+    }
+
+    impl BlockDevice for Flushes {
+        fn size(&self) -> u64 {
+            self.image.size()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.image.read_at(buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.image.write_at(buf, offset)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.copies.push(self.image.as_slice().to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ordered_commit() -> anyhow::Result<()> {
+        let mut image = Flushes {
+            image: MemoryDevice::new(vec![0u8; 256]),
+            copies: vec![],
+        };
+        let mut journal = Journal::new(128, 128, 16);
+        let checksum = util::block_checksum(&[1u8; 16]).to_le_bytes();
+        journal.log_data(32, &[1u8; 16]);
+        journal.log(64, &checksum);
+        journal.commit(&mut image)?;
+
+        // The data block is on disk before the transaction pointing at it
+        let first = &image.copies[0];
+        assert_eq!(&first[32..48], &[1u8; 16]);
+        assert!(Transaction::deserialize_from(&first[128..]).is_err());
+        assert_eq!(&first[64..68], &[0u8; 4]);
+
+        // Crash once the transaction is in the journal: the replay installs
+        // the checksum the writer computed, which matches what is there
+        let crashed = image.copies[1].clone();
+        assert_eq!(&crashed[64..68], &[0u8; 4]);
+        let journal = Journal::new(128, 128, 16);
+        let mut snapshot = journal.snapshot(Box::new(MemoryDevice::new(crashed.clone())))?;
+        let mut buf = [0u8; 4];
+        snapshot.read_at(&mut buf, 64)?;
+        assert_eq!(buf, checksum);
+        assert!(snapshot.write_at(&[1], 0).is_err());
+
+        let mut crashed = MemoryDevice::new(crashed);
+        let mut journal = Journal::new(128, 128, 16);
+        assert!(journal.replay(&mut crashed)?);
+        assert_eq!(&crashed.as_slice()[64..68], &checksum);
+        assert_eq!(&crashed.as_slice()[32..48], &[1u8; 16]);
+
+        Ok(())
+    }
+}
@@ -1,9 +1,29 @@
-pub mod fs;
-pub mod types;
-pub mod util;
+pub(crate) mod device;
+mod file;
+pub(crate) mod fs;
+mod image;
+pub(crate) mod journal;
+pub(crate) mod scrub;
+pub(crate) mod types;
+pub(crate) mod util;
+
+pub use device::{BlockDevice, FileDevice, MemoryDevice, MmapDevice, RawDevice};
+pub use file::File;
+pub use image::{DirEntry, Image, Metadata};
 
 const GOTENKS_MAGIC: u32 = 0x64627a;
-const ROOT_INODE: u32 = 1;
+const REVISION: u32 = 1; // layout of the superblock and the inodes
+pub(crate) const FEATURE_EXTENTS: u32 = 0x1;
+pub(crate) const FEATURE_DATA_CHECKSUMS: u32 = 0x2;
+const KNOWN_FEATURES: u32 = FEATURE_EXTENTS | FEATURE_DATA_CHECKSUMS;
+pub(crate) const ROOT_INODE: u32 = 1;
 const INODE_SIZE: u64 = 128;
-pub const SUPERBLOCK_SIZE: u64 = 1024;
-pub const DIRECT_POINTERS: u64 = 12;
+pub(crate) const SUPERBLOCK_SIZE: u64 = 1024;
+pub(crate) const DIRECT_POINTERS: u64 = 12;
+const INLINE_DATA_SIZE: u64 = DIRECT_POINTERS * 4;
+const DIRECTORY_INDEX_MAGIC: u64 = 0x4754_4b53_4449_5258;
+pub(crate) const EXTENTS_FLAG: u16 = 0x1;
+const JOURNAL_BLOCKS: u32 = 1024;
+pub(crate) const STATE_DIRTY: u16 = 0x1; // mounted and not unmounted yet
+pub(crate) const STATE_ERRORS: u16 = 0x2; // fsck left problems unrepaired
+const MAX_MOUNT_COUNT: u16 = 20;
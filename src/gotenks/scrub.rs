@@ -0,0 +1,282 @@
+use super::{fs::GotenksFS, ROOT_INODE};
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub struct Corruption {
+    pub path: String,
+    pub description: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.description)
+    }
+}
+
+// Reads the file system one block at a time, starting from the root, and
+// verifies the checksums of the inodes, the directories and, when the image
+// keeps them, the data blocks. Once the tree is done, the allocated inodes
+// no directory leads to are checked as well.
+#[derive(Debug, Default)]
+pub struct Scrub {
+    queue: VecDeque<(u32, String)>,
+    // The data blocks of the last inode checked that are left to verify, and
+    // its path.
+    blocks: VecDeque<u32>,
+    path: String,
+    seen: BTreeSet<u32>,
+    unlinked_queued: bool,
+    found: usize,
+}
+
+impl Scrub {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::from(vec![(ROOT_INODE, "/".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.queue.is_empty() && self.blocks.is_empty() && self.unlinked_queued
+    }
+
+    // Corruptions found since the scrub started.
+    pub fn found(&self) -> usize {
+        self.found
+    }
+
+    pub fn restart(&mut self) {
+        *self = Self::new();
+    }
+
+    // Reads the next data block, or else the next inode along with the
+    // entries of directories.
+    pub fn step(&mut self, fs: &GotenksFS) -> Vec<Corruption> {
+        if let Some(block) = self.blocks.pop_front() {
+            let mut buf = vec![0u8; fs.superblock().block_size as usize];
+            if fs.read_data(&mut buf, 0, block).is_ok() {
+                return vec![];
+            }
+            self.found += 1;
+            return vec![Corruption {
+                path: self.path.clone(),
+                description: format!("block {} fails its checksum", block),
+            }];
+        }
+
+        if self.queue.is_empty() && !self.unlinked_queued {
+            self.unlinked_queued = true;
+            for index in fs.used_inodes() {
+                if !self.seen.contains(&index) {
+                    self.queue
+                        .push_back((index, format!("inode {} (unlinked)", index)));
+                }
+            }
+        }
+
+        let corruptions = match self.queue.pop_front() {
+            Some((index, path)) => self.check(fs, index, path),
+            None => vec![],
+        };
+        self.found += corruptions.len();
+        corruptions
+    }
+
+    fn check(&mut self, fs: &GotenksFS, index: u32, path: String) -> Vec<Corruption> {
+        let mut corruptions = vec![];
+        let mut corrupted = |description: String| {
+            corruptions.push(Corruption {
+                path: path.clone(),
+                description,
+            })
+        };
+
+        if !self.seen.insert(index) {
+            return vec![];
+        }
+        if index == 0 || index > fs.superblock().inode_count {
+            corrupted(format!("inode {} is out of range", index));
+            return corruptions;
+        }
+
+        let inode = match fs.find_inode(index) {
+            Ok(inode) => inode,
+            // Removed since it was queued, or a dangling entry fsck reports
+            Err(Errno::ENOENT) => return vec![],
+            Err(_) => {
+                corrupted(format!("inode {} fails its checksum", index));
+                return corruptions;
+            }
+        };
+
+        let blocks = match fs.inode_blocks(&inode) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                corrupted(format!("block pointers can't be read: {}", err));
+                vec![]
+            }
+        };
+        if fs.superblock().data_checksums() {
+            self.blocks = blocks.into();
+            self.path = path.clone();
+        }
+
+        if inode.is_dir() {
+            match fs.find_dir_from_inode(index) {
+                Ok(dir) => {
+                    for (name, child) in dir.entries {
+                        let child_path = Path::new(&path).join(name);
+                        self.queue
+                            .push_back((child, child_path.display().to_string()));
+                    }
+                }
+                Err(_) => corrupted("directory entries can't be read".to_string()),
+            }
+        }
+
+        corruptions
+    }
+}
+
+pub fn scrub<P>(image_path: P) -> anyhow::Result<Vec<Corruption>>
+where
+    P: AsRef<Path>,
+{
+    let fs = GotenksFS::read_only(image_path)?;
+    let mut scrub = Scrub::new();
+    let mut corruptions = vec![];
+    while !scrub.is_done() {
+        corruptions.extend(scrub.step(&fs));
+    }
+
+    Ok(corruptions)
+}
+
+// What a scrub running alongside a mounted file system found.
+#[derive(Debug)]
+pub enum Event {
+    Corruption(Corruption),
+    // With the number of corruptions found during the pass.
+    PassFinished(usize),
+}
+
+// Scrubs the mounted file system over and over again, one step every
+// `interval` whether it's in use or not, and hands what it finds to `report`.
+// Stops once the file system is unmounted.
+pub fn spawn<F>(fs: Arc<Mutex<GotenksFS>>, interval: Duration, mut report: F) -> JoinHandle<()>
+where
+    F: FnMut(Event) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut scrub = Scrub::new();
+        loop {
+            thread::sleep(interval);
+            let fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+            if !fs.is_open() {
+                return;
+            }
+            let corruptions = scrub.step(&fs);
+            drop(fs);
+
+            for corruption in corruptions {
+                report(Event::Corruption(corruption));
+            }
+            if scrub.is_done() {
+                report(Event::PassFinished(scrub.found()));
+                scrub.restart();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gotenks::util, mkfs};
+    use fuse_rs::Filesystem;
+    use nix::sys::stat::Mode;
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn scrub_image() -> anyhow::Result<()> {
+        let tmp_file = mkfs::tmp_image(
+            "scrub",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.create_dir(Path::new("/dir"), Mode::S_IRWXU)?;
+        let mut handles = vec![];
+        for name in &["/dir/foo.txt", "/bar.txt"] {
+            let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+            fs.create(Path::new(name), Mode::S_IRWXU, &mut open_fi)?;
+            let mut file_info = fuse_rs::fs::FileInfo::default();
+            file_info.set_handle(open_fi.handle().unwrap());
+            let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+            fs.write(Path::new(name), &[7u8; 300], 0, &mut write_file_info)?;
+            handles.push(open_fi.handle().unwrap() as u32);
+        }
+        fs.destroy()?;
+        assert!(scrub(&tmp_file)?.is_empty());
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let block = fs.find_inode(handles[0])?.direct_blocks[2];
+        for position in &[
+            fs.data_block_seek_position(block),
+            fs.inode_seek_position(handles[1]),
+        ] {
+            let mut byte = [0u8; 1];
+            fs.device().read_at(&mut byte, *position)?;
+            fs.device_mut().write_at(&[byte[0] ^ 0x1], *position)?;
+        }
+        drop(fs);
+
+        let corruptions = scrub(&tmp_file)?;
+        let mut found: Vec<String> = corruptions.iter().map(|c| c.to_string()).collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                format!("/bar.txt: inode {} fails its checksum", handles[1]),
+                format!("/dir/foo.txt: block {} fails its checksum", block),
+            ]
+        );
+
+        // Mounted, it goes on even while nothing else happens
+        let fs = Arc::new(Mutex::new(GotenksFS::new(&tmp_file)?));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = spawn(fs.clone(), Duration::from_millis(1), move |event| {
+            let _ = tx.send(event);
+        });
+        let mut found = vec![];
+        loop {
+            match rx.recv()? {
+                Event::Corruption(corruption) => found.push(corruption.to_string()),
+                Event::PassFinished(count) => {
+                    assert_eq!(count, 2);
+                    break;
+                }
+            }
+        }
+        assert_eq!(found.len(), 2);
+        fs.lock().unwrap().destroy()?;
+        handle.join().unwrap();
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}
@@ -1,4 +1,8 @@
-use super::{util, DIRECT_POINTERS, GOTENKS_MAGIC, SUPERBLOCK_SIZE};
+use super::{
+    device::BlockDevice, util, DIRECTORY_INDEX_MAGIC, DIRECT_POINTERS, EXTENTS_FLAG,
+    FEATURE_DATA_CHECKSUMS, GOTENKS_MAGIC, INLINE_DATA_SIZE, JOURNAL_BLOCKS, KNOWN_FEATURES,
+    MAX_MOUNT_COUNT, REVISION, STATE_DIRTY, STATE_ERRORS, SUPERBLOCK_SIZE,
+};
 use anyhow::anyhow;
 use bitvec::{order::Lsb0, vec::BitVec};
 use fuse_rs::fs::FileStat;
@@ -6,14 +10,18 @@ use nix::errno::Errno;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
+    convert::TryFrom,
     ffi::OsString,
     io::{prelude::*, SeekFrom},
+    mem,
     path::Path,
 };
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Superblock {
     pub magic: u32,
+    pub revision: u32,
+    pub features: u32, // what an image needs to be understood, see `FEATURE_*`
     pub block_size: u32,
     pub created_at: u64,
     pub modified_at: Option<u64>,
@@ -26,6 +34,11 @@ pub struct Superblock {
     pub data_blocks_per_group: u32,
     pub uid: u32,
     pub gid: u32,
+    pub inode_flags: u16, // given to every new file and directory
+    pub journal_blocks: u32,
+    pub state: u16,
+    pub mount_count: u16,
+    pub max_mount_count: u16, // 0 never forces a check
     pub checksum: u32,
 }
 
@@ -38,6 +51,8 @@ impl Superblock {
             uid,
             gid,
             magic: GOTENKS_MAGIC,
+            revision: REVISION,
+            features: 0,
             created_at: util::now(),
             modified_at: None,
             last_mounted_at: None,
@@ -46,10 +61,84 @@ impl Superblock {
             block_count: total_blocks,
             inode_count: total_blocks,
             data_blocks_per_group: block_size * 8,
+            inode_flags: 0,
+            journal_blocks: JOURNAL_BLOCKS,
+            state: 0,
+            mount_count: 0,
+            max_mount_count: MAX_MOUNT_COUNT,
             checksum: 0,
         }
     }
 
+    // The journal comes right after the last block group.
+    pub fn journal_offset(&self) -> u64 {
+        SUPERBLOCK_SIZE + util::block_group_size(self.block_size) * self.groups as u64
+    }
+
+    pub fn journal_size(&self) -> u64 {
+        self.journal_blocks as u64 * self.block_size as u64
+    }
+
+    // The CRC32 of every data block, in block order, comes after the journal.
+    pub fn checksums_offset(&self) -> u64 {
+        self.journal_offset() + self.journal_size()
+    }
+
+    pub fn data_checksums(&self) -> bool {
+        self.features & FEATURE_DATA_CHECKSUMS != 0
+    }
+
+    pub fn checksums_size(&self) -> u64 {
+        if self.data_checksums() {
+            self.block_count as u64 * 4
+        } else {
+            0
+        }
+    }
+
+    // Images from before revisions may use the blocks backups go in.
+    pub fn backup_groups(&self) -> Vec<u32> {
+        if self.revision == 0 {
+            return vec![];
+        }
+        util::backup_groups(self.groups)
+    }
+
+    pub fn backup_offset(&self, group: u32) -> u64 {
+        util::backup_superblock_offset(self.block_size, group)
+    }
+
+    // The data blocks the backups take.
+    pub fn backup_blocks(&self) -> Vec<u32> {
+        let count = util::superblock_blocks(self.block_size);
+        self.backup_groups()
+            .into_iter()
+            .flat_map(|g| (1..=count).map(move |i| g * self.data_blocks_per_group + i))
+            .collect()
+    }
+
+    // The layout depends on the block size, which is unknown when the primary
+    // copy is damaged, so every one is tried.
+    pub fn read_backup(device: &dyn BlockDevice) -> Option<Self> {
+        let mut copy = [0u8; SUPERBLOCK_SIZE as usize];
+        for blk_size in (7..=13).map(|shift| 1u32 << shift) {
+            for group in util::backup_groups(u32::MAX) {
+                let offset = util::backup_superblock_offset(blk_size, group);
+                if device.read_at(&mut copy, offset).is_err() {
+                    break;
+                }
+                match Self::deserialize_from(&copy[..]) {
+                    Ok(sb) if sb.block_size == blk_size && sb.magic == GOTENKS_MAGIC => {
+                        return Some(sb)
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        None
+    }
+
     pub fn update_last_mounted_at(&mut self) {
         self.last_mounted_at = Some(util::now());
     }
@@ -58,28 +147,62 @@ impl Superblock {
         self.modified_at = Some(util::now());
     }
 
-    #[allow(dead_code)]
-    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
-        self.checksum();
-        bincode::serialize(self).map_err(|e| e.into())
+    // Why the image should go through fsck before being mounted, if it should.
+    pub fn check_reason(&self) -> Option<String> {
+        if self.state & STATE_ERRORS != 0 {
+            Some("has errors".to_string())
+        } else if self.state & STATE_DIRTY != 0 {
+            Some("was not cleanly unmounted".to_string())
+        } else if self.max_mount_count != 0 && self.mount_count >= self.max_mount_count {
+            Some(format!(
+                "has been mounted {} times without being checked",
+                self.mount_count
+            ))
+        } else {
+            None
+        }
     }
 
-    pub fn serialize_into<W>(&mut self, w: W) -> anyhow::Result<()>
-    where
-        W: Write,
-    {
+    // An image keeps the layout of the revision it was made with.
+    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
+        if self.revision == 0 {
+            return SuperblockV0::from(&*self).serialize();
+        }
+
         self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+        bincode::serialize(self).map_err(|e| e.into())
     }
 
     pub fn deserialize_from<R>(r: R) -> anyhow::Result<Self>
     where
         R: Read,
     {
-        let mut sb: Self = bincode::deserialize_from(r)?;
+        let mut buf = vec![];
+        r.take(SUPERBLOCK_SIZE).read_to_end(&mut buf)?;
+        if buf.len() < 8 {
+            return Err(anyhow!("Superblock is too short"));
+        }
+
+        // Images from before revisions have their block size, at least 128,
+        // where the revision is now.
+        let revision = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if revision >= 128 {
+            return Ok(SuperblockV0::deserialize_from(buf.as_slice())?.into());
+        }
+        if revision > REVISION {
+            return Err(anyhow!("Unsupported image revision {}", revision));
+        }
+
+        let mut sb: Self = bincode::deserialize_from(buf.as_slice())?;
         if !sb.verify_checksum() {
             return Err(anyhow!("Superblock checksum verification failed"));
         }
+        if sb.features & !KNOWN_FEATURES != 0 {
+            return Err(anyhow!(
+                "Unsupported image features {:#x}",
+                sb.features & !KNOWN_FEATURES
+            ));
+        }
 
         Ok(sb)
     }
@@ -99,6 +222,95 @@ impl Superblock {
     }
 }
 
+// The superblock as it was before revisions, which images made back then
+// still use.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SuperblockV0 {
+    magic: u32,
+    block_size: u32,
+    created_at: u64,
+    modified_at: Option<u64>,
+    last_mounted_at: Option<u64>,
+    block_count: u32,
+    inode_count: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: u32,
+    data_blocks_per_group: u32,
+    uid: u32,
+    gid: u32,
+    checksum: u32,
+}
+
+impl SuperblockV0 {
+    fn serialize(mut self) -> anyhow::Result<Vec<u8>> {
+        self.checksum = 0;
+        self.checksum = util::calculate_checksum(&self);
+        bincode::serialize(&self).map_err(|e| e.into())
+    }
+
+    fn deserialize_from(buf: &[u8]) -> anyhow::Result<Self> {
+        let mut sb: Self = bincode::deserialize_from(buf)?;
+        let checksum = sb.checksum;
+        sb.checksum = 0;
+        if checksum != util::calculate_checksum(&sb) {
+            return Err(anyhow!("Superblock checksum verification failed"));
+        }
+
+        Ok(sb)
+    }
+}
+
+// Revision 0 has no journal, no checksums and no mount state.
+impl From<SuperblockV0> for Superblock {
+    fn from(sb: SuperblockV0) -> Self {
+        Self {
+            magic: sb.magic,
+            revision: 0,
+            features: 0,
+            block_size: sb.block_size,
+            created_at: sb.created_at,
+            modified_at: sb.modified_at,
+            last_mounted_at: sb.last_mounted_at,
+            block_count: sb.block_count,
+            inode_count: sb.inode_count,
+            free_blocks: sb.free_blocks,
+            free_inodes: sb.free_inodes,
+            groups: sb.groups,
+            data_blocks_per_group: sb.data_blocks_per_group,
+            uid: sb.uid,
+            gid: sb.gid,
+            inode_flags: 0,
+            journal_blocks: 0,
+            state: 0,
+            mount_count: 0,
+            max_mount_count: 0,
+            checksum: 0,
+        }
+    }
+}
+
+impl From<&Superblock> for SuperblockV0 {
+    fn from(sb: &Superblock) -> Self {
+        Self {
+            magic: sb.magic,
+            block_size: sb.block_size,
+            created_at: sb.created_at,
+            modified_at: sb.modified_at,
+            last_mounted_at: sb.last_mounted_at,
+            block_count: sb.block_count,
+            inode_count: sb.inode_count,
+            free_blocks: sb.free_blocks,
+            free_inodes: sb.free_inodes,
+            groups: sb.groups,
+            data_blocks_per_group: sb.data_blocks_per_group,
+            uid: sb.uid,
+            gid: sb.gid,
+            checksum: 0,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Group {
     pub data_bitmap: BitVec<Lsb0, u8>,
@@ -108,6 +320,7 @@ pub struct Group {
 }
 
 impl Group {
+    #[cfg(test)]
     pub fn serialize_into<W>(mut w: W, groups: &[Group]) -> anyhow::Result<()>
     where
         W: Write + Seek,
@@ -209,6 +422,17 @@ impl Group {
         self.next_inode = self.next_free_inode();
     }
 
+    // Unlike the allocations, repairs pick the exact inode or block.
+    pub fn set_inode(&mut self, i: usize, used: bool) {
+        self.inode_bitmap.set(i - 1, used);
+        self.next_inode = self.next_free_inode();
+    }
+
+    pub fn set_data_block(&mut self, i: usize, used: bool) {
+        self.data_bitmap.set(i - 1, used);
+        self.next_data_block = self.next_free_data_block();
+    }
+
     #[inline]
     fn add_inode(&mut self, i: usize) {
         self.inode_bitmap.set(i - 1, true);
@@ -233,13 +457,13 @@ impl Group {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Inode {
     pub mode: libc::mode_t,
     pub hard_links: u16,
     pub user_id: libc::uid_t,
     pub group_id: libc::gid_t,
-    pub block_count: u32, // should be in 512 bytes blocks
+    pub block_count: u32, // allocated blocks, indirect ones included
     pub size: u64,
     pub created_at: u64,
     pub accessed_at: Option<i64>,
@@ -248,6 +472,8 @@ pub struct Inode {
     pub direct_blocks: [u32; DIRECT_POINTERS as usize],
     pub indirect_block: u32,
     pub double_indirect_block: u32,
+    pub triple_indirect_block: u32,
+    pub flags: u16,
     pub checksum: u32,
 }
 
@@ -263,21 +489,21 @@ impl Inode {
         inode
     }
 
-    #[allow(dead_code)]
-    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
+    // In the layout of the image `revision`.
+    pub fn serialize(&mut self, revision: u32) -> anyhow::Result<Vec<u8>> {
+        if revision == 0 {
+            return InodeV0::try_from(&*self)?.serialize();
+        }
+
         self.checksum();
         bincode::serialize(self).map_err(|e| e.into())
     }
 
-    pub fn serialize_into<W>(&mut self, w: W) -> anyhow::Result<()>
-    where
-        W: Write,
-    {
-        self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
-    }
+    pub fn deserialize_from<R: std::io::Read>(r: R, revision: u32) -> anyhow::Result<Self> {
+        if revision == 0 {
+            return Ok(InodeV0::deserialize_from(r)?.into());
+        }
 
-    pub fn deserialize_from<R: std::io::Read>(r: R) -> anyhow::Result<Self> {
         let mut inode: Self = bincode::deserialize_from(r)?;
         if !inode.verify_checksum() {
             return Err(anyhow!("Inode checksum verification failed"));
@@ -290,17 +516,79 @@ impl Inode {
         (self.mode & libc::S_IFDIR) != 0
     }
 
+    pub fn is_symlink(&self) -> bool {
+        (self.mode & libc::S_IFMT) == libc::S_IFLNK
+    }
+
+    // Files are created with only their permissions in `mode`.
+    pub fn is_file(&self) -> bool {
+        let file_type = self.mode & libc::S_IFMT;
+        file_type == libc::S_IFREG || file_type == 0
+    }
+
+    // Files mapped by extents keep the root of their extent tree in
+    // `direct_blocks` and don't use the indirect pointers.
+    pub fn uses_extents(&self) -> bool {
+        self.flags & EXTENTS_FLAG != 0
+    }
+
+    pub fn extent_root(&self) -> ExtentNode {
+        ExtentNode::from_words(&self.direct_blocks)
+    }
+
+    pub fn set_extent_root(&mut self, node: &ExtentNode) {
+        node.to_words(&mut self.direct_blocks);
+    }
+
+    // Symlinks whose target fits in `direct_blocks` don't use any data block.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.size <= INLINE_DATA_SIZE
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        assert!(data.len() as u64 <= INLINE_DATA_SIZE);
+        let mut buf = [0u8; INLINE_DATA_SIZE as usize];
+        buf[..data.len()].copy_from_slice(data);
+        for (i, chunk) in buf.chunks(4).enumerate() {
+            self.direct_blocks[i] = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        self.size = data.len() as u64;
+        self.block_count = 0;
+    }
+
+    // Position of `direct_blocks` inside the serialized inode. Since pointers
+    // are written in little endian, this is where the inline data starts.
+    pub fn inline_data_offset(&self) -> u64 {
+        bincode::serialized_size(&(
+            self.mode,
+            self.hard_links,
+            self.user_id,
+            self.group_id,
+            self.block_count,
+            self.size,
+            self.created_at,
+            self.accessed_at,
+            self.modified_at,
+            self.changed_at,
+        ))
+        .unwrap()
+    }
+
     pub fn update_modified_at(&mut self) {
         let now = util::now();
         self.changed_at = Some(now as _);
         self.modified_at = Some(now as _);
     }
 
+    pub fn update_changed_at(&mut self) {
+        self.changed_at = Some(util::now() as _);
+    }
+
     pub fn update_accessed_at(&mut self) {
         self.accessed_at = Some(util::now() as _);
     }
 
-    pub fn to_stat(&self, index: u32) -> FileStat {
+    pub fn to_stat(&self, index: u32, blk_size: u32) -> FileStat {
         let mut stat = FileStat::new();
         stat.st_ino = index as _;
         stat.st_mode = self.mode;
@@ -310,27 +598,40 @@ impl Inode {
         stat.st_ctime = self.changed_at.unwrap_or(0);
         stat.st_birthtime = self.created_at as _;
         stat.st_size = self.size as i64;
-        stat.st_blocks = self.block_count as i64;
+        // In 512 bytes units, whatever the block size.
+        stat.st_blocks = (self.block_count as u64 * blk_size as u64).div_ceil(512) as i64;
         stat.st_uid = self.user_id;
         stat.st_gid = self.group_id;
 
         stat
     }
 
-    #[inline]
-    pub fn direct_blocks(&self) -> Vec<u32> {
-        self.direct_blocks
-            .iter()
-            .filter_map(|x| if *x != 0 { Some(*x) } else { None })
-            .collect::<Vec<u32>>()
-    }
+    // Sets the size to `len` and returns the direct blocks that are no longer
+    // needed. Blocks behind the indirect pointers, and accounting for what is
+    // released, are left to the caller.
+    pub fn truncate(&mut self, len: u64, blk_size: u64) -> Vec<u32> {
+        // Neither inline data nor extents are made of block pointers.
+        let pointers = !self.is_fast_symlink() && !self.uses_extents();
+        let keep = len.div_ceil(blk_size).min(DIRECT_POINTERS) as usize;
+        let blocks = if pointers {
+            self.direct_blocks[keep..]
+                .iter()
+                .filter_map(|x| if *x != 0 { Some(*x) } else { None })
+                .collect()
+        } else {
+            Vec::new()
+        };
 
-    pub fn truncate(&mut self) -> Vec<u32> {
         self.update_modified_at();
-        self.size = 0;
-        self.block_count = 0;
-        let blocks = self.direct_blocks();
-        self.direct_blocks = [0u32; 12];
+        self.size = len;
+        if len == 0 {
+            self.block_count = 0;
+        }
+        if pointers {
+            for block in self.direct_blocks[keep..].iter_mut() {
+                *block = 0;
+            }
+        }
         blocks
     }
 
@@ -346,14 +647,29 @@ impl Inode {
         Ok(())
     }
 
+    // The root of the indirect tree with `depth` levels of pointers.
+    pub fn indirect_root(&self, depth: u32) -> u32 {
+        match depth {
+            1 => self.indirect_block,
+            2 => self.double_indirect_block,
+            _ => self.triple_indirect_block,
+        }
+    }
+
+    pub fn set_indirect_root(&mut self, depth: u32, block: u32) {
+        match depth {
+            1 => self.indirect_block = block,
+            2 => self.double_indirect_block = block,
+            _ => self.triple_indirect_block = block,
+        }
+    }
+
     pub fn adjust_size(&mut self, len: u64) {
         self.size = self.size.max(len);
-        self.block_count = self.size as u32 / 512 + 1;
     }
 
     pub fn increment_size(&mut self, len: u64) {
         self.size += len;
-        self.block_count = self.size as u32 / 512 + 1;
     }
 
     fn checksum(&mut self) {
@@ -371,6 +687,229 @@ impl Inode {
     }
 }
 
+// The inode as it was before revisions, without triple indirect pointers or
+// flags.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct InodeV0 {
+    mode: libc::mode_t,
+    hard_links: u16,
+    user_id: libc::uid_t,
+    group_id: libc::gid_t,
+    block_count: u32,
+    size: u64,
+    created_at: u64,
+    accessed_at: Option<i64>,
+    modified_at: Option<i64>,
+    changed_at: Option<i64>,
+    direct_blocks: [u32; DIRECT_POINTERS as usize],
+    indirect_block: u32,
+    double_indirect_block: u32,
+    checksum: u32,
+}
+
+impl InodeV0 {
+    fn serialize(mut self) -> anyhow::Result<Vec<u8>> {
+        self.checksum = 0;
+        self.checksum = util::calculate_checksum(&self);
+        bincode::serialize(&self).map_err(|e| e.into())
+    }
+
+    fn deserialize_from<R: std::io::Read>(r: R) -> anyhow::Result<Self> {
+        let mut inode: Self = bincode::deserialize_from(r)?;
+        let checksum = inode.checksum;
+        inode.checksum = 0;
+        if checksum != util::calculate_checksum(&inode) {
+            return Err(anyhow!("Inode checksum verification failed"));
+        }
+
+        Ok(inode)
+    }
+}
+
+impl From<InodeV0> for Inode {
+    fn from(inode: InodeV0) -> Self {
+        Self {
+            mode: inode.mode,
+            hard_links: inode.hard_links,
+            user_id: inode.user_id,
+            group_id: inode.group_id,
+            block_count: inode.block_count,
+            size: inode.size,
+            created_at: inode.created_at,
+            accessed_at: inode.accessed_at,
+            modified_at: inode.modified_at,
+            changed_at: inode.changed_at,
+            direct_blocks: inode.direct_blocks,
+            indirect_block: inode.indirect_block,
+            double_indirect_block: inode.double_indirect_block,
+            triple_indirect_block: 0,
+            flags: 0,
+            checksum: 0,
+        }
+    }
+}
+
+impl TryFrom<&Inode> for InodeV0 {
+    type Error = anyhow::Error;
+
+    fn try_from(inode: &Inode) -> anyhow::Result<Self> {
+        if inode.triple_indirect_block != 0 || inode.flags != 0 {
+            return Err(anyhow!("Inode doesn't fit the layout of revision 0"));
+        }
+
+        Ok(Self {
+            mode: inode.mode,
+            hard_links: inode.hard_links,
+            user_id: inode.user_id,
+            group_id: inode.group_id,
+            block_count: inode.block_count,
+            size: inode.size,
+            created_at: inode.created_at,
+            accessed_at: inode.accessed_at,
+            modified_at: inode.modified_at,
+            changed_at: inode.changed_at,
+            direct_blocks: inode.direct_blocks,
+            indirect_block: inode.indirect_block,
+            double_indirect_block: inode.double_indirect_block,
+            checksum: 0,
+        })
+    }
+}
+
+// A run of `len` logical blocks mapped to as many consecutive blocks starting
+// at `physical`. In index nodes `physical` is the block of the child node
+// mapping everything from `logical` on and `len` is unused.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Extent {
+    pub logical: u32,
+    pub physical: u32,
+    pub len: u32,
+}
+
+impl Extent {
+    pub fn contains(&self, logical: u32) -> bool {
+        logical >= self.logical && (logical as u64) < self.logical as u64 + self.len as u64
+    }
+}
+
+// A node of an extent tree, stored as little endian words: the number of
+// entries and the depth, a reserved word and then three words per entry.
+// Leaves have depth 0. The root lives in the inode's `direct_blocks`, the
+// other nodes take a whole block each.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExtentNode {
+    pub depth: u32,
+    pub extents: Vec<Extent>,
+}
+
+const EXTENT_HEADER_WORDS: usize = 3;
+const EXTENT_WORDS: usize = 3;
+
+impl ExtentNode {
+    pub fn from_words(words: &[u32]) -> Self {
+        let count = words[0] as usize;
+        let extents = words[EXTENT_HEADER_WORDS..]
+            .chunks_exact(EXTENT_WORDS)
+            .take(count)
+            .map(|w| Extent {
+                logical: w[0],
+                physical: w[1],
+                len: w[2],
+            })
+            .collect();
+
+        Self {
+            depth: words[1],
+            extents,
+        }
+    }
+
+    pub fn to_words(&self, words: &mut [u32]) {
+        words.iter_mut().for_each(|w| *w = 0);
+        words[0] = self.extents.len() as u32;
+        words[1] = self.depth;
+        let entries = words[EXTENT_HEADER_WORDS..].chunks_exact_mut(EXTENT_WORDS);
+        for (w, extent) in entries.zip(&self.extents) {
+            w[0] = extent.logical;
+            w[1] = extent.physical;
+            w[2] = extent.len;
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let words: Vec<u32> = buf
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Self::from_words(&words)
+    }
+
+    pub fn to_bytes(&self, blk_size: usize) -> Vec<u8> {
+        let mut words = vec![0u32; blk_size / 4];
+        self.to_words(&mut words);
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    // How many entries fit in `words` words.
+    pub fn capacity(words: usize) -> usize {
+        (words - EXTENT_HEADER_WORDS) / EXTENT_WORDS
+    }
+
+    // The last entry starting at or before `logical`.
+    pub fn position(&self, logical: u32) -> Option<usize> {
+        self.extents
+            .partition_point(|e| e.logical <= logical)
+            .checked_sub(1)
+    }
+
+    // Maps `logical` to `physical` in a leaf, growing a neighbouring extent
+    // when both blocks follow it.
+    pub fn insert(&mut self, logical: u32, physical: u32) {
+        let pos = self.extents.partition_point(|e| e.logical < logical);
+        let follows = |e: &Extent| {
+            e.logical as u64 + e.len as u64 == logical as u64
+                && e.physical as u64 + e.len as u64 == physical as u64
+        };
+        let precedes = |e: &Extent| {
+            logical as u64 + 1 == e.logical as u64 && physical as u64 + 1 == e.physical as u64
+        };
+
+        if pos > 0 && follows(&self.extents[pos - 1]) {
+            self.extents[pos - 1].len += 1;
+            if pos < self.extents.len() && precedes(&self.extents[pos]) {
+                let next = self.extents.remove(pos);
+                self.extents[pos - 1].len += next.len;
+            }
+        } else if pos < self.extents.len() && precedes(&self.extents[pos]) {
+            let next = &mut self.extents[pos];
+            next.logical = logical;
+            next.physical = physical;
+            next.len += 1;
+        } else {
+            self.extents.insert(
+                pos,
+                Extent {
+                    logical,
+                    physical,
+                    len: 1,
+                },
+            );
+        }
+    }
+
+    // Moves the upper half of the entries to a new node.
+    pub fn split_off(&mut self) -> ExtentNode {
+        let at = self.extents.len() / 2;
+        ExtentNode {
+            depth: self.depth,
+            extents: self.extents.split_off(at),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Directory {
     pub entries: BTreeMap<OsString, u32>,
@@ -378,12 +917,9 @@ pub struct Directory {
 }
 
 impl Directory {
-    pub fn serialize_into<W>(&mut self, w: W) -> anyhow::Result<()>
-    where
-        W: Write,
-    {
+    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
         self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+        bincode::serialize(self).map_err(|e| e.into())
     }
 
     pub fn deserialize_from<R>(r: R) -> anyhow::Result<Self>
@@ -408,6 +944,112 @@ impl Directory {
             .map(|x| *x)
     }
 
+    // Moves the entries whose name hashes to at least the median hash into a
+    // new directory. Names sharing a hash always stay together, so it fails
+    // when every entry has the same hash.
+    pub fn split_off(&mut self) -> Option<(u64, Directory)> {
+        let mut hashes = self
+            .entries
+            .keys()
+            .map(|name| util::hash_name(name))
+            .collect::<Vec<u64>>();
+        hashes.sort_unstable();
+
+        let median = hashes[hashes.len() / 2];
+        let separator = if median > hashes[0] {
+            median
+        } else {
+            *hashes.iter().find(|hash| **hash > median)?
+        };
+
+        let (right, left) = mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(name, _)| util::hash_name(name) >= separator);
+        self.entries = left;
+
+        Some((
+            separator,
+            Directory {
+                entries: right,
+                checksum: 0,
+            },
+        ))
+    }
+
+    fn checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = util::calculate_checksum(&self);
+    }
+
+    fn verify_checksum(&mut self) -> bool {
+        let checksum = self.checksum;
+        self.checksum = 0;
+        let ok = checksum == util::calculate_checksum(&self);
+        self.checksum = checksum;
+
+        ok
+    }
+}
+
+// Node of the hash tree used by directories that outgrow a single block.
+// The root always lives in the first block of the directory, where the
+// magic tells it apart from a plain `Directory`. Children are sorted by the
+// lowest name hash they hold and, at level 0, they are leaf blocks holding
+// a `Directory` each.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DirectoryIndex {
+    magic: u64,
+    pub level: u32,
+    pub count: u64, // entries in the whole directory, only kept in the root
+    pub children: Vec<(u64, u32)>,
+    checksum: u32,
+}
+
+impl DirectoryIndex {
+    pub fn new(level: u32, children: Vec<(u64, u32)>) -> Self {
+        Self {
+            magic: DIRECTORY_INDEX_MAGIC,
+            level,
+            count: 0,
+            children,
+            checksum: 0,
+        }
+    }
+
+    pub fn is_index(buf: &[u8]) -> bool {
+        buf.len() >= 8 && buf[..8] == DIRECTORY_INDEX_MAGIC.to_le_bytes()
+    }
+
+    pub fn serialize(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.checksum();
+        bincode::serialize(self).map_err(|e| e.into())
+    }
+
+    pub fn deserialize_from<R>(r: R) -> anyhow::Result<Self>
+    where
+        R: Read,
+    {
+        let mut index: Self = bincode::deserialize_from(r)?;
+        if index.magic != DIRECTORY_INDEX_MAGIC || !index.verify_checksum() {
+            return Err(anyhow!("Directory index checksum verification failed"));
+        }
+
+        Ok(index)
+    }
+
+    // Position of the child covering `hash`.
+    pub fn position(&self, hash: u64) -> usize {
+        self.children
+            .partition_point(|(lowest, _)| *lowest <= hash)
+            .saturating_sub(1)
+    }
+
+    // Moves the upper half of the children into a new node on the same level.
+    pub fn split_off(&mut self) -> (u64, DirectoryIndex) {
+        let right = self.children.split_off(self.children.len() / 2);
+        (right[0].0, DirectoryIndex::new(self.level, right))
+    }
+
     fn checksum(&mut self) {
         self.checksum = 0;
         self.checksum = util::calculate_checksum(&self);
@@ -437,6 +1079,66 @@ mod tests {
         assert_eq!(sb.data_blocks_per_group, 1024 * 8);
     }
 
+    #[test]
+    fn superblock_check_reason() {
+        let mut sb = Superblock::new(1024, 3, 0, 0);
+        assert_eq!(sb.check_reason(), None);
+
+        sb.mount_count = MAX_MOUNT_COUNT;
+        assert!(sb.check_reason().unwrap().contains("mounted 20 times"));
+        sb.max_mount_count = 0;
+        assert_eq!(sb.check_reason(), None);
+
+        sb.state = STATE_DIRTY;
+        assert_eq!(sb.check_reason().unwrap(), "was not cleanly unmounted");
+        sb.state |= STATE_ERRORS;
+        assert_eq!(sb.check_reason().unwrap(), "has errors");
+    }
+
+    #[test]
+    fn superblock_revisions() -> anyhow::Result<()> {
+        let mut sb = Superblock::new(1024, 3, 0, 0);
+        sb.revision = 0;
+        sb.journal_blocks = 0;
+        let buf = <Superblock>::serialize(&mut sb)?;
+        let old = SuperblockV0::deserialize_from(&buf)?;
+        assert_eq!(old.block_size, 1024);
+        let sb = Superblock::deserialize_from(buf.as_slice())?;
+        assert_eq!((sb.revision, sb.groups, sb.max_mount_count), (0, 3, 0));
+
+        let mut sb = Superblock::new(1024, 3, 0, 0);
+        sb.revision = REVISION + 1;
+        let buf = <Superblock>::serialize(&mut sb)?;
+        assert!(Superblock::deserialize_from(buf.as_slice()).is_err());
+
+        let mut sb = Superblock::new(1024, 3, 0, 0);
+        sb.features = FEATURE_DATA_CHECKSUMS;
+        let buf = <Superblock>::serialize(&mut sb)?;
+        assert!(Superblock::deserialize_from(buf.as_slice())?.data_checksums());
+        sb.features |= 0x80;
+        let buf = <Superblock>::serialize(&mut sb)?;
+        assert!(Superblock::deserialize_from(buf.as_slice()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn inode_revisions() -> anyhow::Result<()> {
+        let mut inode = Inode::new();
+        inode.indirect_block = 7;
+        let buf = <Inode>::serialize(&mut inode, 0)?;
+        assert!(buf.len() < <Inode>::serialize(&mut inode, REVISION)?.len());
+        assert_eq!(
+            Inode::deserialize_from(buf.as_slice(), 0)?.indirect_block,
+            7
+        );
+
+        inode.triple_indirect_block = 8;
+        assert!(<Inode>::serialize(&mut inode, 0).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn superblock_checksum() -> anyhow::Result<()> {
         let mut sb = Superblock::new(1024, 3, 0, 0);
@@ -457,8 +1159,8 @@ mod tests {
     fn inode_checksum() -> anyhow::Result<()> {
         let mut inode = Inode::default();
         inode.block_count = 24;
-        let buf = <Inode>::serialize(&mut inode)?;
-        let mut deserialised_inode = Inode::deserialize_from(buf.as_slice())?;
+        let buf = <Inode>::serialize(&mut inode, REVISION)?;
+        let mut deserialised_inode = Inode::deserialize_from(buf.as_slice(), REVISION)?;
         assert_ne!(deserialised_inode.checksum, 0);
         assert_eq!(deserialised_inode.checksum, inode.checksum);
 
@@ -468,8 +1170,8 @@ mod tests {
                 .unwrap()
                 .as_secs() as _,
         );
-        let buf = <Inode>::serialize(&mut deserialised_inode)?;
-        let deserialised_inode = Inode::deserialize_from(buf.as_slice())?;
+        let buf = <Inode>::serialize(&mut deserialised_inode, REVISION)?;
+        let deserialised_inode = Inode::deserialize_from(buf.as_slice(), REVISION)?;
 
         assert_ne!(inode.checksum, deserialised_inode.checksum);
 
@@ -486,6 +1188,25 @@ mod tests {
         assert!(inode.is_dir());
     }
 
+    #[test]
+    fn inode_inline_data() -> anyhow::Result<()> {
+        let mut inode = Inode::new();
+        inode.mode = libc::S_IFLNK | 0o777;
+        let target = b"../some/where/else.txt";
+        inode.set_inline_data(target);
+
+        assert!(inode.is_symlink());
+        assert!(inode.is_fast_symlink());
+        assert_eq!(inode.size, target.len() as u64);
+        assert_eq!(inode.block_count, 0);
+
+        let buf = <Inode>::serialize(&mut inode, REVISION)?;
+        let offset = inode.inline_data_offset() as usize;
+        assert_eq!(&buf[offset..offset + target.len()], &target[..]);
+
+        Ok(())
+    }
+
     #[test]
     fn inode_truncate() {
         let mut inode = Inode::new();
@@ -494,10 +1215,17 @@ mod tests {
         inode.direct_blocks[0] = 23;
         assert!(!inode.direct_blocks.iter().all(|x| *x == 0));
 
-        inode.truncate();
+        inode.truncate(0, 512);
         assert_eq!(inode.size, 0);
         assert_eq!(inode.block_count, 0);
         assert!(inode.direct_blocks.iter().all(|x| *x == 0));
+
+        inode.direct_blocks[0] = 23;
+        inode.direct_blocks[1] = 24;
+        inode.direct_blocks[2] = 25;
+        assert_eq!(inode.truncate(513, 512), vec![25]);
+        assert_eq!(inode.size, 513);
+        assert_eq!(&inode.direct_blocks[..3], &[23, 24, 0]);
     }
 
     #[test]
@@ -619,12 +1347,8 @@ mod tests {
             checksum: 0,
         };
 
-        let size = bincode::serialized_size(&dir)?;
-        let buf = vec![0u8; size as _];
-        let mut cursor = Cursor::new(buf);
-        dir.serialize_into(&mut cursor)?;
-        cursor.set_position(0);
-        let deserialized = Directory::deserialize_from(cursor)?;
+        let buf = Directory::serialize(&mut dir)?;
+        let deserialized = Directory::deserialize_from(Cursor::new(buf))?;
 
         assert_eq!(deserialized.entries.len(), 2);
         assert_ne!(deserialized.checksum, 0);
@@ -657,4 +1381,108 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn directory_split_off() {
+        let mut dir = Directory::default();
+        for i in 0..9 {
+            dir.entries.insert(OsString::from(format!("{}.txt", i)), i);
+        }
+
+        let (hash, right) = dir.split_off().unwrap();
+        assert_eq!(dir.entries.len() + right.entries.len(), 9);
+        assert!(dir.entries.keys().all(|name| util::hash_name(name) < hash));
+        assert!(right
+            .entries
+            .keys()
+            .all(|name| util::hash_name(name) >= hash));
+
+        let mut dir = Directory::default();
+        dir.entries.insert(OsString::from("foo.txt"), 1);
+        assert!(dir.split_off().is_none());
+    }
+
+    #[test]
+    fn directory_index() -> anyhow::Result<()> {
+        let mut index = DirectoryIndex::new(0, vec![(0, 1), (100, 2), (200, 3), (300, 4)]);
+        assert_eq!(index.position(0), 0);
+        assert_eq!(index.position(99), 0);
+        assert_eq!(index.position(100), 1);
+        assert_eq!(index.position(u64::MAX), 3);
+
+        let buf = <DirectoryIndex>::serialize(&mut index)?;
+        assert!(DirectoryIndex::is_index(&buf));
+        assert!(!DirectoryIndex::is_index(&<Directory>::serialize(
+            &mut Directory::default()
+        )?));
+        assert_eq!(
+            DirectoryIndex::deserialize_from(buf.as_slice())?
+                .children
+                .len(),
+            4
+        );
+
+        let (hash, right) = index.split_off();
+        assert_eq!(hash, 200);
+        assert_eq!(index.children, vec![(0, 1), (100, 2)]);
+        assert_eq!(right.children, vec![(200, 3), (300, 4)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn extent_node() {
+        let mut node = ExtentNode::default();
+        node.insert(0, 10);
+        node.insert(1, 11);
+        node.insert(5, 20);
+        node.insert(3, 13);
+        assert_eq!(
+            node.extents,
+            vec![
+                Extent {
+                    logical: 0,
+                    physical: 10,
+                    len: 2
+                },
+                Extent {
+                    logical: 3,
+                    physical: 13,
+                    len: 1
+                },
+                Extent {
+                    logical: 5,
+                    physical: 20,
+                    len: 1
+                },
+            ]
+        );
+
+        // Filling the gap joins both neighbours, and a block right before an
+        // extent extends it backwards.
+        node.insert(2, 12);
+        node.insert(4, 19);
+        assert_eq!(node.extents.len(), 2);
+        assert_eq!(node.extents[0].len, 4);
+        assert_eq!(node.extents[1].logical, 4);
+        assert_eq!(node.extents[1].len, 2);
+
+        assert_eq!(node.position(3), Some(0));
+        assert_eq!(node.position(100), Some(1));
+        assert!(node.extents[0].contains(3));
+        assert!(!node.extents[1].contains(6));
+
+        let mut inode = Inode::new();
+        inode.flags = EXTENTS_FLAG;
+        assert!(inode.uses_extents());
+        assert_eq!(inode.extent_root(), ExtentNode::default());
+        inode.set_extent_root(&node);
+        assert_eq!(inode.extent_root(), node);
+        assert_eq!(ExtentNode::capacity(DIRECT_POINTERS as usize), 3);
+        assert_eq!(ExtentNode::from_bytes(&node.to_bytes(128)), node);
+
+        let right = node.split_off();
+        assert_eq!(node.extents.len(), 1);
+        assert_eq!(right.extents[0].logical, 4);
+    }
 }
@@ -1,5 +1,10 @@
-use super::INODE_SIZE;
-use std::time::{self, SystemTime};
+use super::{INODE_SIZE, SUPERBLOCK_SIZE};
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    time::{self, SystemTime},
+};
 
 #[inline]
 pub fn calculate_checksum<S>(s: &S) -> u32
@@ -11,6 +16,23 @@ where
     hasher.finalize()
 }
 
+#[inline]
+pub fn block_checksum(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// FNV-1a, it has to stay stable since the directory index is keyed by it.
+#[inline]
+pub fn hash_name(name: &OsStr) -> u64 {
+    name.as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
 #[inline]
 pub fn now() -> u64 {
     SystemTime::now()
@@ -28,6 +50,37 @@ pub fn block_group_size(blk_size: u32) -> u64 {
     size as u64
 }
 
+// Groups 1 and the powers of 3, 5 and 7 keep a backup of the superblock in
+// their first data blocks, like ext2's sparse superblocks.
+pub fn backup_groups(groups: u32) -> Vec<u32> {
+    let mut backups = BTreeSet::new();
+    if groups > 1 {
+        backups.insert(1);
+    }
+    for base in &[3u64, 5, 7] {
+        let mut group = *base;
+        while group < groups as u64 {
+            backups.insert(group as u32);
+            group *= base;
+        }
+    }
+
+    backups.into_iter().collect()
+}
+
+#[inline(always)]
+pub fn superblock_blocks(blk_size: u32) -> u32 {
+    ((SUPERBLOCK_SIZE - 1) / blk_size as u64 + 1) as u32
+}
+
+#[inline(always)]
+pub fn backup_superblock_offset(blk_size: u32, group: u32) -> u64 {
+    SUPERBLOCK_SIZE
+        + block_group_size(blk_size) * group as u64
+        + 2 * blk_size as u64
+        + inode_table_size(blk_size) as u64
+}
+
 #[inline(always)]
 pub fn inode_table_size(blk_size: u32) -> u32 {
     blk_size * 8 * INODE_SIZE as u32
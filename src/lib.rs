@@ -0,0 +1,33 @@
+//! GotenksFS is a file system stored in a single image file, usually mounted
+//! with FUSE. Images can also be read and changed in-process through
+//! [`Image`](gotenks::Image), without mounting them:
+//!
+//! ```no_run
+//! use gotenksfs::gotenks::Image;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut image = Image::new("disk.img")?;
+//! image.mkdir("/docs", 0o755)?;
+//! image.create("/docs/hello.txt", 0o644)?;
+//! image.write("/docs/hello.txt", b"hello", 0)?;
+//! for entry in image.readdir("/docs")? {
+//!     println!("{:?} {}", entry.name, entry.metadata.size);
+//! }
+//! image.close()?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod cli;
+mod debug;
+mod dumpfs;
+mod extract;
+mod fsck;
+pub mod gotenks;
+mod mkfs;
+mod mount;
+mod resize;
+
+// The command line tool, which `main.rs` runs.
+#[doc(hidden)]
+pub use cli::run;
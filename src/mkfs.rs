@@ -1,13 +1,27 @@
-use crate::gotenks::{types::Superblock, util, SUPERBLOCK_SIZE};
+use crate::gotenks::{
+    fs::GotenksFS, types::Superblock, util, EXTENTS_FLAG, FEATURE_DATA_CHECKSUMS, FEATURE_EXTENTS,
+    SUPERBLOCK_SIZE,
+};
 use anyhow::anyhow;
 use byte_unit::{Byte, ByteUnit};
+use fuse_rs::Filesystem;
+use nix::sys::stat::{Mode, SFlag};
 use std::{
-    fs::OpenOptions,
-    io::{BufWriter, Write},
-    path::Path,
+    collections::HashMap,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
 };
 
-pub fn make<P>(path: P, file_size: u64, blk_size: u32) -> anyhow::Result<()>
+// Features to turn on in a new image.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub extents: bool,
+    pub checksums: bool,
+}
+
+pub fn make<P>(path: P, file_size: u64, blk_size: u32, options: Options) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
@@ -22,15 +36,270 @@ where
     }
 
     let groups = (file_size as f64 / bg_size as f64).ceil();
-    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
-    let mut buf = BufWriter::new(&file);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path.as_ref())?;
     let uid = nix::unistd::geteuid().as_raw();
     let gid = nix::unistd::getegid().as_raw();
     let mut sb = Superblock::new(blk_size, groups as _, uid, gid);
+    if options.extents {
+        sb.inode_flags |= EXTENTS_FLAG;
+        sb.features |= FEATURE_EXTENTS;
+    }
+    if options.checksums {
+        sb.features |= FEATURE_DATA_CHECKSUMS;
+    }
+
+    // The backups are marked as used in the data bitmap of their group.
+    let reserved = util::superblock_blocks(blk_size) as usize;
+    let mut bitmap = vec![0u8; (reserved - 1) / 8 + 1];
+    for i in 0..reserved {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+    let backups = sb.backup_groups();
+    sb.free_blocks -= (reserved * backups.len()) as u32;
+
+    file.set_len(sb.checksums_offset() + sb.checksums_size())?;
+    let data = sb.serialize()?;
+    file.write_all(&data)?;
+    for group in backups {
+        file.seek(SeekFrom::Start(
+            SUPERBLOCK_SIZE + util::block_group_size(blk_size) * group as u64,
+        ))?;
+        file.write_all(&bitmap)?;
+        file.seek(SeekFrom::Start(sb.backup_offset(group)))?;
+        file.write_all(&data)?;
+    }
+    file.flush()?;
+    drop(file);
+
+    // Opening the image creates the root directory, which is all that
+    // read-only tools like dumpfs can't do themselves.
+    Ok(GotenksFS::new(path)?.destroy()?)
+}
+
+// A new image named after `name` in the temporary directory, replacing the
+// one a previous run left behind.
+#[cfg(test)]
+pub(crate) fn tmp_image(
+    name: &str,
+    file_size: u64,
+    blk_size: u32,
+    options: Options,
+) -> anyhow::Result<PathBuf> {
+    let mut tmp_file = std::env::temp_dir();
+    tmp_file.push(name);
+    tmp_file.set_extension("img");
+    if tmp_file.exists() {
+        fs::remove_file(&tmp_file)?;
+    }
+
+    make(&tmp_file, file_size, blk_size, options)?;
+    Ok(tmp_file)
+}
+
+// Copies the tree under `dir` into the image at `path`, keeping modes,
+// ownership, timestamps, symlinks and hard links. Blocks of zeros are left
+// as holes.
+pub fn populate<P, Q>(path: P, dir: Q) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut fs = GotenksFS::new(path)?;
+    let mut links = HashMap::new();
+    copy_dir(&mut fs, dir.as_ref(), Path::new("/"), &mut links)?;
+    copy_metadata(&mut fs, Path::new("/"), &fs::metadata(dir.as_ref())?)?;
+
+    Ok(fs.destroy()?)
+}
+
+// Children first, adding them changes the modification time of `dst`.
+fn copy_dir(
+    fs: &mut GotenksFS,
+    src: &Path,
+    dst: &Path,
+    links: &mut HashMap<(u64, u64), PathBuf>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(src)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
 
-    sb.serialize_into(&mut buf)?;
+    for entry in entries {
+        let src = entry.path();
+        let dst = dst.join(entry.file_name());
+        let meta = fs::symlink_metadata(&src)?;
+        let file_type = meta.file_type();
+        let failed = |err| anyhow!("{}: {}", src.display(), err);
 
-    buf.flush()?;
+        if file_type.is_dir() {
+            fs.create_dir(&dst, Mode::S_IRWXU).map_err(failed)?;
+            copy_dir(fs, &src, &dst, links)?;
+        } else if file_type.is_symlink() {
+            fs.symlink(&fs::read_link(&src)?, &dst).map_err(failed)?;
+        } else if file_type.is_file() {
+            if meta.nlink() > 1 {
+                if let Some(first) = links.get(&(meta.dev(), meta.ino())) {
+                    fs.hard_link(first, &dst).map_err(failed)?;
+                    copy_metadata(fs, &dst, &meta)?;
+                    continue;
+                }
+                links.insert((meta.dev(), meta.ino()), dst.clone());
+            }
+            copy_file(fs, &src, &dst, &meta)?;
+        } else {
+            let kind = if file_type.is_fifo() {
+                "FIFO"
+            } else if file_type.is_socket() {
+                "socket"
+            } else {
+                "device"
+            };
+            eprintln!(
+                "Skipping {}: {} files are not supported",
+                src.display(),
+                kind
+            );
+            continue;
+        }
 
-    Ok(file.set_len(SUPERBLOCK_SIZE + bg_size * groups as u64)?)
+        copy_metadata(fs, &dst, &meta)?;
+    }
+
+    Ok(())
+}
+
+fn copy_file(fs: &mut GotenksFS, src: &Path, dst: &Path, meta: &Metadata) -> anyhow::Result<()> {
+    let failed = |err: nix::errno::Errno| anyhow!("{}: {}", src.display(), err);
+    let io_failed = |err: io::Error| anyhow!("{}: {}", src.display(), err);
+    let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+    fs.create(dst, Mode::S_IRWXU, &mut open_fi)
+        .map_err(failed)?;
+    let mut file_info = fuse_rs::fs::FileInfo::default();
+    file_info.set_handle(open_fi.handle().unwrap());
+    let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+
+    let blk_size = fs.superblock().block_size as usize;
+    let mut file = File::open(src).map_err(io_failed)?;
+    let mut buf = vec![0u8; blk_size * (64 * 1024 / blk_size).max(1)];
+    let mut offset = 0;
+    loop {
+        // Fill the buffer so every chunk starts on a block boundary.
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(io_failed(e)),
+            }
+        }
+        if read == 0 {
+            break;
+        }
+
+        for chunk in buf[..read].chunks(blk_size) {
+            if chunk.iter().any(|b| *b != 0) {
+                fs.write(dst, chunk, offset, &mut write_file_info)
+                    .map_err(failed)?;
+            }
+            offset += chunk.len() as u64;
+        }
+    }
+
+    fs.set_len(dst, meta.len()).map_err(failed)
+}
+
+fn copy_metadata(fs: &mut GotenksFS, dst: &Path, meta: &Metadata) -> anyhow::Result<()> {
+    let file_type = if meta.is_dir() {
+        SFlag::S_IFDIR
+    } else if meta.file_type().is_symlink() {
+        SFlag::S_IFLNK
+    } else {
+        SFlag::S_IFREG
+    };
+
+    let (mut inode, index) = fs.find_inode_from_path(dst)?;
+    inode.mode = file_type.bits() | (meta.mode() & 0o7777);
+    inode.user_id = meta.uid();
+    inode.group_id = meta.gid();
+    inode.accessed_at = Some(meta.atime());
+    inode.modified_at = Some(meta.mtime());
+    inode.changed_at = Some(meta.ctime());
+    fs.save_inode(inode, index)?;
+
+    fs.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gotenks::ROOT_INODE;
+    use std::{ffi::OsStr, os::unix::fs::PermissionsExt};
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn populate_from_dir() -> anyhow::Result<()> {
+        let mut dir = std::env::temp_dir();
+        dir.push("populate");
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(dir.join("sub"))?;
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        fs::write(dir.join("sub/foo.txt"), &data)?;
+        fs::set_permissions(dir.join("sub/foo.txt"), fs::Permissions::from_mode(0o640))?;
+        fs::hard_link(dir.join("sub/foo.txt"), dir.join("bar.txt"))?;
+        std::os::unix::fs::symlink("sub/foo.txt", dir.join("link"))?;
+        let sparse = File::create(dir.join("sparse"))?;
+        sparse.set_len(100_000)?;
+        let mut holes = vec![0u8; BLOCK_SIZE as usize * 3];
+        holes[0] = 1;
+        holes[BLOCK_SIZE as usize * 2 + 1] = 1;
+        fs::write(dir.join("holes"), &holes)?;
+
+        let tmp_file = tmp_image(
+            "populate",
+            util::block_group_size(BLOCK_SIZE),
+            BLOCK_SIZE,
+            Options::default(),
+        )?;
+        populate(&tmp_file, &dir)?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let (inode, index) = fs.find_inode_from_path("/sub/foo.txt")?;
+        let meta = fs::metadata(dir.join("sub/foo.txt"))?;
+        assert_eq!(inode.mode, libc::S_IFREG | 0o640);
+        assert_eq!(inode.hard_links, 2);
+        assert_eq!(inode.user_id, meta.uid());
+        assert_eq!(inode.modified_at, Some(meta.mtime()));
+        assert_eq!(fs.find_dir_entry(ROOT_INODE, OsStr::new("bar.txt"))?, index);
+
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.open(Path::new("/bar.txt"), &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut buf = vec![0u8; 1000];
+        fs.read(Path::new("/bar.txt"), &mut buf, 0, file_info)?;
+        assert_eq!(buf, data);
+
+        assert_eq!(fs.read_link(Path::new("/link"))?, OsStr::new("sub/foo.txt"));
+        let (inode, _) = fs.find_inode_from_path("/sparse")?;
+        assert_eq!(inode.size, 100_000);
+        assert_eq!(inode.block_count, 0);
+        let (inode, _) = fs.find_inode_from_path("/holes")?;
+        assert_eq!(inode.size, holes.len() as u64);
+        assert_eq!(inode.block_count, 2);
+        assert_eq!(inode.direct_blocks[1], 0);
+        let (inode, _) = fs.find_inode_from_path("/sub")?;
+        assert!(inode.is_dir());
+        assert_eq!(
+            inode.modified_at,
+            Some(fs::metadata(dir.join("sub"))?.mtime())
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(fs::remove_file(&tmp_file)?)
+    }
 }
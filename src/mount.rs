@@ -1,20 +1,53 @@
-use crate::gotenks::fs::GotenksFS;
+use crate::{
+    fsck,
+    gotenks::{
+        device,
+        fs::GotenksFS,
+        scrub::{self, Event},
+    },
+};
 use anyhow::anyhow;
-use std::{ffi::OsString, path::Path};
-
-static mut FS: GotenksFS = GotenksFS {
-    sb: None,
-    mmap: None,
-    groups: None,
+use fuse_rs::{
+    fs::{ConnectionInfo, DirEntry, FileInfo, FileStat, OpenFileInfo, WriteFileInfo},
+    Filesystem,
+};
+use nix::sys::stat::Mode;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
 };
 
-pub fn mount<P>(image_path: P, mountpoint: P) -> anyhow::Result<()>
+// With `scrub_interval`, the image is scrubbed while mounted, one block at a
+// time at most every interval, and `report` gets what the scrub finds.
+// Without `mmap`, it's read and written with pread and pwrite instead of
+// being mapped into memory.
+pub fn mount<P, F>(
+    image_path: P,
+    mountpoint: P,
+    scrub_interval: Option<Duration>,
+    mmap: bool,
+    report: F,
+) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
+    F: FnMut(Event) + Send + 'static,
 {
-    unsafe {
-        FS = GotenksFS::new(image_path)?;
+    fsck::check_if_needed(image_path.as_ref())?;
+
+    let fs = GotenksFS::with_device(device::open(image_path, mmap)?)?;
+    let fs = Arc::new(Mutex::new(fs));
+    if let Some(interval) = scrub_interval {
+        scrub::spawn(fs.clone(), interval, report);
     }
+    // FUSE keeps the file system until the process exits.
+    let mounted = Box::leak(Box::new(Mounted {
+        fs,
+        link_targets: Mutex::new(HashMap::new()),
+    }));
 
     let opts = vec![
         // OsString::from("-h"),
@@ -25,13 +58,143 @@ where
         OsString::from("volname=gotenksfs"),
     ];
 
-    match fuse_rs::mount(
-        OsString::from("GotenksFS"),
-        mountpoint,
-        unsafe { &mut FS },
-        opts,
-    ) {
+    match fuse_rs::mount(OsString::from("GotenksFS"), mountpoint, mounted, opts) {
         Ok(_) => Ok(()),
         Err(err) => Err(anyhow!(format!("{:?}", err))),
     }
 }
+
+// The file system as FUSE sees it, shared with the scrub.
+struct Mounted {
+    fs: Arc<Mutex<GotenksFS>>,
+    // Like `GotenksFS::link_targets`, whose own copies can't outlive the
+    // lock. FUSE reads links from several threads at once.
+    link_targets: Mutex<HashMap<u32, Box<[u8]>>>,
+}
+
+impl Mounted {
+    fn fs(&self) -> MutexGuard<'_, GotenksFS> {
+        self.fs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn fs_mut(&mut self) -> MutexGuard<'_, GotenksFS> {
+        self.link_targets
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.fs()
+    }
+}
+
+impl Filesystem for Mounted {
+    fn metadata(&self, path: &Path) -> fuse_rs::Result<FileStat> {
+        self.fs().metadata(path)
+    }
+
+    fn read_dir(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        file_info: FileInfo,
+    ) -> fuse_rs::Result<Vec<DirEntry>> {
+        self.fs_mut().read_dir(path, offset, file_info)
+    }
+
+    fn create(
+        &mut self,
+        path: &Path,
+        permissions: Mode,
+        file_info: &mut OpenFileInfo,
+    ) -> fuse_rs::Result<()> {
+        self.fs_mut().create(path, permissions, file_info)
+    }
+
+    fn statfs(&self, path: &Path) -> fuse_rs::Result<libc::statvfs> {
+        self.fs().statfs(path)
+    }
+
+    fn open(&mut self, path: &Path, file_info: &mut OpenFileInfo) -> fuse_rs::Result<()> {
+        self.fs_mut().open(path, file_info)
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        buf: &[u8],
+        offset: u64,
+        file_info: &mut WriteFileInfo,
+    ) -> fuse_rs::Result<usize> {
+        self.fs_mut().write(path, buf, offset, file_info)
+    }
+
+    fn read(
+        &mut self,
+        path: &Path,
+        buf: &mut [u8],
+        offset: u64,
+        file_info: FileInfo,
+    ) -> fuse_rs::Result<usize> {
+        self.fs_mut().read(path, buf, offset, file_info)
+    }
+
+    fn ftruncate(&mut self, path: &Path, len: u64, file_info: FileInfo) -> fuse_rs::Result<()> {
+        self.fs_mut().ftruncate(path, len, file_info)
+    }
+
+    fn set_len(&mut self, path: &Path, len: u64) -> fuse_rs::Result<()> {
+        self.fs_mut().set_len(path, len)
+    }
+
+    fn fmetadata(&self, path: &Path, file_info: FileInfo) -> fuse_rs::Result<FileStat> {
+        self.fs().fmetadata(path, file_info)
+    }
+
+    fn set_permissions(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
+        self.fs_mut().set_permissions(path, mode)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> fuse_rs::Result<()> {
+        self.fs_mut().remove_file(path)
+    }
+
+    fn create_dir(&mut self, path: &Path, mode: Mode) -> fuse_rs::Result<()> {
+        self.fs_mut().create_dir(path, mode)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> fuse_rs::Result<()> {
+        self.fs_mut().remove_dir(path)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> fuse_rs::Result<()> {
+        self.fs_mut().rename(from, to)
+    }
+
+    fn symlink(&mut self, src: &Path, dst: &Path) -> fuse_rs::Result<()> {
+        self.fs_mut().symlink(src, dst)
+    }
+
+    fn read_link(&self, path: &Path) -> fuse_rs::Result<&OsStr> {
+        let (index, target) = self.fs().link_target(path)?;
+        let mut targets = self
+            .link_targets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // The box doesn't move when the map grows, and stays there until
+        // `&mut self` is borrowed again.
+        let ptr: *const [u8] = &**targets.entry(index).or_insert(target);
+        Ok(OsStr::from_bytes(unsafe { &*ptr }))
+    }
+
+    fn hard_link(&mut self, src: &Path, dst: &Path) -> fuse_rs::Result<()> {
+        self.fs_mut().hard_link(src, dst)
+    }
+
+    fn init(&mut self, connection_info: &mut ConnectionInfo) -> fuse_rs::Result<()> {
+        self.fs_mut().init(connection_info)
+    }
+
+    fn destroy(&mut self) -> fuse_rs::Result<()> {
+        self.fs_mut().destroy()
+    }
+}
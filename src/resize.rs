@@ -0,0 +1,268 @@
+use crate::gotenks::{
+    device, fs::GotenksFS, journal::Transaction, util, STATE_DIRTY, STATE_ERRORS, SUPERBLOCK_SIZE,
+};
+use anyhow::anyhow;
+use fuse_rs::Filesystem;
+use std::{
+    fs::{self, OpenOptions},
+    ops::Range,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+
+// Changes the number of block groups to fit `file_size`, like `mkfs` does.
+// The journal and the data checksums, which come after the last group, move
+// with it. Groups can only be removed when nothing but a backup superblock
+// lives in them. Returns the new number of groups.
+//
+// Everything the new layout needs is written and flushed before the
+// superblock switches to it, so an interrupted resize leaves the image as it
+// was. When the new metadata would overwrite the checksums still in use, they
+// first move past it behind a larger journal.
+pub fn resize<P>(path: P, file_size: u64) -> anyhow::Result<u32>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if fs::metadata(path)?.file_type().is_block_device() {
+        return Err(anyhow!("The size of a block device can't be changed"));
+    }
+
+    let fs = GotenksFS::read_only(path)?;
+    let mut sb = fs.superblock().clone();
+    if sb.state & (STATE_DIRTY | STATE_ERRORS) != 0 {
+        return Err(anyhow!(
+            "The image {}, run fsck first",
+            sb.check_reason().unwrap_or_default()
+        ));
+    }
+
+    let old_groups = sb.groups;
+    let groups = (file_size as f64 / util::block_group_size(sb.block_size) as f64).ceil() as u32;
+    if groups == 0 {
+        return Err(anyhow!("The image needs at least one block group"));
+    }
+    if groups == old_groups {
+        return Ok(groups);
+    }
+
+    let backups = sb.backup_groups();
+    let reserved = util::superblock_blocks(sb.block_size) as usize;
+    for g in groups..old_groups {
+        let group = &fs.groups()[g as usize];
+        let used = if backups.contains(&g) { reserved } else { 0 };
+        if group.data_bitmap.count_ones() > used || group.inode_bitmap.any() {
+            return Err(anyhow!(
+                "Block group {} is in use, the image can't have fewer than {} groups",
+                g,
+                g + 1
+            ));
+        }
+    }
+
+    let kept = &fs.groups()[..groups.min(old_groups) as usize];
+    let mut free_blocks = kept.iter().map(|g| g.free_data_blocks()).sum::<usize>() as u32;
+    let free_inodes = kept.iter().map(|g| g.free_inodes()).sum::<usize>() as u32;
+    let mut checksums = vec![0u8; sb.checksums_size() as usize];
+    fs.device().read_at(&mut checksums, sb.checksums_offset())?;
+    drop(fs);
+
+    let mut old = sb.clone();
+    let total_blocks = sb.block_size * 8 * groups;
+    sb.groups = groups;
+    sb.block_count = total_blocks;
+    sb.inode_count = total_blocks;
+    sb.free_inodes = free_inodes + (groups.saturating_sub(old_groups) * sb.block_size * 8);
+    let new_backups = sb.backup_groups();
+    for g in old_groups..groups {
+        free_blocks += sb.data_blocks_per_group;
+        if new_backups.contains(&g) {
+            free_blocks -= reserved as u32;
+        }
+    }
+    sb.free_blocks = free_blocks;
+
+    // The bitmaps and backup superblocks of the new groups, the cleared
+    // journal and the checksums, as they will be in the new layout.
+    let sb_data = sb.serialize()?;
+    let mut writes = vec![];
+    let mut bitmaps = vec![0u8; 2 * sb.block_size as usize];
+    for i in 0..reserved {
+        bitmaps[i / 8] |= 1 << (i % 8);
+    }
+    for g in old_groups..groups {
+        let offset = SUPERBLOCK_SIZE + util::block_group_size(sb.block_size) * g as u64;
+        if new_backups.contains(&g) {
+            writes.push((offset, bitmaps.clone()));
+            writes.push((sb.backup_offset(g), sb_data.clone()));
+        } else {
+            writes.push((offset, vec![0u8; bitmaps.len()]));
+        }
+    }
+    writes.push((sb.journal_offset(), Transaction::default().serialize()?));
+    let mut table = checksums.clone();
+    table.resize(sb.checksums_size() as usize, 0);
+    writes.push((sb.checksums_offset(), table));
+
+    let end = sb.checksums_offset() + sb.checksums_size();
+    let old_end = old.checksums_offset() + old.checksums_size();
+    let in_use = old.checksums_offset()..old_end;
+    let overlap = writes
+        .iter()
+        .any(|(offset, data)| overlaps(&in_use, *offset..*offset + data.len() as u64));
+    let mut len = end.max(old_end);
+    if overlap {
+        // Only the checksums of the old layout move, behind a journal that
+        // reaches past the new metadata.
+        let blk_size = old.block_size as u64;
+        old.journal_blocks = (len - old.journal_offset()).div_ceil(blk_size) as u32;
+        len = old.checksums_offset() + old.checksums_size();
+    }
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(file.metadata()?.len().max(len))?;
+    drop(file);
+    let mut device = device::open(path, false)?;
+    if overlap {
+        device.write_at(&checksums, old.checksums_offset())?;
+        device.flush()?;
+        device.write_at(&old.serialize()?, 0)?;
+        device.flush()?;
+    }
+
+    for (offset, data) in &writes {
+        device.write_at(data, *offset)?;
+    }
+    device.flush()?;
+    device.write_at(&sb_data, 0)?;
+    device.flush()?;
+    drop(device);
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end)?;
+    drop(file);
+
+    // Brings the backups in the groups that were already there up to date.
+    GotenksFS::new(path)?.destroy()?;
+
+    Ok(groups)
+}
+
+fn overlaps(a: &Range<u64>, b: Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsck, gotenks::ROOT_INODE, mkfs};
+    use nix::sys::stat::Mode;
+    use std::ffi::OsStr;
+
+    const BLOCK_SIZE: u32 = 128;
+
+    #[test]
+    fn grow_and_shrink() -> anyhow::Result<()> {
+        let bg_size = util::block_group_size(BLOCK_SIZE);
+        let tmp_file = mkfs::tmp_image(
+            "resize",
+            bg_size,
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(Path::new("/foo.txt"), Mode::S_IRWXU, &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        fs.write(Path::new("/foo.txt"), &data, 0, &mut write_file_info)?;
+        fs.superblock_mut().state |= STATE_ERRORS;
+        fs.destroy()?;
+
+        // Not before fsck is happy with the image
+        assert!(resize(&tmp_file, bg_size * 3).is_err());
+        fsck::check(&tmp_file, true)?;
+
+        // Group 1 gets a backup superblock, group 2 doesn't
+        assert_eq!(resize(&tmp_file, bg_size * 3)?, 3);
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let total = BLOCK_SIZE * 8 * 3;
+        assert_eq!(fs.superblock().groups, 3);
+        assert_eq!(fs.superblock().block_count, total);
+        assert_eq!(fs.superblock().free_inodes, total - 2);
+        assert_eq!(fs.superblock().free_blocks, total - 8 - 4);
+        assert_eq!(
+            std::fs::metadata(&tmp_file)?.len(),
+            fs.superblock().checksums_offset() + total as u64 * 4
+        );
+
+        // Data still matches its checksums after the table moved
+        let mut buf = vec![0u8; 300];
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        fs.read(Path::new("/foo.txt"), &mut buf, 0, file_info)?;
+        assert_eq!(buf, data);
+
+        fs.mark_group(2);
+        fs.groups_mut()[2].set_data_block(5, true);
+        fs.destroy()?;
+        assert!(resize(&tmp_file, bg_size * 2).is_err());
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        fs.mark_group(2);
+        fs.groups_mut()[2].set_data_block(5, false);
+        fs.destroy()?;
+        assert_eq!(resize(&tmp_file, bg_size)?, 1);
+        assert!(fsck::check(&tmp_file, false)?.is_empty());
+
+        let fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().free_blocks, BLOCK_SIZE * 8 - 4);
+        assert!(fs.find_dir_entry(ROOT_INODE, OsStr::new("foo.txt")).is_ok());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+
+    #[test]
+    fn move_checksums_in_use() -> anyhow::Result<()> {
+        // The new journal lands on the checksums of 33 groups
+        let bg_size = util::block_group_size(BLOCK_SIZE);
+        let tmp_file = mkfs::tmp_image(
+            "resize_checksums",
+            bg_size * 33,
+            BLOCK_SIZE,
+            mkfs::Options {
+                checksums: true,
+                ..mkfs::Options::default()
+            },
+        )?;
+
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        let mut open_fi = fuse_rs::fs::OpenFileInfo::default();
+        fs.create(Path::new("/foo.txt"), Mode::S_IRWXU, &mut open_fi)?;
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        let mut write_file_info = fuse_rs::fs::WriteFileInfo::from_file_info(file_info);
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        fs.write(Path::new("/foo.txt"), &data, 0, &mut write_file_info)?;
+        fs.destroy()?;
+
+        assert_eq!(resize(&tmp_file, bg_size * 34)?, 34);
+        let mut fs = GotenksFS::new(&tmp_file)?;
+        assert_eq!(fs.superblock().journal_blocks, 1024);
+        let mut buf = vec![0u8; 300];
+        let mut file_info = fuse_rs::fs::FileInfo::default();
+        file_info.set_handle(open_fi.handle().unwrap());
+        fs.read(Path::new("/foo.txt"), &mut buf, 0, file_info)?;
+        assert_eq!(buf, data);
+        drop(fs);
+        assert!(fsck::check(&tmp_file, false)?.is_empty());
+
+        Ok(std::fs::remove_file(&tmp_file)?)
+    }
+}